@@ -4,26 +4,48 @@
 use crate::auth::Auth;
 use crate::body::ResponseBody as RB;
 use crate::config::Config;
-use crate::ir::{Query, Reply};
-use http_body_util::{BodyExt, Empty, Full};
+use crate::fed::FED_KEYS_PATH;
+use crate::ir::{Id, Query, Reply};
+use crate::limit::Limit;
+use http_body_util::{BodyExt, Empty, Full, Limited};
 use hyper::server::conn::http1;
 use hyper::{Method, Request, Response, body::Bytes, service::service_fn};
 use hyper_util::rt::TokioIo;
 use std::convert::Infallible;
 use std::error::Error;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
 use tokio::net::TcpListener;
+
+/// Path of the inbound SMS webhook.
+const SMS_WEBHOOK_PATH: &str = "/sms";
+
+/// Path of the email magic link.
+const EMAIL_LINK_PATH: &str = "/email";
+
+/// Max bytes of a webhook payload.
+const WEBHOOK_MAX_BYTES: usize = 64 * 1024;
+
 pub struct Api {
     auth: &'static Auth,
+    limit: &'static Limit,
     http_addr: SocketAddr,
+    real_ip_header: String,
+    real_ip_hops: usize,
+    /// Fed public keys as JSON.
+    fed_keys: String,
 }
 
 /// Server endpoints.
 impl Api {
-    pub fn new(config: &Config, auth: &'static Auth) -> Api {
+    pub fn new(config: &Config, auth: &'static Auth, limit: &'static Limit) -> Api {
         Api {
             auth,
+            limit,
             http_addr: config.http_addr,
+            real_ip_header: config.real_ip_header.to_lowercase(),
+            real_ip_hops: config.real_ip_hops,
+            fed_keys: serde_json::to_string(&config.fed_public_keys).unwrap(),
         }
     }
 
@@ -38,11 +60,11 @@ impl Api {
     async fn serve_http(&'static self) -> Result<(), Box<dyn Error + Send + Sync>> {
         let listener = TcpListener::bind(self.http_addr).await?;
         loop {
-            let (stream, _) = listener.accept().await?;
+            let (stream, peer) = listener.accept().await?;
             let io = TokioIo::new(stream);
             tokio::task::spawn(async move {
                 if let Err(err) = http1::Builder::new()
-                    .serve_connection(io, service_fn(move |req| handle_http(req, self.auth)))
+                    .serve_connection(io, service_fn(move |req| handle_http(req, self, peer.ip())))
                     .await
                 {
                     panic!("Error serving: {:?}", err);
@@ -50,19 +72,58 @@ impl Api {
             });
         }
     }
+
+    /// Client IP from the proxy header if configured, otherwise the peer.
+    fn client_ip<T>(&self, req: &Request<T>, peer: IpAddr) -> IpAddr {
+        if self.real_ip_header.is_empty() {
+            return peer;
+        }
+        req.headers()
+            .get(&self.real_ip_header)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| real_ip(v, self.real_ip_hops))
+            .unwrap_or(peer)
+    }
+}
+
+/// Entry hops from the right of a comma separated proxy header.
+fn real_ip(header: &str, hops: usize) -> Option<IpAddr> {
+    let entry = header.rsplit(',').nth(hops.checked_sub(1)?)?;
+    IpAddr::from_str(entry.trim()).ok()
 }
 
 async fn handle_http(
     req: Request<hyper::body::Incoming>,
-    auth: &'static Auth,
+    api: &'static Api,
+    peer: IpAddr,
 ) -> Result<Response<RB>, Infallible> {
+    let ip = api.client_ip(&req, peer);
     match *req.method() {
+        // Magic link
+        Method::GET if req.uri().path() == EMAIL_LINK_PATH => {
+            Ok(match handle_email_link(&req, api, &ip).await {
+                Ok(()) => Response::new(full("Signed in, you may close this page.")),
+                Err(error) => Reply::Error { error }.to_response(),
+            })
+        }
+        // Key discovery of peers
+        Method::GET if req.uri().path() == FED_KEYS_PATH => {
+            Ok(Response::new(full(api.fed_keys.clone())))
+        }
         // Ping server
         Method::GET => Ok(Response::new(full("PONG"))),
+        // Carrier callback
+        Method::POST if req.uri().path() == SMS_WEBHOOK_PATH => {
+            Ok(match handle_sms_webhook(req, api, &ip).await {
+                Ok(()) => Response::new(empty()),
+                Err(error) => Reply::Error { error }.to_response(),
+            })
+        }
         // Everything has side effect, so this is POST-only.
         Method::POST => match Query::try_from(req) {
-            Ok(query) => Ok(auth
-                .handle(query)
+            Ok(query) => Ok(api
+                .limit
+                .handle(query, &ip)
                 .await
                 .unwrap_or_else(|error| Reply::Error { error })
                 .to_response()),
@@ -75,6 +136,41 @@ async fn handle_http(
     }
 }
 
+async fn handle_sms_webhook(
+    req: Request<hyper::body::Incoming>,
+    api: &'static Api,
+    ip: &IpAddr,
+) -> crate::Result<()> {
+    api.limit.check(ip, "SmsWebhook").await?;
+    let (parts, body) = req.into_parts();
+    let body = Limited::new(body, WEBHOOK_MAX_BYTES)
+        .collect()
+        .await
+        .map_err(|_| crate::Error::ApiPayloadTooLarge)?
+        .to_bytes();
+    api.auth.handle_sms_webhook(&parts.headers, &body).await
+}
+
+async fn handle_email_link(
+    req: &Request<hyper::body::Incoming>,
+    api: &'static Api,
+    ip: &IpAddr,
+) -> crate::Result<()> {
+    api.limit.check(ip, "EmailLink").await?;
+    let mut token = None;
+    let mut code = None;
+    for (k, v) in form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes()) {
+        match k.as_ref() {
+            "token" => token = Some(Id::from_str(&v)?),
+            "code" => code = Some(Id::from_str(&v)?),
+            _ => {}
+        }
+    }
+    let token = token.ok_or(crate::Error::ApiMissingEntry)?;
+    let code = code.ok_or(crate::Error::ApiMissingEntry)?;
+    api.auth.handle_email_click(&token, &code).await
+}
+
 // Utility functions to make Empty and Full bodies.
 
 pub fn empty() -> RB {
@@ -92,3 +188,13 @@ pub fn full<T: Into<Bytes>>(chunk: T) -> RB {
             .boxed(),
     )
 }
+
+#[test]
+fn test_real_ip() {
+    let header = "6.6.6.6, 1.2.3.4, 10.0.0.1";
+    assert_eq!(real_ip(header, 1), "10.0.0.1".parse().ok());
+    assert_eq!(real_ip(header, 2), "1.2.3.4".parse().ok());
+    assert_eq!(real_ip(header, 4), None);
+    assert_eq!(real_ip(header, 0), None);
+    assert_eq!(real_ip("bogus", 1), None);
+}
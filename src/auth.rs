@@ -2,25 +2,77 @@
 
 use crate::config::Config;
 use crate::cost::Cost;
-use crate::database::Database;
-use crate::ir::{Id, Query, Reply};
+use crate::database::{Database, session_handle};
+use crate::ir::{Access, Id, Query, Reply};
+use crate::limit::Limiter;
 use crate::{Error, Result};
 use bytes::{BufMut, Bytes, BytesMut};
+use chrono::{DateTime, Duration, Utc};
+use http::HeaderMap;
+use mail::MailTransport;
+use sms::SmsProvider;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Mutex;
+use token::{FULL_SCOPE, Signer};
+
+pub mod mail;
+pub mod sms;
+pub mod token;
+pub mod totp;
+
+/// Issuer shown in authenticator apps.
+const TOTP_ISSUER: &str = "VOxOV";
+
+/// Touch times are pruned when there are more than this.
+const TOUCHED_MAX_KEYS: usize = 100_000;
+
+/// Known full accounts are forgotten when there are more than this.
+const FULL_MAX_KEYS: usize = 100_000;
 
 pub struct Auth {
     cost: &'static Cost,
     db: &'static Database,
     skip_auth: bool,
     phones: &'static Vec<String>,
+    sms: Option<Box<dyn SmsProvider>>,
+    mail: Option<Box<dyn MailTransport>>,
+    email_link: String,
+    account_delete_grace: i64,
+    signer: Option<Signer>,
+    access_ttl: i64,
+    limiter: &'static Limiter,
+    anonymous: bool,
+    anonymous_credit: i64,
+    /// When each access token last bumped its session, per instance.
+    touched: Mutex<HashMap<Id, i64>>,
+    /// Uids known to be full accounts, per instance. Upgrades never revert.
+    full: Mutex<HashSet<Id>>,
 }
 
 impl Auth {
-    pub fn new(config: &Config, db: &'static Database, cost: &'static Cost) -> Auth {
+    pub fn new(
+        config: &Config,
+        db: &'static Database,
+        cost: &'static Cost,
+        limiter: &'static Limiter,
+    ) -> Auth {
         Auth {
             cost,
             db,
             skip_auth: config.skip_auth,
             phones: config.auth_phones,
+            sms: sms::new_provider(config),
+            mail: mail::new_transport(config),
+            email_link: config.email_link.clone(),
+            account_delete_grace: config.account_delete_grace,
+            signer: Signer::new(&config.access_keys),
+            access_ttl: config.access_ttl,
+            limiter,
+            anonymous: config.anonymous,
+            anonymous_credit: config.anonymous_credit,
+            touched: Mutex::new(HashMap::new()),
+            full: Mutex::new(HashSet::new()),
         }
     }
 
@@ -28,72 +80,228 @@ impl Auth {
         match query {
             // Session management
             Query::AuthSessionStart => self.handle_session_start().await,
-            Query::AuthSessionRefresh { refresh } => self.handle_session_refresh(&refresh).await,
+            Query::AuthSessionRefresh { refresh, scope } => {
+                self.handle_session_refresh(&refresh, &scope).await
+            }
             Query::AuthSessionEnd {
                 access,
                 option_refresh,
             } => self.handle_session_end(&access, &option_refresh).await,
+            Query::AuthSessionList { access } => self.handle_session_list(&access).await,
+            Query::AuthSessionRevoke {
+                access,
+                handle,
+                totp,
+            } => self.handle_session_revoke(&access, &handle, &totp).await,
+            Query::AuthSessionRevokeAll { access, totp } => {
+                self.handle_session_revoke_all(&access, &totp).await
+            }
+            Query::AuthSessionAnonymous {
+                access,
+                refresh,
+                label,
+            } => {
+                self.handle_session_anonymous(&access, &refresh, &label)
+                    .await
+            }
             Query::AuthSmsSendTo { access } => self.handle_sms_send_to(&access).await,
             Query::AuthSmsSent {
                 access,
                 refresh,
                 phone,
                 message,
+                totp,
+                label,
+            } => {
+                self.handle_sms_sent(&access, &refresh, &phone, &message, &totp, &label)
+                    .await
+            }
+            Query::AuthPhoneChange {
+                access,
+                phone,
+                message,
+                totp,
+            } => {
+                self.handle_phone_change(&access, &phone, &message, &totp)
+                    .await
+            }
+            Query::AuthEmailStart { access, email } => {
+                self.handle_email_start(&access, &email).await
+            }
+            Query::AuthEmailVerify {
+                access,
+                refresh,
+                token,
+                code,
+                totp,
+                label,
             } => {
-                self.handle_sms_sent(&access, &refresh, &phone, &message)
+                self.handle_email_verify(&access, &refresh, &token, &code, &totp, &label)
                     .await
             }
 
+            // Account
+            Query::AuthAccountDelete { access, totp } => {
+                self.handle_account_delete(&access, &totp).await
+            }
+            Query::AuthAccountDeleteCancel { access } => {
+                self.handle_account_delete_cancel(&access).await
+            }
+
+            // Second factor
+            Query::AuthTotpEnroll { access, totp } => self.handle_totp_enroll(&access, &totp).await,
+            Query::AuthTotpVerify { access, code } => self.handle_totp_verify(&access, &code).await,
+            Query::AuthTotpDisable { access, code } => {
+                self.handle_totp_disable(&access, &code).await
+            }
+
             // Authenticate and pass to next layer
             q => {
                 let access = q.get_access();
-                let uid = self.authenticate(access).await?;
+                let uid = self.authenticate_for(access, (&q).into()).await?;
                 if uid.is_zero() {
                     return Err(Error::AuthNotAuthenticated);
                 }
+                self.limiter.check_uid(&uid).await?;
+                if !anonymous_allows(&q) && self.is_anonymous(&uid).await? {
+                    return Err(Error::AuthAnonymous);
+                }
+                if let Some(totp) = q.get_totp() {
+                    self.check_totp(&uid, totp).await?;
+                }
                 Ok(self.cost.handle(q, &uid).await?)
             }
         }
     }
 
-    /// Generate two random tokens.
+    /// Whether uid is an anonymous account, read once per full account.
+    async fn is_anonymous(&self, uid: &Id) -> Result<bool> {
+        if self.full.lock().unwrap().contains(uid) {
+            return Ok(false);
+        }
+        let anonymous = self.db.is_anonymous(uid).await?;
+        if !anonymous {
+            let mut full = self.full.lock().unwrap();
+            if full.len() > FULL_MAX_KEYS {
+                full.clear();
+            }
+            full.insert(*uid);
+        }
+        Ok(anonymous)
+    }
+
+    /// Generate an access token and a refresh token.
     async fn handle_session_start(&self) -> Result<Reply> {
-        let (access, refresh) = {
+        let uid = Id::zero();
+        let refresh = {
             let mut rng = rand::rng();
-            (Id::rand(&mut rng), Id::rand(&mut rng))
+            Id::rand(&mut rng)
         }; // drop rng before await
-        let uid = Id::zero();
+        let access = self.new_access(&uid, &refresh, FULL_SCOPE).await?;
 
-        // Store tokens in ScyllaDB
-        self.db.set_access(&access.0, &uid).await?;
+        // Store refresh token in ScyllaDB
         self.db.set_refresh(&refresh.0, &uid).await?;
 
         Ok(Reply::AuthSessionStart { access, refresh })
     }
 
+    /// Bind a session without phone or email to a new account with a small
+    /// credit grant. It is upgraded in place by AuthSmsSent or AuthEmailVerify.
+    async fn handle_session_anonymous(
+        &self,
+        access: &Access,
+        refresh: &Id,
+        label: &Option<String>,
+    ) -> Result<Reply> {
+        if !self.anonymous {
+            return Err(Error::AuthAnonymousDisabled);
+        }
+        let current = self.authenticate(access).await?;
+        if !current.is_zero() {
+            return Ok(Reply::AuthSessionAnonymous { uid: current });
+        }
+
+        let uid = {
+            let mut rng = rand::rng();
+            Id::rand(&mut rng)
+        };
+        let db = self.db;
+        db.set_anonymous(&uid).await?;
+        db.create_user_account(&uid).await?;
+        db.incr_credit(&uid, None, self.anonymous_credit, "AuthAnonymous")
+            .await?;
+
+        self.bind_session(&uid, access, refresh, label).await?;
+
+        Ok(Reply::AuthSessionAnonymous { uid })
+    }
+
     /// If refresh exists, reset its TTL, then generate a new access.
-    async fn handle_session_refresh(&self, refresh: &Id) -> Result<Reply> {
+    /// Signed access tokens may be narrowed to a scope.
+    async fn handle_session_refresh(&self, refresh: &Id, scope: &Option<String>) -> Result<Reply> {
+        if scope.is_some() && self.signer.is_none() {
+            return Err(Error::AuthScope);
+        }
         let uid = self
             .db
             .get_refresh_and_extend(&refresh.0)
             .await?
             .ok_or(Error::AuthInvalidRefreshToken)?;
 
+        let access = self
+            .new_access(&uid, refresh, scope.as_deref().unwrap_or(FULL_SCOPE))
+            .await?;
+
+        // Keep the login's label and age, and bump its last use
+        if !uid.is_zero() {
+            let (label, created) = self
+                .db
+                .get_session_info(&uid, &refresh.0)
+                .await?
+                .unwrap_or_else(|| (String::new(), Utc::now()));
+            self.index_login(&uid, &access, refresh, &label, created)
+                .await?;
+        }
+
+        Ok(Reply::AuthSessionRefresh { access })
+    }
+
+    /// Sign an access token for the login of refresh,
+    /// or store a random one in ScyllaDB.
+    async fn new_access(&self, uid: &Id, refresh: &Id, scope: &str) -> Result<Access> {
+        if let Some(signer) = &self.signer {
+            let now = Utc::now().timestamp();
+            return Ok(Access::Signed(signer.sign(
+                uid,
+                &session_handle(&refresh.0),
+                scope,
+                now,
+                self.access_ttl,
+            )));
+        }
         let access = {
             let mut rng = rand::rng();
             Id::rand(&mut rng)
         };
-
-        self.db.set_access(&access.0, &uid).await?;
-
-        Ok(Reply::AuthSessionRefresh { access })
+        self.db.set_access(&access.0, uid).await?;
+        Ok(Access::Opaque(access))
     }
 
     /// If access is valid, delete access and optionally refresh.
-    async fn handle_session_end(&self, access: &Id, option_refresh: &Option<Id>) -> Result<Reply> {
+    /// Signed access tokens cannot be deleted, they expire.
+    async fn handle_session_end(
+        &self,
+        access: &Access,
+        option_refresh: &Option<Id>,
+    ) -> Result<Reply> {
         let access_uid = self.authenticate(access).await?;
 
-        self.db.del_session(&access.0).await?;
+        if let Some(access) = access.opaque() {
+            self.db.del_session(&access.0).await?;
+            if !access_uid.is_zero() {
+                self.db.unindex_session(&access_uid, &access.0).await?;
+            }
+        }
 
         if let Some(refresh) = option_refresh {
             // Check if uid matches
@@ -105,21 +313,197 @@ impl Auth {
                 return Err(Error::AuthInvalidRefreshToken);
             }
             self.db.del_session(&refresh.0).await?;
+            if !access_uid.is_zero() {
+                self.db.unindex_session(&access_uid, &refresh.0).await?;
+            }
         }
 
         Ok(Reply::AuthSessionEnd)
     }
 
+    /// Set tokens' value to uid, and index them as a new login.
+    /// A signed access token keeps its uid, the client refreshes for a new one.
+    async fn bind_session(
+        &self,
+        uid: &Id,
+        access: &Access,
+        refresh: &Id,
+        label: &Option<String>,
+    ) -> Result<()> {
+        use crate::config::SESSION_LABEL_MAX_CHARS;
+
+        if let Some(access) = access.opaque() {
+            self.db.set_access(&access.0, uid).await?;
+        }
+        self.db.set_refresh(&refresh.0, uid).await?;
+
+        let label: String = label
+            .as_deref()
+            .unwrap_or_default()
+            .trim()
+            .chars()
+            .filter(|c| !c.is_control())
+            .take(SESSION_LABEL_MAX_CHARS)
+            .collect();
+        self.index_login(uid, access, refresh, &label, Utc::now())
+            .await
+    }
+
+    /// Index the tokens of a login kept in ScyllaDB.
+    async fn index_login(
+        &self,
+        uid: &Id,
+        access: &Access,
+        refresh: &Id,
+        label: &str,
+        created: DateTime<Utc>,
+    ) -> Result<()> {
+        let db = self.db;
+        if let Some(access) = access.opaque() {
+            db.index_session(uid, &refresh.0, &access.0, 0, label, created)
+                .await?;
+        }
+        db.index_session(uid, &refresh.0, &refresh.0, 1, label, created)
+            .await
+    }
+
+    /// List tokens of the user as JSON, grouped by login handle.
+    async fn handle_session_list(&self, access: &Access) -> Result<Reply> {
+        let uid = self.authenticate_user(access).await?;
+        let sessions = self.db.list_sessions(&uid).await?;
+
+        let current = access.opaque().and_then(|access| {
+            sessions
+                .iter()
+                .find(|s| s.sid[..] == access.0[..])
+                .map(|s| s.handle.clone())
+        });
+        let sessions: Vec<_> = sessions
+            .iter()
+            .map(|s| {
+                serde_json::json!({
+                    "handle": hex::encode(&s.handle),
+                    "kind": if s.kind == 0 { "access" } else { "refresh" },
+                    "label": s.label,
+                    "created": s.created,
+                    "last_used": s.last_used,
+                    "current": current.as_ref() == Some(&s.handle),
+                })
+            })
+            .collect();
+
+        Ok(Reply::AuthSessionList {
+            sessions: serde_json::to_string(&sessions)?,
+        })
+    }
+
+    /// Revoke all tokens of a login by its handle.
+    async fn handle_session_revoke(
+        &self,
+        access: &Access,
+        handle: &str,
+        totp: &Option<String>,
+    ) -> Result<Reply> {
+        let uid = self.authenticate_user(access).await?;
+        self.check_totp(&uid, totp).await?;
+        let handle = hex::decode(handle).map_err(|_| Error::AuthInvalidSessionHandle)?;
+        if !self.db.revoke_session(&uid, &handle).await? {
+            return Err(Error::AuthInvalidSessionHandle);
+        }
+        Ok(Reply::AuthSessionRevoke)
+    }
+
+    /// Revoke every login of the user, including this one.
+    async fn handle_session_revoke_all(
+        &self,
+        access: &Access,
+        totp: &Option<String>,
+    ) -> Result<Reply> {
+        let uid = self.authenticate_user(access).await?;
+        self.check_totp(&uid, totp).await?;
+        self.db.revoke_all_sessions(&uid).await?;
+        // Tokens bound before the index existed
+        if let Some(access) = access.opaque() {
+            self.db.del_session(&access.0).await?;
+        }
+        Ok(Reply::AuthSessionRevokeAll)
+    }
+
     /// Query UID from access token, zero is anonymous.
-    async fn authenticate(&self, access: &Id) -> Result<Id> {
-        self.db
-            .get_access(&access.0)
-            .await?
-            .ok_or(Error::AuthInvalidAccessToken)
+    /// Signed tokens need the full scope for Auth queries.
+    async fn authenticate(&self, access: &Access) -> Result<Id> {
+        self.authenticate_for(access, FULL_SCOPE).await
+    }
+
+    /// Query UID from access token for a query type.
+    async fn authenticate_for(&self, access: &Access, query: &str) -> Result<Id> {
+        match access {
+            Access::Opaque(access) => {
+                let uid = self
+                    .db
+                    .get_access(&access.0)
+                    .await?
+                    .ok_or(Error::AuthInvalidAccessToken)?;
+                if !uid.is_zero() {
+                    self.touch_session(&uid, access);
+                }
+                Ok(uid)
+            }
+            Access::Signed(token) => {
+                let signer = self.signer.as_ref().ok_or(Error::AuthInvalidAccessToken)?;
+                let claims = signer.verify(token, Utc::now().timestamp())?;
+                if !token::allows(&claims.scope, query) {
+                    return Err(Error::AuthScope);
+                }
+                let handle = hex::decode(&claims.sid).unwrap_or_default();
+                if !claims.sub.is_zero() && self.db.access_revoked(&claims.sub, &handle, claims.iat)
+                {
+                    return Err(Error::AuthInvalidAccessToken);
+                }
+                Ok(claims.sub)
+            }
+        }
+    }
+
+    /// Bump last_used of the session in the background,
+    /// at most once per SESSION_TOUCH_INTERVAL for each access token.
+    fn touch_session(&self, uid: &Id, access: &Id) {
+        use crate::config::SESSION_TOUCH_INTERVAL;
+
+        let now = Utc::now().timestamp();
+        {
+            let mut touched = self.touched.lock().unwrap();
+            if touched
+                .get(access)
+                .is_some_and(|t| now - t < SESSION_TOUCH_INTERVAL)
+            {
+                return;
+            }
+            if touched.len() > TOUCHED_MAX_KEYS {
+                touched.retain(|_, t| now - *t < SESSION_TOUCH_INTERVAL);
+            }
+            touched.insert(*access, now);
+        } // drop lock before spawn
+
+        let (db, uid, access) = (self.db, *uid, *access);
+        tokio::spawn(async move {
+            if let Err(error) = db.touch_session(&uid, &access.0).await {
+                println!("Touch session error for {}: {}", uid, error);
+            }
+        });
+    }
+
+    /// Like authenticate, but anonymous sessions are rejected.
+    async fn authenticate_user(&self, access: &Access) -> Result<Id> {
+        let uid = self.authenticate(access).await?;
+        if uid.is_zero() {
+            return Err(Error::AuthNotAuthenticated);
+        }
+        Ok(uid)
     }
 
     /// Send what to who to authenticate.
-    async fn handle_sms_send_to(&self, access: &Id) -> Result<Reply> {
+    async fn handle_sms_send_to(&self, access: &Access) -> Result<Reply> {
         self.authenticate(access).await?;
 
         let (phone, message): (&'static _, _) = {
@@ -136,15 +520,30 @@ impl Auth {
         Ok(Reply::AuthSmsSendTo { phone, message })
     }
 
+    /// Record an SMS reported by the provider webhook.
+    pub async fn handle_sms_webhook(&self, headers: &HeaderMap, body: &[u8]) -> Result<()> {
+        let provider = self.sms.as_ref().ok_or(Error::AuthSmsProvider)?;
+        let sms = provider.parse(headers, body)?;
+
+        if !self.phones.contains(&sms.to) {
+            return Err(Error::AuthInvalidPhone);
+        }
+        let message = Id::from_str(sms.body.trim())?;
+
+        self.db.sms_sent(&sms.from, &sms.to, &message.0).await
+    }
+
     /// If sent, set tokens' value to uid.
     async fn handle_sms_sent(
         &self,
-        access: &Id,
+        access: &Access,
         refresh: &Id,
         phone: &str,
         message: &Id,
+        totp: &Option<String>,
+        label: &Option<String>,
     ) -> Result<Reply> {
-        self.authenticate(access).await?;
+        let current = self.authenticate(access).await?;
         let db = self.db;
 
         // Find user's phone from SMS sent records
@@ -156,20 +555,44 @@ impl Auth {
                 .ok_or(Error::AuthInvalidPhone)?
         };
 
-        // Find user's uid by phone
-        let mut is_new_user = false;
-        let uid = match db.get_phone_to_uid(&user_phone).await? {
-            Some(uid) => uid,
+        // Find user's uid by phone, or by the phone it moved away from,
+        // or link the phone to current uid
+        let mut recovered = false;
+        let known = match db.get_phone_to_uid(&user_phone).await? {
+            Some(uid) => Some(uid),
             None => {
-                is_new_user = true;
-                let mut rng = rand::rng();
-                Id::rand(&mut rng)
+                let moved = db.get_phone_tombstone(&user_phone).await?;
+                recovered = moved.is_some();
+                moved
             }
         };
+        let has_phone =
+            known.is_none() && !current.is_zero() && db.get_uid_to_phone(&current).await?.is_some();
+        let (uid, is_new_user) = link_or_new(current, known, has_phone);
+
+        // Signing in to another account needs its second factor
+        if uid != current && !is_new_user {
+            self.check_totp(&uid, totp).await?;
+        }
+
+        // Undo the phone change, and sign out whoever made it
+        if recovered {
+            let moved_to = db.get_uid_to_phone(&uid).await?;
+            db.revoke_all_sessions(&uid).await?;
+            db.change_phone(
+                &uid,
+                moved_to.as_deref(),
+                &user_phone,
+                false,
+                "PhoneRecover",
+            )
+            .await?;
+        }
 
         // Create or refresh UID <-> Phone mappings
         db.set_uid_to_phone(&uid, &user_phone).await?;
         db.set_phone_to_uid(&user_phone, &uid).await?;
+        db.del_anonymous(&uid).await?;
 
         // Create user account in TigerBeetle if new
         if is_new_user {
@@ -177,11 +600,305 @@ impl Auth {
         }
 
         // Set uid of auth tokens
-        db.set_access(&access.0, &uid).await?;
-        db.set_refresh(&refresh.0, &uid).await?;
+        self.bind_session(&uid, access, refresh, label).await?;
 
         Ok(Reply::AuthSmsSent { uid })
     }
+
+    /// Move the account to the phone that sent the message.
+    async fn handle_phone_change(
+        &self,
+        access: &Access,
+        phone: &str,
+        message: &Id,
+        totp: &Option<String>,
+    ) -> Result<Reply> {
+        let uid = self.authenticate_user(access).await?;
+        self.check_totp(&uid, totp).await?;
+        let db = self.db;
+
+        let new_phone = if self.skip_auth {
+            phone.to_owned()
+        } else {
+            db.get_sms_sent(phone, &message.0)
+                .await?
+                .ok_or(Error::AuthInvalidPhone)?
+        };
+
+        match db.get_phone_to_uid(&new_phone).await? {
+            Some(owner) if owner == uid => return Ok(Reply::AuthPhoneChange),
+            Some(_) => return Err(Error::AuthPhoneTaken),
+            None => {}
+        }
+        // Others may recover their account with it for now
+        if db
+            .get_phone_tombstone(&new_phone)
+            .await?
+            .is_some_and(|owner| owner != uid)
+        {
+            return Err(Error::AuthPhoneCooldown);
+        }
+
+        let old_phone = db.get_uid_to_phone(&uid).await?;
+        db.change_phone(&uid, old_phone.as_deref(), &new_phone, true, "PhoneChange")
+            .await?;
+
+        Ok(Reply::AuthPhoneChange)
+    }
+
+    /// Mail a magic link for the email.
+    async fn handle_email_start(&self, access: &Access, email: &str) -> Result<Reply> {
+        self.authenticate(access).await?;
+        let mail = self.mail.as_ref().ok_or(Error::AuthMailTransport)?;
+        let email = normalize_email(email)?;
+
+        let (token, code) = {
+            let mut rng = rand::rng();
+            (Id::rand(&mut rng), Id::rand(&mut rng))
+        };
+
+        self.db.set_email_code(&token.0, &code.0, &email).await?;
+
+        let link = format!("{}?token={}&code={}", self.email_link, token, code);
+        let body = format!(
+            "Open this link to sign in to VOxOV:\r\n\r\n{}\r\n\r\nOr enter this code: {}",
+            link, code
+        );
+        mail.send(&email, "Sign in to VOxOV", &body).await?;
+
+        Ok(Reply::AuthEmailStart { token })
+    }
+
+    /// The magic link lands here.
+    pub async fn handle_email_click(&self, token: &Id, code: &Id) -> Result<()> {
+        let (expected, _, _) = self
+            .db
+            .get_email_code(&token.0)
+            .await?
+            .ok_or(Error::AuthInvalidEmailToken)?;
+        if expected != code.0 {
+            return Err(Error::AuthInvalidEmailToken);
+        }
+        self.db.set_email_verified(&token.0).await
+    }
+
+    /// If the link was opened or the code matches, set tokens' value to uid.
+    async fn handle_email_verify(
+        &self,
+        access: &Access,
+        refresh: &Id,
+        token: &Id,
+        code: &Option<Id>,
+        totp: &Option<String>,
+        label: &Option<String>,
+    ) -> Result<Reply> {
+        let current = self.authenticate(access).await?;
+        let db = self.db;
+
+        // Find user's email from magic link records
+        let (expected, email, clicked) = db
+            .get_email_code(&token.0)
+            .await?
+            .ok_or(Error::AuthInvalidEmailToken)?;
+        let typed = code.as_ref().is_some_and(|code| code.0[..] == expected[..]);
+        if !(clicked || typed || self.skip_auth) {
+            return Err(Error::AuthEmailNotVerified);
+        }
+
+        // Find user's uid by email, or link the email to current uid
+        let known = db.get_email_to_uid(&email).await?;
+        let has_email =
+            known.is_none() && !current.is_zero() && db.get_uid_to_email(&current).await?.is_some();
+        let (uid, is_new_user) = link_or_new(current, known, has_email);
+
+        // Signing in to another account needs its second factor,
+        // keep the email code for a retry with it
+        if uid != current && !is_new_user {
+            self.check_totp(&uid, totp).await?;
+        }
+        db.del_email_code(&token.0).await?;
+
+        // Create or refresh UID <-> Email mappings
+        db.set_uid_to_email(&uid, &email).await?;
+        db.set_email_to_uid(&email, &uid).await?;
+        db.del_anonymous(&uid).await?;
+
+        if is_new_user {
+            db.create_user_account(&uid).await?;
+        }
+
+        // Set uid of auth tokens
+        self.bind_session(&uid, access, refresh, label).await?;
+
+        Ok(Reply::AuthEmailVerify { uid })
+    }
+
+    /// Export the account to an archive meme, then schedule its deletion.
+    /// A pending deletion keeps its archive and due time.
+    async fn handle_account_delete(&self, access: &Access, totp: &Option<String>) -> Result<Reply> {
+        let uid = self.authenticate_user(access).await?;
+        self.check_totp(&uid, totp).await?;
+        if let Some((archive, due)) = self.db.get_account_deletion(&uid).await? {
+            return Ok(Reply::AuthAccountDelete { archive, due });
+        }
+
+        // The archive lives until the account does
+        let due = Utc::now() + Duration::seconds(self.account_delete_grace);
+        let export = self.db.export_account(&uid).await?;
+        let archive = self
+            .db
+            .put_archive(&uid, serde_json::to_vec_pretty(&export)?, due)
+            .await?;
+        self.db
+            .schedule_account_deletion(&uid, &archive, due)
+            .await?;
+
+        Ok(Reply::AuthAccountDelete { archive, due })
+    }
+
+    /// Keep the account during the grace period.
+    async fn handle_account_delete_cancel(&self, access: &Access) -> Result<Reply> {
+        let uid = self.authenticate_user(access).await?;
+        if !self.db.cancel_account_deletion(&uid).await? {
+            return Err(Error::AuthAccountNotScheduled);
+        }
+        Ok(Reply::AuthAccountDeleteCancel)
+    }
+
+    /// Pass if uid has no TOTP enabled, or the code is a valid TOTP
+    /// or an unused recovery code. Attempts are limited per account.
+    async fn check_totp(&self, uid: &Id, code: &Option<String>) -> Result<()> {
+        let record = match self.db.get_totp(uid).await? {
+            Some(record) if record.enabled => record,
+            _ => return Ok(()),
+        };
+        let code = code.as_deref().ok_or(Error::AuthTotpRequired)?.trim();
+        self.limiter.check_totp(uid).await?;
+
+        if let Some(step) = totp::verify(
+            &record.secret,
+            code,
+            Utc::now().timestamp(),
+            record.last_step,
+        ) {
+            return self.db.set_totp_step(uid, step).await;
+        }
+
+        let hash = totp::hash_recovery_code(code);
+        if record.recovery.iter().any(|h| h[..] == hash[..]) {
+            return self.db.del_totp_recovery(uid, &hash).await;
+        }
+
+        Err(Error::AuthTotpInvalid)
+    }
+
+    /// Start a pending enrollment. Replacing an enabled one needs its code.
+    async fn handle_totp_enroll(&self, access: &Access, code: &Option<String>) -> Result<Reply> {
+        let uid = self.authenticate_user(access).await?;
+        self.check_totp(&uid, code).await?;
+
+        let (secret, codes) = {
+            let mut rng = rand::rng();
+            (
+                totp::new_secret(&mut rng),
+                totp::new_recovery_codes(&mut rng),
+            )
+        };
+        let hashes = codes
+            .iter()
+            .map(|c| totp::hash_recovery_code(c).to_vec())
+            .collect();
+
+        self.db.set_totp(&uid, &secret, hashes).await?;
+
+        Ok(Reply::AuthTotpEnroll {
+            secret: totp::encode_secret(&secret),
+            uri: totp::provisioning_uri(&secret, TOTP_ISSUER, &uid.to_string()),
+            recovery: codes.join(","),
+        })
+    }
+
+    /// Enable TOTP with the first code from the app.
+    async fn handle_totp_verify(&self, access: &Access, code: &str) -> Result<Reply> {
+        let uid = self.authenticate_user(access).await?;
+        let record = self
+            .db
+            .get_totp(&uid)
+            .await?
+            .ok_or(Error::AuthTotpNotEnrolled)?;
+        self.limiter.check_totp(&uid).await?;
+
+        let step = totp::verify(
+            &record.secret,
+            code.trim(),
+            Utc::now().timestamp(),
+            record.last_step,
+        )
+        .ok_or(Error::AuthTotpInvalid)?;
+        self.db.enable_totp(&uid, step).await?;
+
+        Ok(Reply::AuthTotpVerify)
+    }
+
+    /// Remove TOTP with a valid code.
+    async fn handle_totp_disable(&self, access: &Access, code: &str) -> Result<Reply> {
+        let uid = self.authenticate_user(access).await?;
+        self.db
+            .get_totp(&uid)
+            .await?
+            .ok_or(Error::AuthTotpNotEnrolled)?;
+        self.check_totp(&uid, &Some(code.to_string())).await?;
+        self.db.del_totp(&uid).await?;
+
+        Ok(Reply::AuthTotpDisable)
+    }
+}
+
+/// Anonymous accounts may only read public data.
+fn anonymous_allows(query: &Query) -> bool {
+    match query {
+        Query::CostGet { .. } | Query::GeneMeta { .. } | Query::MemeMeta { .. } => true,
+        Query::MemeGet { public, .. } => *public,
+        Query::GeneCall { gid, arg, .. } => match gid.as_str() {
+            "info_1" => true,
+            "map_1" => serde_json::from_str::<serde_json::Value>(arg)
+                .is_ok_and(|arg| arg["_type"] == "Get"),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Known identities sign in to their uid. New identities link to the current
+/// uid if it holds none of their kind, otherwise they start a new account.
+/// Returns the uid and whether it is new.
+fn link_or_new(current: Id, known: Option<Id>, current_has_kind: bool) -> (Id, bool) {
+    match known {
+        Some(uid) => (uid, false),
+        None if !current.is_zero() && !current_has_kind => (current, false),
+        None => {
+            let mut rng = rand::rng();
+            (Id::rand(&mut rng), true)
+        }
+    }
+}
+
+/// Lowercase and sanity check an email address.
+fn normalize_email(email: &str) -> Result<String> {
+    use crate::config::EMAIL_MAX_BYTES;
+
+    let email = email.trim().to_lowercase();
+    let valid = email.len() <= EMAIL_MAX_BYTES
+        && !email
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || c == '/' || c == '\\')
+        && matches!(email.split_once('@'), Some((local, domain))
+            if !local.is_empty() && domain.contains('.') && !domain.contains('@'));
+    if valid {
+        Ok(email)
+    } else {
+        Err(Error::AuthInvalidEmail)
+    }
 }
 
 /// Build namespaced key from phone and message (kept for compatibility).
@@ -0,0 +1,75 @@
+//! Mail transports for email authentication.
+
+use crate::config::Config;
+use crate::{Error, Result};
+use lettre::message::header::ContentType;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use std::path::PathBuf;
+use std::pin::Pin;
+
+pub type MailFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// Delivers plain text mails.
+pub trait MailTransport: Send + Sync {
+    fn send<'a>(&'a self, to: &'a str, subject: &'a str, body: &'a str) -> MailFuture<'a>;
+}
+
+/// Select the transport by config, None disables email authentication.
+pub fn new_transport(config: &Config) -> Option<Box<dyn MailTransport>> {
+    match config.mail_transport.as_str() {
+        "smtp" => Some(Box::new(Smtp {
+            transport: AsyncSmtpTransport::<Tokio1Executor>::from_url(&config.smtp_url)
+                .expect("Invalid SMTP_URL")
+                .build(),
+            from: config.mail_from.clone(),
+        })),
+        "file" => Some(Box::new(FileDrop {
+            dir: PathBuf::from(&config.mail_dir),
+        })),
+        _ => None,
+    }
+}
+
+/// Send through an SMTP relay.
+pub struct Smtp {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: String,
+}
+
+impl MailTransport for Smtp {
+    fn send<'a>(&'a self, to: &'a str, subject: &'a str, body: &'a str) -> MailFuture<'a> {
+        Box::pin(async move {
+            let message = Message::builder()
+                .from(self.from.parse().map_err(|_| Error::AuthMail)?)
+                .to(to.parse().map_err(|_| Error::AuthInvalidEmail)?)
+                .subject(subject)
+                .header(ContentType::TEXT_PLAIN)
+                .body(body.to_string())
+                .map_err(|_| Error::AuthMail)?;
+            self.transport
+                .send(message)
+                .await
+                .map_err(|_| Error::AuthMail)?;
+            Ok(())
+        })
+    }
+}
+
+/// Write each mail to DIR/TO.eml, replacing older ones. For tests only.
+pub struct FileDrop {
+    dir: PathBuf,
+}
+
+impl MailTransport for FileDrop {
+    fn send<'a>(&'a self, to: &'a str, subject: &'a str, body: &'a str) -> MailFuture<'a> {
+        Box::pin(async move {
+            tokio::fs::create_dir_all(&self.dir)
+                .await
+                .map_err(|_| Error::AuthMail)?;
+            let mail = format!("To: {}\r\nSubject: {}\r\n\r\n{}\r\n", to, subject, body);
+            tokio::fs::write(self.dir.join(format!("{}.eml", to)), mail)
+                .await
+                .map_err(|_| Error::AuthMail)
+        })
+    }
+}
@@ -0,0 +1,175 @@
+//! Inbound SMS providers.
+//!
+//! Carriers call the webhook when a user texts the message from
+//! AuthSmsSendTo to one of auth_phones, so no one has to run `vctl sent`.
+
+use crate::config::{Config, PHONE_MAX_BYTES};
+use crate::{Error, Result};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use hmac::{Hmac, Mac};
+use http::HeaderMap;
+use sha1::Sha1;
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// An SMS received by one of auth_phones.
+#[derive(Debug, PartialEq)]
+pub struct InboundSms {
+    /// The user's phone.
+    pub from: String,
+    /// One of auth_phones.
+    pub to: String,
+    /// Text, expected to be the message from AuthSmsSendTo.
+    pub body: String,
+}
+
+/// Turns a webhook call into an inbound SMS.
+pub trait SmsProvider: Send + Sync {
+    /// Verify the request signature, then parse the payload.
+    fn parse(&self, headers: &HeaderMap, body: &[u8]) -> Result<InboundSms>;
+}
+
+/// Select the provider by config, None disables the webhook.
+pub fn new_provider(config: &Config) -> Option<Box<dyn SmsProvider>> {
+    match config.sms_provider.as_str() {
+        "twilio" => Some(Box::new(Twilio {
+            url: config.sms_webhook_url.clone(),
+            token: config.sms_auth_token.clone(),
+        })),
+        "fake" => Some(Box::new(Fake)),
+        _ => None,
+    }
+}
+
+/// Twilio-style payload: form-encoded From, To and Body,
+/// signed with HMAC-SHA1 over the URL and the sorted parameters.
+pub struct Twilio {
+    url: String,
+    token: String,
+}
+
+impl Twilio {
+    fn mac(&self, params: &[(String, String)]) -> HmacSha1 {
+        let mut sorted: Vec<_> = params.iter().collect();
+        sorted.sort();
+        let mut mac =
+            HmacSha1::new_from_slice(self.token.as_bytes()).expect("HMAC takes any key size");
+        mac.update(self.url.as_bytes());
+        for (k, v) in sorted {
+            mac.update(k.as_bytes());
+            mac.update(v.as_bytes());
+        }
+        mac
+    }
+}
+
+impl SmsProvider for Twilio {
+    fn parse(&self, headers: &HeaderMap, body: &[u8]) -> Result<InboundSms> {
+        let params = parse_form(body);
+
+        let signature = headers
+            .get("x-twilio-signature")
+            .and_then(|v| v.to_str().ok())
+            .ok_or(Error::AuthSmsSignature)?;
+        let signature = STANDARD
+            .decode(signature)
+            .map_err(|_| Error::AuthSmsSignature)?;
+        self.mac(&params)
+            .verify_slice(&signature)
+            .map_err(|_| Error::AuthSmsSignature)?;
+
+        from_params(&params, "From", "To", "Body")
+    }
+}
+
+/// Trusts any payload of the same shape. For tests only.
+pub struct Fake;
+
+impl SmsProvider for Fake {
+    fn parse(&self, _headers: &HeaderMap, body: &[u8]) -> Result<InboundSms> {
+        from_params(&parse_form(body), "From", "To", "Body")
+    }
+}
+
+fn parse_form(body: &[u8]) -> Vec<(String, String)> {
+    form_urlencoded::parse(body).into_owned().collect()
+}
+
+fn from_params(
+    params: &[(String, String)],
+    from: &str,
+    to: &str,
+    body: &str,
+) -> Result<InboundSms> {
+    let get = |key: &str| {
+        params
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.clone())
+            .ok_or(Error::ApiMissingEntry)
+    };
+    let sms = InboundSms {
+        from: get(from)?,
+        to: get(to)?,
+        body: get(body)?,
+    };
+    if sms.from.is_empty() || sms.from.len() > PHONE_MAX_BYTES {
+        return Err(Error::AuthInvalidPhone);
+    }
+    Ok(sms)
+}
+
+#[test]
+fn test_twilio_signature() {
+    let twilio = Twilio {
+        url: "https://mycompany.com/myapp.php?foo=1&bar=2".into(),
+        token: "12345".into(),
+    };
+    let params: Vec<(String, String)> = [
+        ("CallSid", "CA1234567890ABCDE"),
+        ("Caller", "+12349013030"),
+        ("Digits", "1234"),
+        ("From", "+12349013030"),
+        ("To", "+18005551212"),
+    ]
+    .iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect();
+    let signature = STANDARD.encode(twilio.mac(&params).finalize().into_bytes());
+    assert_eq!(signature, "0/KCTR6DLpKmkAf8muzZqo1nDgQ=");
+}
+
+#[test]
+fn test_twilio_parse() {
+    let twilio = Twilio {
+        url: "https://example.com/sms".into(),
+        token: "secret".into(),
+    };
+    let body = b"From=%2B15550001111&To=12345&Body=00112233445566778899aabbccddeeff";
+    let signature = STANDARD.encode(twilio.mac(&parse_form(body)).finalize().into_bytes());
+
+    let mut headers = HeaderMap::new();
+    headers.insert("x-twilio-signature", signature.parse().unwrap());
+    let sms = twilio.parse(&headers, body).unwrap();
+    assert_eq!(sms.from, "+15550001111");
+    assert_eq!(sms.to, "12345");
+
+    let tampered = b"From=%2B15550002222&To=12345&Body=00112233445566778899aabbccddeeff";
+    assert!(twilio.parse(&headers, tampered).is_err());
+    assert!(twilio.parse(&HeaderMap::new(), body).is_err());
+}
+
+#[test]
+fn test_fake_parse() {
+    let sms = Fake
+        .parse(&HeaderMap::new(), b"From=67890&To=12345&Body=hello")
+        .unwrap();
+    assert_eq!(
+        sms,
+        InboundSms {
+            from: "67890".into(),
+            to: "12345".into(),
+            body: "hello".into(),
+        }
+    );
+}
@@ -0,0 +1,180 @@
+//! Signed access tokens, JWT with HS256.
+//!
+//! They are verified without looking up the token. Refresh tokens stay in
+//! ScyllaDB, and revoking a login denies the access tokens issued to it so far
+//! by a per-account list, which Auth reads once per request.
+
+use crate::ir::Id;
+use crate::{Error, Result};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Scope of unrestricted tokens.
+pub const FULL_SCOPE: &str = "*";
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    alg: String,
+    typ: String,
+    kid: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    /// The uid, zero is anonymous.
+    pub sub: Id,
+    /// Hex handle of the login, see database::session_handle.
+    #[serde(default)]
+    pub sid: String,
+    pub iat: i64,
+    pub exp: i64,
+    /// Space separated prefixes of query types, * for all.
+    pub scope: String,
+}
+
+/// Signs with the first key and verifies with any of them,
+/// so a new key can be put in front before the old one is dropped.
+pub struct Signer {
+    keys: Vec<(String, Vec<u8>)>,
+}
+
+impl Signer {
+    /// Parse keys like "kid:secret,kid:secret", None if there is none.
+    pub fn new(keys: &str) -> Option<Signer> {
+        let keys: Vec<_> = keys
+            .split(',')
+            .filter_map(|k| k.trim().split_once(':'))
+            .filter(|(kid, secret)| !kid.is_empty() && !secret.is_empty())
+            .map(|(kid, secret)| (kid.to_string(), secret.as_bytes().to_vec()))
+            .collect();
+        if keys.is_empty() {
+            None
+        } else {
+            Some(Signer { keys })
+        }
+    }
+
+    /// Issue a token for uid of the login with handle sid,
+    /// valid for ttl seconds from now.
+    pub fn sign(&self, uid: &Id, sid: &[u8], scope: &str, now: i64, ttl: i64) -> String {
+        let (kid, secret) = &self.keys[0];
+        let header = Header {
+            alg: "HS256".into(),
+            typ: "JWT".into(),
+            kid: kid.clone(),
+        };
+        let claims = Claims {
+            sub: *uid,
+            sid: hex::encode(sid),
+            iat: now,
+            exp: now + ttl,
+            scope: scope.into(),
+        };
+        let input = format!("{}.{}", encode(&header), encode(&claims));
+        let signature = URL_SAFE_NO_PAD.encode(mac(secret, &input).finalize().into_bytes());
+        format!("{}.{}", input, signature)
+    }
+
+    /// Check signature and expiry at unix time now.
+    pub fn verify(&self, token: &str, now: i64) -> Result<Claims> {
+        let (input, signature) = token
+            .rsplit_once('.')
+            .ok_or(Error::AuthInvalidAccessToken)?;
+        let (header, claims) = input.split_once('.').ok_or(Error::AuthInvalidAccessToken)?;
+
+        let header: Header = decode(header)?;
+        if header.alg != "HS256" {
+            return Err(Error::AuthInvalidAccessToken);
+        }
+        let (_, secret) = self
+            .keys
+            .iter()
+            .find(|(kid, _)| *kid == header.kid)
+            .ok_or(Error::AuthInvalidAccessToken)?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| Error::AuthInvalidAccessToken)?;
+        mac(secret, input)
+            .verify_slice(&signature)
+            .map_err(|_| Error::AuthInvalidAccessToken)?;
+
+        let claims: Claims = decode(claims)?;
+        if claims.exp <= now {
+            return Err(Error::AuthAccessExpired);
+        }
+        Ok(claims)
+    }
+}
+
+/// Whether scope allows the query type. A prefix like Meme allows
+/// MemeGet and MemePut, only * allows FULL_SCOPE.
+pub fn allows(scope: &str, query: &str) -> bool {
+    scope
+        .split_whitespace()
+        .any(|s| s == FULL_SCOPE || (query != FULL_SCOPE && query.starts_with(s)))
+}
+
+fn mac(secret: &[u8], input: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes any key size");
+    mac.update(input.as_bytes());
+    mac
+}
+
+fn encode<T: Serialize>(value: &T) -> String {
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(value).expect("Plain struct"))
+}
+
+fn decode<T: DeserializeOwned>(s: &str) -> Result<T> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(s)
+        .map_err(|_| Error::AuthInvalidAccessToken)?;
+    serde_json::from_slice(&bytes).map_err(|_| Error::AuthInvalidAccessToken)
+}
+
+#[test]
+fn test_sign_verify() {
+    let signer = Signer::new("k1:secret").unwrap();
+    let uid = Id([7; 16]);
+    let token = signer.sign(&uid, &[1; 8], FULL_SCOPE, 1000, 60);
+    let claims = signer.verify(&token, 1059).unwrap();
+    assert_eq!(claims.sub, uid);
+    assert_eq!(claims.sid, "0101010101010101");
+    assert_eq!(claims.scope, FULL_SCOPE);
+
+    assert!(signer.verify(&token, 1060).is_err());
+    let mut tampered = token.clone();
+    tampered.insert(tampered.find('.').unwrap() + 2, 'x');
+    assert!(signer.verify(&tampered, 1000).is_err());
+    assert!(
+        Signer::new("k1:other")
+            .unwrap()
+            .verify(&token, 1000)
+            .is_err()
+    );
+}
+
+#[test]
+fn test_rotation() {
+    let old = Signer::new("k1:old").unwrap();
+    let new = Signer::new("k2:new, k1:old").unwrap();
+    let token = old.sign(&Id::zero(), &[], FULL_SCOPE, 0, 60);
+    assert!(new.verify(&token, 0).is_ok());
+    let token = new.sign(&Id::zero(), &[], FULL_SCOPE, 0, 60);
+    assert!(old.verify(&token, 0).is_err());
+    assert!(Signer::new("").is_none());
+}
+
+#[test]
+fn test_allows() {
+    assert!(allows("*", "MemeGet"));
+    assert!(allows("*", FULL_SCOPE));
+    assert!(allows("Meme GeneCall", "MemePut"));
+    assert!(allows("Meme GeneCall", "GeneCall"));
+    assert!(!allows("Meme GeneCall", "GeneMeta"));
+    assert!(!allows("Meme", FULL_SCOPE));
+}
@@ -0,0 +1,116 @@
+//! Time-based one-time passwords (RFC 6238) as the second factor.
+//!
+//! HMAC-SHA1, 6 digits and 30 seconds per step, the defaults of most apps.
+
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha1::Sha1;
+
+/// Seconds per step.
+const PERIOD: i64 = 30;
+
+/// Accepted steps before and after now, for clock drift.
+const DRIFT: i64 = 1;
+
+/// Number of recovery codes per enrollment.
+const RECOVERY_CODES: usize = 8;
+
+/// Generate a 160-bit secret.
+pub fn new_secret(rng: &mut impl Rng) -> [u8; 20] {
+    rng.random()
+}
+
+/// Base32 form of the secret for manual entry.
+pub fn encode_secret(secret: &[u8]) -> String {
+    BASE32_NOPAD.encode(secret)
+}
+
+/// The otpauth URI that authenticator apps scan as a QR code.
+pub fn provisioning_uri(secret: &[u8], issuer: &str, account: &str) -> String {
+    let issuer: String = form_urlencoded::byte_serialize(issuer.as_bytes()).collect();
+    let account: String = form_urlencoded::byte_serialize(account.as_bytes()).collect();
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits=6&period={}",
+        issuer,
+        account,
+        encode_secret(secret),
+        issuer,
+        PERIOD
+    )
+}
+
+/// The 6-digit code of a step.
+pub fn code(secret: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes any key size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[19] & 0xf) as usize;
+    let binary = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    binary % 1_000_000
+}
+
+/// Return the matching step if code is valid at unix time now,
+/// and is newer than last_step to prevent replays.
+pub fn verify(secret: &[u8], code: &str, now: i64, last_step: i64) -> Option<i64> {
+    if code.len() != 6 {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let step = now / PERIOD;
+    (step - DRIFT..=step + DRIFT).find(|&s| s > last_step && self::code(secret, s) == code)
+}
+
+/// Generate one-shot recovery codes like 0a1b2-c3d4e.
+pub fn new_recovery_codes(rng: &mut impl Rng) -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let s = hex::encode(rng.random::<[u8; 5]>());
+            format!("{}-{}", &s[..5], &s[5..])
+        })
+        .collect()
+}
+
+/// Recovery codes are stored hashed.
+pub fn hash_recovery_code(code: &str) -> [u8; 32] {
+    blake3::hash(code.trim().to_lowercase().as_bytes()).into()
+}
+
+#[test]
+fn test_rfc6238() {
+    let secret = b"12345678901234567890";
+    for (time, expected) in [
+        (59, 287082),
+        (1111111109, 81804),
+        (1111111111, 50471),
+        (1234567890, 5924),
+        (2000000000, 279037),
+    ] {
+        assert_eq!(code(secret, time / PERIOD), expected);
+    }
+}
+
+#[test]
+fn test_verify() {
+    let secret = b"12345678901234567890";
+    let now = 1111111109;
+    let step = now / PERIOD;
+    let at = |s: i64| format!("{:06}", code(secret, s));
+
+    assert_eq!(verify(secret, &at(step), now, 0), Some(step));
+    assert_eq!(verify(secret, &at(step - 1), now, 0), Some(step - 1));
+    assert_eq!(verify(secret, &at(step + 1), now, 0), Some(step + 1));
+    assert_eq!(verify(secret, &at(step + 2), now, 0), None);
+    // Replay
+    assert_eq!(verify(secret, &at(step), now, step), None);
+    assert_eq!(verify(secret, "12345", now, 0), None);
+}
+
+#[test]
+fn test_provisioning_uri() {
+    let uri = provisioning_uri(b"12345678901234567890", "VOxOV", "00ff");
+    assert_eq!(
+        uri,
+        "otpauth://totp/VOxOV:00ff?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=VOxOV&algorithm=SHA1&digits=6&period=30"
+    );
+}
@@ -11,43 +11,54 @@ use tokio_stream::Stream;
 pub type S3StreamItem = Result<Bytes, S3Error>;
 pub type BytesStream = Pin<Box<dyn Stream<Item = S3StreamItem> + Send>>;
 
+pub type FedStreamItem = Result<Bytes, reqwest::Error>;
+pub type FedStream = Pin<Box<dyn Stream<Item = FedStreamItem> + Send>>;
+
+pub type GeneStream = Pin<Box<dyn Stream<Item = Bytes> + Send>>;
+
 pub enum ResponseBody {
     Box(BoxBody<Bytes, Infallible>),
     S3Stream(StreamBody<BytesStream>),
+    FedStream(StreamBody<FedStream>),
+    GeneStream(StreamBody<GeneStream>),
 }
 
 impl Body for ResponseBody {
     type Data = Bytes;
-    type Error = Infallible;
+    /// Aborts the response, so a broken upstream is not taken for a whole body.
+    type Error = Box<dyn std::error::Error + Send + Sync>;
 
     fn poll_frame(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
         match &mut *self.get_mut() {
-            Self::Box(b) => Pin::new(&mut *b).poll_frame(cx),
-            Self::S3Stream(s) => Pin::new(&mut *s).poll_next(cx).map(|maybe_item| {
-                maybe_item.map(|item| {
-                    Ok(match item {
-                        Ok(bytes) => Frame::data(bytes),
-                        Err(_) => Frame::data(Bytes::default()),
-                    })
-                })
-            }),
+            Self::Box(b) => Pin::new(&mut *b)
+                .poll_frame(cx)
+                .map_err(|never| match never {}),
+            Self::S3Stream(s) => Pin::new(&mut *s)
+                .poll_next(cx)
+                .map(|maybe_item| maybe_item.map(|item| item.map(Frame::data).map_err(Into::into))),
+            Self::FedStream(s) => Pin::new(&mut *s)
+                .poll_next(cx)
+                .map(|maybe_item| maybe_item.map(|item| item.map(Frame::data).map_err(Into::into))),
+            Self::GeneStream(s) => Pin::new(&mut *s)
+                .poll_next(cx)
+                .map(|maybe_bytes| maybe_bytes.map(|bytes| Ok(Frame::data(bytes)))),
         }
     }
 
     fn is_end_stream(&self) -> bool {
         match self {
             Self::Box(b) => b.is_end_stream(),
-            Self::S3Stream(_) => false,
+            Self::S3Stream(_) | Self::FedStream(_) | Self::GeneStream(_) => false,
         }
     }
 
     fn size_hint(&self) -> SizeHint {
         match self {
             Self::Box(b) => b.size_hint(),
-            Self::S3Stream(_) => SizeHint::default(),
+            Self::S3Stream(_) | Self::FedStream(_) | Self::GeneStream(_) => SizeHint::default(),
         }
     }
 }
@@ -13,6 +13,7 @@ pub struct Cost {
     fed: &'static Fed,
     db: &'static Database,
     time_cost: i64,
+    hold_grace: i64,
     check_in_award: i64,
     check_in_refresh: i64,
 }
@@ -23,6 +24,7 @@ impl Cost {
             fed,
             db,
             time_cost: config.time_cost,
+            hold_grace: config.hold_grace,
             check_in_award: config.check_in_award,
             check_in_refresh: config.check_in_refresh,
         }
@@ -30,7 +32,7 @@ impl Cost {
 
     pub async fn handle(&self, query: Query, uid: &Id) -> Result<Reply> {
         match query {
-            Query::CostPay { access: _, vendor } => Ok(Reply::CostPay {
+            Query::CostPay { vendor, .. } => Ok(Reply::CostPay {
                 uri: format!("Not implemented: {}, {}", vendor, uid),
             }),
 
@@ -65,16 +67,42 @@ impl Cost {
             }
 
             _ => {
-                // Entry-refund to prevent double pay.
+                // Set limits.
                 let costs = query.get_costs();
-                self.db
-                    .decr_credit(uid, None, costs.sum().ok_or(Error::NumCheck)?, "CostEntry")
+                let millis: u64 = (costs.time / self.time_cost).try_into()?;
+                let deadline = Instant::now() + Duration::from_millis(millis);
+
+                // Hold-capture to survive crashes mid-request.
+                let expiry = Utc::now()
+                    + ChronoDuration::milliseconds(millis.try_into()?)
+                    + ChronoDuration::seconds(self.hold_grace);
+                let hold = self
+                    .db
+                    .hold_credit(uid, costs.sum().ok_or(Error::NumCheck)?, expiry, "CostHold")
                     .await?;
 
-                // Set limits.
-                let deadline = Instant::now()
-                    + Duration::from_millis((costs.time / self.time_cost).try_into()?);
-                self.fed.handle(query, uid, costs, deadline).await
+                let start = Instant::now();
+                let reply = self.fed.handle(query, uid, costs, deadline, &hold).await;
+
+                // Failures still pay for the time they took, if not captured below.
+                // Nothing is stored on failure, so no space is used.
+                if reply.is_err() {
+                    let millis = start.elapsed().as_millis() as i64;
+                    let used = millis.saturating_mul(self.time_cost).min(costs.time);
+                    if let Err(error) = self.db.capture_hold(&hold.id, used, "CostCapture").await {
+                        println!("Capture hold error for {}: {}", hold.id, error);
+                    }
+                }
+
+                // Release anything the lower layers did not capture.
+                // Feeds keep theirs open and capture it when they end.
+                if matches!(reply, Ok(Reply::GeneSubscribe { .. })) {
+                    return reply;
+                }
+                if let Err(error) = self.db.release_hold(&hold.id, "CostRelease").await {
+                    println!("Release hold error for {}: {}", hold.id, error);
+                }
+                reply
             }
         }
     }
@@ -83,7 +111,7 @@ impl Cost {
 pub mod macros {
     #[macro_export]
     macro_rules! cost_macros {
-        ($self: expr, $uid: expr, $changes: expr, $deadline: expr) => {
+        ($self: expr, $hold: expr, $changes: expr, $deadline: expr) => {
             /// Subtract traffic from changes based on $s.len().
             macro_rules! traffic {
                 ($s: expr) => {
@@ -110,27 +138,26 @@ pub mod macros {
                 };
             }
 
-            /// Refund current changes.
-            macro_rules! refund {
+            /// Capture what was used, and refund current changes.
+            macro_rules! capture {
                 () => {
                     $self
                         .db
-                        .incr_credit(
-                            $uid,
-                            None,
-                            $changes.sum().ok_or(Error::NumCheck)?,
-                            "CostRefund",
+                        .capture_hold(
+                            &$hold.id,
+                            $hold.amount - $changes.sum().ok_or(Error::NumCheck)?,
+                            "CostCapture",
                         )
                         .await?;
                 };
             }
 
             /// Three in one.
-            macro_rules! traffic_time_refund {
+            macro_rules! traffic_time_capture {
                 ($s: expr) => {
                     traffic!($s);
                     time!();
-                    refund!();
+                    capture!();
                 };
             }
         };
@@ -1,5 +1,15 @@
+mod account;
 mod credit;
+mod fed;
+mod identity;
+mod limit;
 pub mod ripperd;
+mod session;
+mod totp;
+
+pub use credit::Hold;
+pub use session::{UserSession, session_handle};
+pub use totp::Totp;
 
 use crate::Result;
 use crate::config::Config;
@@ -12,10 +22,14 @@ use scylla::client::session::Session;
 use scylla::client::session_builder::SessionBuilder;
 use scylla::statement::prepared::PreparedStatement;
 use sqlx::PgPool;
-use sqlx::postgres::PgPoolOptions;
-use std::sync::Arc;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration as StdDuration;
 use sysinfo::{Disks, System};
+use tokio::sync::broadcast;
+use uuid::Uuid;
 
 /// Prepared statements for ScyllaDB operations.
 pub struct ScyllaPreparedStatements {
@@ -23,6 +37,14 @@ pub struct ScyllaPreparedStatements {
     pub insert_session: PreparedStatement,
     pub select_session: PreparedStatement,
     pub delete_session: PreparedStatement,
+    pub insert_user_session: PreparedStatement,
+    pub select_user_session: PreparedStatement,
+    pub select_user_sessions: PreparedStatement,
+    pub update_user_session_used: PreparedStatement,
+    pub delete_user_session: PreparedStatement,
+    pub delete_user_sessions: PreparedStatement,
+    pub insert_access_revocation: PreparedStatement,
+    pub select_access_revocations: PreparedStatement,
     // SMS codes
     pub insert_sms_sendto: PreparedStatement,
     pub insert_sms_sent: PreparedStatement,
@@ -32,11 +54,69 @@ pub struct ScyllaPreparedStatements {
     pub select_phone_to_uid: PreparedStatement,
     pub insert_uid_to_phone: PreparedStatement,
     pub select_uid_to_phone: PreparedStatement,
+    pub delete_phone_to_uid: PreparedStatement,
+    pub delete_uid_to_phone: PreparedStatement,
+    pub insert_phone_tombstone: PreparedStatement,
+    pub select_phone_tombstone: PreparedStatement,
+    pub delete_phone_tombstone: PreparedStatement,
+    pub insert_anonymous: PreparedStatement,
+    pub select_anonymous: PreparedStatement,
+    pub delete_anonymous: PreparedStatement,
+    // Email codes
+    pub insert_email_code: PreparedStatement,
+    pub select_email_code: PreparedStatement,
+    pub update_email_verified: PreparedStatement,
+    pub delete_email_code: PreparedStatement,
+    // Email identity
+    pub insert_email_to_uid: PreparedStatement,
+    pub select_email_to_uid: PreparedStatement,
+    pub insert_uid_to_email: PreparedStatement,
+    pub select_uid_to_email: PreparedStatement,
+    pub delete_email_to_uid: PreparedStatement,
+    pub delete_uid_to_email: PreparedStatement,
+    // TOTP
+    pub insert_totp: PreparedStatement,
+    pub select_totp: PreparedStatement,
+    pub update_totp_step: PreparedStatement,
+    pub update_totp_enabled: PreparedStatement,
+    pub update_totp_recovery: PreparedStatement,
+    pub delete_totp: PreparedStatement,
     // Check-ins
     pub insert_checkin: PreparedStatement,
     pub select_checkin: PreparedStatement,
+    pub delete_checkin: PreparedStatement,
+    // Fed
+    pub insert_fed_nonce: PreparedStatement,
+    pub insert_meme_visa: PreparedStatement,
+    pub select_meme_visa: PreparedStatement,
+    pub select_meme_visas_by_uid: PreparedStatement,
+    pub delete_meme_visa: PreparedStatement,
+    // Rate limits
+    pub select_rate_tat: PreparedStatement,
+    pub insert_rate_tat: PreparedStatement,
+    pub update_rate_tat: PreparedStatement,
+}
+
+/// A committed change of a map_1 doc, as seen by this process.
+/// Other instances have their own feeds, so changes made there are not seen.
+#[derive(Clone, Debug)]
+pub struct MapChange {
+    /// Put, Patch or Drop.
+    pub op: &'static str,
+    pub id: Uuid,
+    pub uid: Id,
+    pub ns: String,
+    pub is_pub: bool,
+    /// The doc as written, or as it was before a Drop, to filter in memory.
+    pub doc: Arc<serde_json::Value>,
 }
 
+/// Changes kept for slow subscribers before they lag.
+const MAP_FEED_CAPACITY: usize = 1024;
+
+/// Compiled JSON Schema of a map_1 namespace, with the id and version of its doc.
+pub type MapSchema = (Uuid, i64, Arc<jsonschema::Validator>);
+
 pub struct Database {
     /// ScyllaDB session
     pub scylla: Arc<Session>,
@@ -54,6 +134,16 @@ pub struct Database {
     pub access_ttl: i64,
     pub refresh_ttl: i64,
     pub user_ttl: i64,
+    pub phone_cooldown: i64,
+
+    /// Changes of map_1 docs, for GeneSubscribe.
+    pub map_feed: broadcast::Sender<MapChange>,
+
+    /// Schemas of map_1 namespaces, compiled once per version.
+    pub map_schemas: Mutex<HashMap<String, MapSchema>>,
+
+    /// Revoked logins of signed access tokens, kept in process.
+    pub access_revocations: RwLock<HashMap<(Id, Vec<u8>), i64>>,
 }
 
 impl Database {
@@ -94,8 +184,16 @@ impl Database {
         .with_path_style();
 
         // Create schemas if requested
+        if create_schema {
+            Self::create_scylla_keyspace(&scylla, &config.scylla_keyspace).await;
+        }
+        scylla
+            .use_keyspace(&config.scylla_keyspace, true)
+            .await
+            .expect("ScyllaDB keyspace missing?");
         if create_schema {
             Self::create_scylla_schema(&scylla).await;
+            Self::create_crdb_database(&crdb, &config.crdb_addr).await;
             Self::create_crdb_schema(&crdb).await;
         }
 
@@ -111,6 +209,10 @@ impl Database {
             access_ttl: config.access_ttl,
             refresh_ttl: config.refresh_ttl,
             user_ttl: config.user_ttl,
+            phone_cooldown: config.phone_cooldown,
+            map_feed: broadcast::channel(MAP_FEED_CAPACITY).0,
+            map_schemas: Mutex::new(HashMap::new()),
+            access_revocations: RwLock::new(HashMap::new()),
         };
 
         if config.samsara {
@@ -126,21 +228,26 @@ impl Database {
         Database::new(&config, false).await
     }
 
-    /// Create ScyllaDB keyspace and tables.
-    async fn create_scylla_schema(scylla: &Session) {
-        // Create keyspace
+    /// Create ScyllaDB keyspace.
+    async fn create_scylla_keyspace(scylla: &Session, keyspace: &str) {
         scylla
             .query_unpaged(
-                "CREATE KEYSPACE IF NOT EXISTS voxov WITH replication = {'class': 'SimpleStrategy', 'replication_factor': 1}",
+                format!(
+                    "CREATE KEYSPACE IF NOT EXISTS \"{}\" WITH replication = {{'class': 'SimpleStrategy', 'replication_factor': 1}}",
+                    keyspace
+                ),
                 &[],
             )
             .await
             .expect("Failed to create keyspace");
+    }
 
+    /// Create ScyllaDB tables in the keyspace in use.
+    async fn create_scylla_schema(scylla: &Session) {
         // Sessions table
         scylla
             .query_unpaged(
-                "CREATE TABLE IF NOT EXISTS voxov.sessions (
+                "CREATE TABLE IF NOT EXISTS sessions (
                     sid BLOB PRIMARY KEY,
                     uid BLOB,
                     kind TINYINT
@@ -150,10 +257,42 @@ impl Database {
             .await
             .expect("Failed to create sessions table");
 
+        // Sessions of a user, tokens of one login share a handle
+        scylla
+            .query_unpaged(
+                "CREATE TABLE IF NOT EXISTS user_sessions (
+                    uid BLOB,
+                    handle BLOB,
+                    sid BLOB,
+                    kind TINYINT,
+                    label TEXT,
+                    created TIMESTAMP,
+                    last_used TIMESTAMP,
+                    PRIMARY KEY (uid, handle, sid)
+                )",
+                &[],
+            )
+            .await
+            .expect("Failed to create user_sessions table");
+
+        // Revoked logins of signed access tokens, empty handle for all
+        scylla
+            .query_unpaged(
+                "CREATE TABLE IF NOT EXISTS access_revocations (
+                    uid BLOB,
+                    handle BLOB,
+                    since BIGINT,
+                    PRIMARY KEY (uid, handle)
+                )",
+                &[],
+            )
+            .await
+            .expect("Failed to create access_revocations table");
+
         // SMS codes table (tracks SMS verification)
         scylla
             .query_unpaged(
-                "CREATE TABLE IF NOT EXISTS voxov.sms_codes (
+                "CREATE TABLE IF NOT EXISTS sms_codes (
                     phone TEXT,
                     message BLOB,
                     user_phone TEXT,
@@ -167,7 +306,7 @@ impl Database {
         // Phone to UID mapping
         scylla
             .query_unpaged(
-                "CREATE TABLE IF NOT EXISTS voxov.phone_to_uid (
+                "CREATE TABLE IF NOT EXISTS phone_to_uid (
                     phone TEXT PRIMARY KEY,
                     uid BLOB
                 )",
@@ -179,7 +318,7 @@ impl Database {
         // UID to phone mapping
         scylla
             .query_unpaged(
-                "CREATE TABLE IF NOT EXISTS voxov.uid_to_phone (
+                "CREATE TABLE IF NOT EXISTS uid_to_phone (
                     uid BLOB PRIMARY KEY,
                     phone TEXT
                 )",
@@ -188,10 +327,86 @@ impl Database {
             .await
             .expect("Failed to create uid_to_phone table");
 
+        // Phones moved away from, still pointing to their account
+        scylla
+            .query_unpaged(
+                "CREATE TABLE IF NOT EXISTS phone_tombstones (
+                    phone TEXT PRIMARY KEY,
+                    uid BLOB
+                )",
+                &[],
+            )
+            .await
+            .expect("Failed to create phone_tombstones table");
+
+        // Accounts without a phone or email
+        scylla
+            .query_unpaged(
+                "CREATE TABLE IF NOT EXISTS anonymous_users (
+                    uid BLOB PRIMARY KEY
+                )",
+                &[],
+            )
+            .await
+            .expect("Failed to create anonymous_users table");
+
+        // Email codes table (tracks magic links)
+        scylla
+            .query_unpaged(
+                "CREATE TABLE IF NOT EXISTS email_codes (
+                    token BLOB PRIMARY KEY,
+                    code BLOB,
+                    email TEXT,
+                    verified BOOLEAN
+                )",
+                &[],
+            )
+            .await
+            .expect("Failed to create email_codes table");
+
+        // Email to UID mapping
+        scylla
+            .query_unpaged(
+                "CREATE TABLE IF NOT EXISTS email_to_uid (
+                    email TEXT PRIMARY KEY,
+                    uid BLOB
+                )",
+                &[],
+            )
+            .await
+            .expect("Failed to create email_to_uid table");
+
+        // UID to email mapping
+        scylla
+            .query_unpaged(
+                "CREATE TABLE IF NOT EXISTS uid_to_email (
+                    uid BLOB PRIMARY KEY,
+                    email TEXT
+                )",
+                &[],
+            )
+            .await
+            .expect("Failed to create uid_to_email table");
+
+        // TOTP second factor
+        scylla
+            .query_unpaged(
+                "CREATE TABLE IF NOT EXISTS totp (
+                    uid BLOB PRIMARY KEY,
+                    secret BLOB,
+                    enabled BOOLEAN,
+                    last_step BIGINT,
+                    recovery SET<BLOB>
+                )",
+                &[],
+            )
+            .await
+            .expect("Failed to create totp table");
+
         // Check-ins table
         scylla
             .query_unpaged(
-                "CREATE TABLE IF NOT EXISTS voxov.checkins (
+                "CREATE TABLE IF NOT EXISTS checkins (
                     uid BLOB PRIMARY KEY,
                     last_checkin TIMESTAMP
                 )",
@@ -199,9 +414,71 @@ impl Database {
             )
             .await
             .expect("Failed to create checkins table");
+
+        // Nonces of signed requests from peers
+        scylla
+            .query_unpaged(
+                "CREATE TABLE IF NOT EXISTS fed_nonces (
+                    nonce BLOB PRIMARY KEY
+                )",
+                &[],
+            )
+            .await
+            .expect("Failed to create fed_nonces table");
+
+        // Private memes peers may fetch, by owner
+        scylla
+            .query_unpaged(
+                "CREATE TABLE IF NOT EXISTS meme_visas (
+                    peer BLOB,
+                    hash BLOB,
+                    uid BLOB,
+                    PRIMARY KEY ((peer, hash))
+                )",
+                &[],
+            )
+            .await
+            .expect("Failed to create meme_visas table");
+
+        // Visas of a user, to export and delete them with the account
+        scylla
+            .query_unpaged(
+                "CREATE INDEX IF NOT EXISTS meme_visas_uid_idx ON meme_visas (uid)",
+                &[],
+            )
+            .await
+            .expect("Failed to create meme_visas_uid_idx");
+
+        // Rate limit arrival times, shared by instances
+        scylla
+            .query_unpaged(
+                "CREATE TABLE IF NOT EXISTS rate_limits (
+                    key TEXT PRIMARY KEY,
+                    tat BIGINT
+                )",
+                &[],
+            )
+            .await
+            .expect("Failed to create rate_limits table");
     }
 
     /// Create CockroachDB tables.
+    /// Create the database of the CockroachDB URI.
+    /// CockroachDB lets sessions start in a database that doesn't exist yet.
+    async fn create_crdb_database(crdb: &PgPool, addr: &str) {
+        let options = PgConnectOptions::from_str(addr).expect("Invalid CockroachDB URI");
+        let Some(database) = options.get_database() else {
+            return;
+        };
+        sqlx::query(&format!(
+            "CREATE DATABASE IF NOT EXISTS \"{}\"",
+            database.replace('"', "\"\"")
+        ))
+        .execute(crdb)
+        .await
+        .expect("Failed to create database");
+    }
+
     async fn create_crdb_schema(crdb: &PgPool) {
         // User accounts table (for credits)
         sqlx::query(
@@ -241,6 +518,68 @@ impl Database {
             .await
             .ok();
 
+        // Pending credit holds
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS credit_holds (
+                id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+                uid BYTEA NOT NULL,
+                amount BIGINT NOT NULL,
+                note TEXT NOT NULL,
+                deadline TIMESTAMPTZ NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        )
+        .execute(crdb)
+        .await
+        .expect("Failed to create credit_holds table");
+
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS credit_holds_deadline_idx ON credit_holds (deadline)",
+        )
+        .execute(crdb)
+        .await
+        .ok();
+
+        // Accounts scheduled for deletion
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS account_deletions (
+                uid BYTEA PRIMARY KEY,
+                archive BYTEA NOT NULL,
+                due TIMESTAMPTZ NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        )
+        .execute(crdb)
+        .await
+        .expect("Failed to create account_deletions table");
+
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS account_deletions_due_idx ON account_deletions (due)",
+        )
+        .execute(crdb)
+        .await
+        .ok();
+
+        // Identity changes, for audit
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS identity_log (
+                id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+                uid BYTEA NOT NULL,
+                kind TEXT NOT NULL,
+                old_value TEXT,
+                new_value TEXT,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        )
+        .execute(crdb)
+        .await
+        .expect("Failed to create identity_log table");
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS identity_log_uid_idx ON identity_log (uid)")
+            .execute(crdb)
+            .await
+            .ok();
+
         // Meme metadata table
         sqlx::query(
             "CREATE TABLE IF NOT EXISTS meme_meta (
@@ -282,13 +621,29 @@ impl Database {
                 i4 JSONB, i5 JSONB, i6 JSONB, i7 JSONB,
                 geo_lon DOUBLE PRECISION,
                 geo_lat DOUBLE PRECISION,
-                body JSONB
+                body JSONB,
+                version INT8 NOT NULL DEFAULT 0,
+                paid INT8 NOT NULL DEFAULT 0
             )",
         )
         .execute(crdb)
         .await
         .expect("Failed to create map_docs table");
 
+        // Tables from before Patch
+        sqlx::query(
+            "ALTER TABLE map_docs ADD COLUMN IF NOT EXISTS version INT8 NOT NULL DEFAULT 0",
+        )
+        .execute(crdb)
+        .await
+        .ok();
+
+        // Tables from before refunds were capped by what was paid
+        sqlx::query("ALTER TABLE map_docs ADD COLUMN IF NOT EXISTS paid INT8 NOT NULL DEFAULT 0")
+            .execute(crdb)
+            .await
+            .ok();
+
         sqlx::query("CREATE INDEX IF NOT EXISTS map_docs_eol_idx ON map_docs (eol)")
             .execute(crdb)
             .await
@@ -303,70 +658,354 @@ impl Database {
             .execute(crdb)
             .await
             .ok();
+
+        // Geo boxes within a namespace
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS map_docs_geo_idx ON map_docs (ns, geo_lat, geo_lon)",
+        )
+        .execute(crdb)
+        .await
+        .ok();
+
+        // Indexed keys, filtered within a namespace
+        for i in 0..8 {
+            sqlx::query(&format!(
+                "CREATE INDEX IF NOT EXISTS map_docs_i{i}_idx ON map_docs (ns, i{i})"
+            ))
+            .execute(crdb)
+            .await
+            .ok();
+        }
+
+        // Namespaces of map docs claimed by their owners
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS map_ns (
+                ns TEXT PRIMARY KEY,
+                uid BYTEA NOT NULL,
+                public TEXT NOT NULL DEFAULT '',
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        )
+        .execute(crdb)
+        .await
+        .expect("Failed to create map_ns table");
+
+        // Levels granted in claimed namespaces
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS map_acl (
+                ns TEXT NOT NULL,
+                uid BYTEA NOT NULL,
+                level TEXT NOT NULL,
+                PRIMARY KEY (ns, uid)
+            )",
+        )
+        .execute(crdb)
+        .await
+        .expect("Failed to create map_acl table");
+
+        // User genes, as WASM memes
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS wasm_genes (
+                id TEXT PRIMARY KEY,
+                uid BYTEA NOT NULL,
+                hash BYTEA NOT NULL,
+                description TEXT NOT NULL DEFAULT '',
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        )
+        .execute(crdb)
+        .await
+        .expect("Failed to create wasm_genes table");
+
+        // Credits owed between instances
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS fed_ledger (
+                id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+                peer BYTEA NOT NULL,
+                kind TEXT NOT NULL,
+                peer_amount BIGINT NOT NULL,
+                local_amount BIGINT NOT NULL,
+                rate DOUBLE PRECISION NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        )
+        .execute(crdb)
+        .await
+        .expect("Failed to create fed_ledger table");
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS fed_ledger_peer_idx ON fed_ledger (peer)")
+            .execute(crdb)
+            .await
+            .ok();
     }
 
     /// Prepare ScyllaDB statements for better performance.
     async fn prepare_statements(scylla: &Session) -> ScyllaPreparedStatements {
         ScyllaPreparedStatements {
             insert_session: scylla
-                .prepare("INSERT INTO voxov.sessions (sid, uid, kind) VALUES (?, ?, ?) USING TTL ?")
+                .prepare("INSERT INTO sessions (sid, uid, kind) VALUES (?, ?, ?) USING TTL ?")
                 .await
                 .expect("Failed to prepare insert_session"),
 
             select_session: scylla
-                .prepare("SELECT uid, kind FROM voxov.sessions WHERE sid = ?")
+                .prepare("SELECT uid, kind FROM sessions WHERE sid = ?")
                 .await
                 .expect("Failed to prepare select_session"),
 
             delete_session: scylla
-                .prepare("DELETE FROM voxov.sessions WHERE sid = ?")
+                .prepare("DELETE FROM sessions WHERE sid = ?")
                 .await
                 .expect("Failed to prepare delete_session"),
 
+            insert_user_session: scylla
+                .prepare("INSERT INTO user_sessions (uid, handle, sid, kind, label, created, last_used) VALUES (?, ?, ?, ?, ?, ?, ?) USING TTL ?")
+                .await
+                .expect("Failed to prepare insert_user_session"),
+
+            select_user_session: scylla
+                .prepare("SELECT label, created FROM user_sessions WHERE uid = ? AND handle = ? AND sid = ?")
+                .await
+                .expect("Failed to prepare select_user_session"),
+
+            select_user_sessions: scylla
+                .prepare("SELECT handle, sid, kind, label, created, last_used, TTL(kind) FROM user_sessions WHERE uid = ?")
+                .await
+                .expect("Failed to prepare select_user_sessions"),
+
+            update_user_session_used: scylla
+                .prepare("UPDATE user_sessions USING TTL ? SET last_used = ? WHERE uid = ? AND handle = ? AND sid = ?")
+                .await
+                .expect("Failed to prepare update_user_session_used"),
+
+            delete_user_session: scylla
+                .prepare("DELETE FROM user_sessions WHERE uid = ? AND handle = ? AND sid = ?")
+                .await
+                .expect("Failed to prepare delete_user_session"),
+
+            delete_user_sessions: scylla
+                .prepare("DELETE FROM user_sessions WHERE uid = ?")
+                .await
+                .expect("Failed to prepare delete_user_sessions"),
+
+            insert_access_revocation: scylla
+                .prepare("INSERT INTO access_revocations (uid, handle, since) VALUES (?, ?, ?) USING TTL ?")
+                .await
+                .expect("Failed to prepare insert_access_revocation"),
+
+            select_access_revocations: scylla
+                .prepare("SELECT uid, handle, since FROM access_revocations")
+                .await
+                .expect("Failed to prepare select_access_revocations"),
+
             insert_sms_sendto: scylla
-                .prepare("INSERT INTO voxov.sms_codes (phone, message) VALUES (?, ?) USING TTL ?")
+                .prepare("INSERT INTO sms_codes (phone, message) VALUES (?, ?) USING TTL ?")
                 .await
                 .expect("Failed to prepare insert_sms_sendto"),
 
             insert_sms_sent: scylla
-                .prepare("UPDATE voxov.sms_codes USING TTL ? SET user_phone = ? WHERE phone = ? AND message = ?")
+                .prepare("UPDATE sms_codes USING TTL ? SET user_phone = ? WHERE phone = ? AND message = ?")
                 .await
                 .expect("Failed to prepare insert_sms_sent"),
 
             select_sms_sent: scylla
-                .prepare("SELECT user_phone FROM voxov.sms_codes WHERE phone = ? AND message = ?")
+                .prepare("SELECT user_phone FROM sms_codes WHERE phone = ? AND message = ?")
                 .await
                 .expect("Failed to prepare select_sms_sent"),
 
             insert_phone_to_uid: scylla
-                .prepare("INSERT INTO voxov.phone_to_uid (phone, uid) VALUES (?, ?) USING TTL ?")
+                .prepare("INSERT INTO phone_to_uid (phone, uid) VALUES (?, ?) USING TTL ?")
                 .await
                 .expect("Failed to prepare insert_phone_to_uid"),
 
             select_phone_to_uid: scylla
-                .prepare("SELECT uid FROM voxov.phone_to_uid WHERE phone = ?")
+                .prepare("SELECT uid FROM phone_to_uid WHERE phone = ?")
                 .await
                 .expect("Failed to prepare select_phone_to_uid"),
 
             insert_uid_to_phone: scylla
-                .prepare("INSERT INTO voxov.uid_to_phone (uid, phone) VALUES (?, ?) USING TTL ?")
+                .prepare("INSERT INTO uid_to_phone (uid, phone) VALUES (?, ?) USING TTL ?")
                 .await
                 .expect("Failed to prepare insert_uid_to_phone"),
 
             select_uid_to_phone: scylla
-                .prepare("SELECT phone FROM voxov.uid_to_phone WHERE uid = ?")
+                .prepare("SELECT phone FROM uid_to_phone WHERE uid = ?")
                 .await
                 .expect("Failed to prepare select_uid_to_phone"),
 
+            delete_phone_to_uid: scylla
+                .prepare("DELETE FROM phone_to_uid WHERE phone = ?")
+                .await
+                .expect("Failed to prepare delete_phone_to_uid"),
+
+            delete_uid_to_phone: scylla
+                .prepare("DELETE FROM uid_to_phone WHERE uid = ?")
+                .await
+                .expect("Failed to prepare delete_uid_to_phone"),
+
+            insert_phone_tombstone: scylla
+                .prepare("INSERT INTO phone_tombstones (phone, uid) VALUES (?, ?) USING TTL ?")
+                .await
+                .expect("Failed to prepare insert_phone_tombstone"),
+
+            select_phone_tombstone: scylla
+                .prepare("SELECT uid FROM phone_tombstones WHERE phone = ?")
+                .await
+                .expect("Failed to prepare select_phone_tombstone"),
+
+            delete_phone_tombstone: scylla
+                .prepare("DELETE FROM phone_tombstones WHERE phone = ?")
+                .await
+                .expect("Failed to prepare delete_phone_tombstone"),
+
+            insert_anonymous: scylla
+                .prepare("INSERT INTO anonymous_users (uid) VALUES (?) USING TTL ?")
+                .await
+                .expect("Failed to prepare insert_anonymous"),
+
+            select_anonymous: scylla
+                .prepare("SELECT uid FROM anonymous_users WHERE uid = ?")
+                .await
+                .expect("Failed to prepare select_anonymous"),
+
+            delete_anonymous: scylla
+                .prepare("DELETE FROM anonymous_users WHERE uid = ?")
+                .await
+                .expect("Failed to prepare delete_anonymous"),
+
+            insert_email_code: scylla
+                .prepare("INSERT INTO email_codes (token, code, email, verified) VALUES (?, ?, ?, false) USING TTL ?")
+                .await
+                .expect("Failed to prepare insert_email_code"),
+
+            select_email_code: scylla
+                .prepare("SELECT code, email, verified FROM email_codes WHERE token = ?")
+                .await
+                .expect("Failed to prepare select_email_code"),
+
+            update_email_verified: scylla
+                .prepare("UPDATE email_codes USING TTL ? SET verified = true WHERE token = ?")
+                .await
+                .expect("Failed to prepare update_email_verified"),
+
+            delete_email_code: scylla
+                .prepare("DELETE FROM email_codes WHERE token = ?")
+                .await
+                .expect("Failed to prepare delete_email_code"),
+
+            insert_email_to_uid: scylla
+                .prepare("INSERT INTO email_to_uid (email, uid) VALUES (?, ?) USING TTL ?")
+                .await
+                .expect("Failed to prepare insert_email_to_uid"),
+
+            select_email_to_uid: scylla
+                .prepare("SELECT uid FROM email_to_uid WHERE email = ?")
+                .await
+                .expect("Failed to prepare select_email_to_uid"),
+
+            insert_uid_to_email: scylla
+                .prepare("INSERT INTO uid_to_email (uid, email) VALUES (?, ?) USING TTL ?")
+                .await
+                .expect("Failed to prepare insert_uid_to_email"),
+
+            select_uid_to_email: scylla
+                .prepare("SELECT email FROM uid_to_email WHERE uid = ?")
+                .await
+                .expect("Failed to prepare select_uid_to_email"),
+
+            delete_email_to_uid: scylla
+                .prepare("DELETE FROM email_to_uid WHERE email = ?")
+                .await
+                .expect("Failed to prepare delete_email_to_uid"),
+
+            delete_uid_to_email: scylla
+                .prepare("DELETE FROM uid_to_email WHERE uid = ?")
+                .await
+                .expect("Failed to prepare delete_uid_to_email"),
+
+            insert_totp: scylla
+                .prepare("INSERT INTO totp (uid, secret, enabled, last_step, recovery) VALUES (?, ?, false, 0, ?)")
+                .await
+                .expect("Failed to prepare insert_totp"),
+
+            select_totp: scylla
+                .prepare("SELECT secret, enabled, last_step, recovery FROM totp WHERE uid = ?")
+                .await
+                .expect("Failed to prepare select_totp"),
+
+            update_totp_step: scylla
+                .prepare("UPDATE totp SET last_step = ? WHERE uid = ?")
+                .await
+                .expect("Failed to prepare update_totp_step"),
+
+            update_totp_enabled: scylla
+                .prepare("UPDATE totp SET enabled = true, last_step = ? WHERE uid = ?")
+                .await
+                .expect("Failed to prepare update_totp_enabled"),
+
+            update_totp_recovery: scylla
+                .prepare("UPDATE totp SET recovery = recovery - ? WHERE uid = ?")
+                .await
+                .expect("Failed to prepare update_totp_recovery"),
+
+            delete_totp: scylla
+                .prepare("DELETE FROM totp WHERE uid = ?")
+                .await
+                .expect("Failed to prepare delete_totp"),
+
             insert_checkin: scylla
-                .prepare("INSERT INTO voxov.checkins (uid, last_checkin) VALUES (?, ?)")
+                .prepare("INSERT INTO checkins (uid, last_checkin) VALUES (?, ?)")
                 .await
                 .expect("Failed to prepare insert_checkin"),
 
             select_checkin: scylla
-                .prepare("SELECT last_checkin FROM voxov.checkins WHERE uid = ?")
+                .prepare("SELECT last_checkin FROM checkins WHERE uid = ?")
                 .await
                 .expect("Failed to prepare select_checkin"),
+
+            delete_checkin: scylla
+                .prepare("DELETE FROM checkins WHERE uid = ?")
+                .await
+                .expect("Failed to prepare delete_checkin"),
+
+            insert_fed_nonce: scylla
+                .prepare("INSERT INTO fed_nonces (nonce) VALUES (?) IF NOT EXISTS USING TTL ?")
+                .await
+                .expect("Failed to prepare insert_fed_nonce"),
+
+            insert_meme_visa: scylla
+                .prepare("INSERT INTO meme_visas (peer, hash, uid) VALUES (?, ?, ?) USING TTL ?")
+                .await
+                .expect("Failed to prepare insert_meme_visa"),
+
+            select_meme_visa: scylla
+                .prepare("SELECT uid FROM meme_visas WHERE peer = ? AND hash = ?")
+                .await
+                .expect("Failed to prepare select_meme_visa"),
+
+            select_meme_visas_by_uid: scylla
+                .prepare("SELECT peer, hash, TTL(uid) FROM meme_visas WHERE uid = ?")
+                .await
+                .expect("Failed to prepare select_meme_visas_by_uid"),
+
+            delete_meme_visa: scylla
+                .prepare("DELETE FROM meme_visas WHERE peer = ? AND hash = ?")
+                .await
+                .expect("Failed to prepare delete_meme_visa"),
+
+            select_rate_tat: scylla
+                .prepare("SELECT tat FROM rate_limits WHERE key = ?")
+                .await
+                .expect("Failed to prepare select_rate_tat"),
+
+            insert_rate_tat: scylla
+                .prepare("INSERT INTO rate_limits (key, tat) VALUES (?, ?) IF NOT EXISTS USING TTL ?")
+                .await
+                .expect("Failed to prepare insert_rate_tat"),
+
+            update_rate_tat: scylla
+                .prepare("UPDATE rate_limits USING TTL ? SET tat = ? WHERE key = ? IF tat = ?")
+                .await
+                .expect("Failed to prepare update_rate_tat"),
         }
     }
 
@@ -536,6 +1175,107 @@ impl Database {
         Ok(None)
     }
 
+    // --- Email operations (ScyllaDB) ---
+
+    /// Record a magic link mailed to email.
+    pub async fn set_email_code(&self, token: &[u8], code: &[u8], email: &str) -> Result<()> {
+        self.scylla
+            .execute_unpaged(
+                &self.stmts.insert_email_code,
+                (token, code, email, self.access_ttl as i32),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Get code, email and whether the magic link was opened.
+    pub async fn get_email_code(&self, token: &[u8]) -> Result<Option<(Vec<u8>, String, bool)>> {
+        let result = self
+            .scylla
+            .execute_unpaged(&self.stmts.select_email_code, (token,))
+            .await?;
+
+        if let Some(row) = result
+            .into_rows_result()?
+            .rows::<(Option<Vec<u8>>, Option<String>, Option<bool>)>()?
+            .next()
+        {
+            if let (Some(code), Some(email), verified) = row? {
+                return Ok(Some((code, email, verified.unwrap_or_default())));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Record that the magic link was opened.
+    pub async fn set_email_verified(&self, token: &[u8]) -> Result<()> {
+        self.scylla
+            .execute_unpaged(
+                &self.stmts.update_email_verified,
+                (self.access_ttl as i32, token),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Delete a used magic link.
+    pub async fn del_email_code(&self, token: &[u8]) -> Result<()> {
+        self.scylla
+            .execute_unpaged(&self.stmts.delete_email_code, (token,))
+            .await?;
+        Ok(())
+    }
+
+    /// Set email to UID mapping.
+    pub async fn set_email_to_uid(&self, email: &str, uid: &Id) -> Result<()> {
+        self.scylla
+            .execute_unpaged(
+                &self.stmts.insert_email_to_uid,
+                (email, &uid.0[..], self.user_ttl as i32),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Get UID from email.
+    pub async fn get_email_to_uid(&self, email: &str) -> Result<Option<Id>> {
+        let result = self
+            .scylla
+            .execute_unpaged(&self.stmts.select_email_to_uid, (email,))
+            .await?;
+
+        if let Some(row) = result.into_rows_result()?.rows::<(Vec<u8>,)>()?.next() {
+            let (uid_bytes,) = row?;
+            return Ok(Some(Id::try_from(uid_bytes)?));
+        }
+        Ok(None)
+    }
+
+    /// Set UID to email mapping.
+    pub async fn set_uid_to_email(&self, uid: &Id, email: &str) -> Result<()> {
+        self.scylla
+            .execute_unpaged(
+                &self.stmts.insert_uid_to_email,
+                (&uid.0[..], email, self.user_ttl as i32),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Get email from UID.
+    pub async fn get_uid_to_email(&self, uid: &Id) -> Result<Option<String>> {
+        let result = self
+            .scylla
+            .execute_unpaged(&self.stmts.select_uid_to_email, (&uid.0[..],))
+            .await?;
+
+        if let Some(row) = result.into_rows_result()?.rows::<(String,)>()?.next() {
+            let (email,) = row?;
+            return Ok(Some(email));
+        }
+        Ok(None)
+    }
+
     // --- Check-in operations (ScyllaDB) ---
 
     /// Get last check-in time.
@@ -620,13 +1360,24 @@ impl Database {
                 // Truncate ScyllaDB tables
                 for table in [
                     "sessions",
+                    "user_sessions",
+                    "access_revocations",
                     "sms_codes",
                     "phone_to_uid",
                     "uid_to_phone",
+                    "phone_tombstones",
+                    "anonymous_users",
+                    "email_codes",
+                    "email_to_uid",
+                    "uid_to_email",
+                    "totp",
                     "checkins",
+                    "fed_nonces",
+                    "meme_visas",
+                    "rate_limits",
                 ] {
                     if let Err(e) = scylla
-                        .query_unpaged(format!("TRUNCATE voxov.{}", table), &[])
+                        .query_unpaged(format!("TRUNCATE {}", table), &[])
                         .await
                     {
                         println!("Samsara ScyllaDB error truncating {}: {}", table, e);
@@ -634,7 +1385,19 @@ impl Database {
                 }
 
                 // Truncate CockroachDB tables
-                for table in ["meme_meta", "map_docs", "user_accounts", "credit_log"] {
+                for table in [
+                    "meme_meta",
+                    "map_docs",
+                    "user_accounts",
+                    "credit_log",
+                    "credit_holds",
+                    "account_deletions",
+                    "identity_log",
+                    "fed_ledger",
+                    "wasm_genes",
+                    "map_ns",
+                    "map_acl",
+                ] {
                     if let Err(e) = sqlx::query(&format!("TRUNCATE TABLE {}", table))
                         .execute(&crdb)
                         .await
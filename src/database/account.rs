@@ -0,0 +1,409 @@
+use super::Database;
+use crate::ir::{Hash, Id};
+use crate::{Error, Result};
+use chrono::{DateTime, Utc};
+use serde_json::{Value, json};
+use sqlx::Row;
+
+impl Database {
+    /// Collect everything stored about uid. Meme blobs are listed by hash,
+    /// and stay downloadable with MemeGet until the account is deleted.
+    pub async fn export_account(&self, uid: &Id) -> Result<Value> {
+        let uid_bytes = &uid.0[..];
+
+        let credit_log: Vec<Value> = sqlx::query(
+            "SELECT other_uid, amount, note, created_at FROM credit_log
+             WHERE uid = $1 ORDER BY created_at",
+        )
+        .bind(uid_bytes)
+        .fetch_all(&self.crdb)
+        .await?
+        .iter()
+        .map(|row| {
+            let other_uid: Option<Vec<u8>> = row.get("other_uid");
+            let created_at: DateTime<Utc> = row.get("created_at");
+            json!({
+                "other_uid": other_uid.map(hex::encode),
+                "amount": row.get::<i64, _>("amount"),
+                "note": row.get::<String, _>("note"),
+                "created_at": created_at.to_rfc3339(),
+            })
+        })
+        .collect();
+
+        let memes: Vec<Value> = sqlx::query(
+            "SELECT hash, size, pub, tip, eol FROM meme_meta WHERE uid = $1 ORDER BY eol",
+        )
+        .bind(uid_bytes)
+        .fetch_all(&self.crdb)
+        .await?
+        .iter()
+        .map(|row| {
+            let eol: DateTime<Utc> = row.get("eol");
+            json!({
+                "hash": hex::encode(row.get::<Vec<u8>, _>("hash")),
+                "size": row.get::<i64, _>("size"),
+                "pub": row.get::<bool, _>("pub"),
+                "tip": row.get::<i64, _>("tip"),
+                "eol": eol.to_rfc3339(),
+            })
+        })
+        .collect();
+
+        let map_docs: Vec<Value> = sqlx::query(
+            "SELECT id, pub, eol, tip, ns, i0, i1, i2, i3, i4, i5, i6, i7,
+                    geo_lon, geo_lat, body
+             FROM map_docs WHERE uid = $1 ORDER BY eol",
+        )
+        .bind(uid_bytes)
+        .fetch_all(&self.crdb)
+        .await?
+        .iter()
+        .map(|row| {
+            let id: uuid::Uuid = row.get("id");
+            let eol: DateTime<Utc> = row.get("eol");
+            let mut doc = json!({
+                "_id": id.to_string(),
+                "_pub": row.get::<bool, _>("pub"),
+                "_eol": eol.to_rfc3339(),
+                "_tip": row.get::<i64, _>("tip"),
+                "_ns": row.get::<String, _>("ns"),
+                "_geo": [
+                    row.get::<Option<f64>, _>("geo_lon"),
+                    row.get::<Option<f64>, _>("geo_lat"),
+                ],
+                "body": row.get::<Option<Value>, _>("body"),
+            });
+            for i in 0..8 {
+                let key = format!("i{}", i);
+                doc[format!("_{}", i)] = row.get::<Option<Value>, _>(key.as_str()).into();
+            }
+            doc
+        })
+        .collect();
+
+        let identity_log: Vec<Value> = sqlx::query(
+            "SELECT kind, old_value, new_value, created_at FROM identity_log
+             WHERE uid = $1 ORDER BY created_at",
+        )
+        .bind(uid_bytes)
+        .fetch_all(&self.crdb)
+        .await?
+        .iter()
+        .map(|row| {
+            let created_at: DateTime<Utc> = row.get("created_at");
+            json!({
+                "kind": row.get::<String, _>("kind"),
+                "old": row.get::<Option<String>, _>("old_value"),
+                "new": row.get::<Option<String>, _>("new_value"),
+                "created_at": created_at.to_rfc3339(),
+            })
+        })
+        .collect();
+
+        let map_ns: Vec<Value> = sqlx::query(
+            "SELECT n.ns, n.public, n.created_at,
+                    COALESCE(json_agg(json_build_object('uid', encode(a.uid, 'hex'), 'level', a.level))
+                        FILTER (WHERE a.uid IS NOT NULL), '[]') AS acl
+             FROM map_ns n LEFT JOIN map_acl a ON a.ns = n.ns
+             WHERE n.uid = $1 GROUP BY n.ns, n.public, n.created_at ORDER BY n.ns",
+        )
+        .bind(uid_bytes)
+        .fetch_all(&self.crdb)
+        .await?
+        .iter()
+        .map(|row| {
+            let created_at: DateTime<Utc> = row.get("created_at");
+            json!({
+                "ns": row.get::<String, _>("ns"),
+                "public": row.get::<String, _>("public"),
+                "created_at": created_at.to_rfc3339(),
+                "acl": row.get::<Value, _>("acl"),
+            })
+        })
+        .collect();
+
+        let map_acl: Vec<Value> =
+            sqlx::query("SELECT ns, level FROM map_acl WHERE uid = $1 ORDER BY ns")
+                .bind(uid_bytes)
+                .fetch_all(&self.crdb)
+                .await?
+                .iter()
+                .map(|row| {
+                    json!({
+                        "ns": row.get::<String, _>("ns"),
+                        "level": row.get::<String, _>("level"),
+                    })
+                })
+                .collect();
+
+        let wasm_genes: Vec<Value> = sqlx::query(
+            "SELECT id, hash, description, created_at FROM wasm_genes
+             WHERE uid = $1 ORDER BY created_at",
+        )
+        .bind(uid_bytes)
+        .fetch_all(&self.crdb)
+        .await?
+        .iter()
+        .map(|row| {
+            let created_at: DateTime<Utc> = row.get("created_at");
+            json!({
+                "id": row.get::<String, _>("id"),
+                "hash": hex::encode(row.get::<Vec<u8>, _>("hash")),
+                "description": row.get::<String, _>("description"),
+                "created_at": created_at.to_rfc3339(),
+            })
+        })
+        .collect();
+
+        let meme_visas: Vec<Value> = self
+            .get_meme_visas_of(uid)
+            .await?
+            .iter()
+            .map(|(peer, hash, ttl)| {
+                json!({
+                    "peer": peer.to_string(),
+                    "hash": hex::encode(hash),
+                    "ttl": ttl,
+                })
+            })
+            .collect();
+
+        let sessions: Vec<Value> = self
+            .list_sessions(uid)
+            .await?
+            .iter()
+            .map(|s| {
+                json!({
+                    "handle": hex::encode(&s.handle),
+                    "label": s.label,
+                    "created": s.created.to_rfc3339(),
+                    "last_used": s.last_used.to_rfc3339(),
+                })
+            })
+            .collect();
+
+        Ok(json!({
+            "uid": uid.to_string(),
+            "exported_at": Utc::now().to_rfc3339(),
+            "phone": self.get_uid_to_phone(uid).await?,
+            "email": self.get_uid_to_email(uid).await?,
+            "credit": self.get_credit(uid).await?,
+            "credit_log": credit_log,
+            "memes": memes,
+            "map_docs": map_docs,
+            "map_ns": map_ns,
+            "map_acl": map_acl,
+            "wasm_genes": wasm_genes,
+            "meme_visas": meme_visas,
+            "identity_log": identity_log,
+            "sessions": sessions,
+        }))
+    }
+
+    /// Store data as a private meme of uid until eol.
+    pub async fn put_archive(&self, uid: &Id, data: Vec<u8>, eol: DateTime<Utc>) -> Result<Hash> {
+        let oid = {
+            let mut rng = rand::rng();
+            Id::rand(&mut rng)
+        };
+        let hash = blake3::hash(&data);
+        let size = data.len() as i64;
+
+        self.mr.put_object(&oid.to_string(), &data).await?;
+
+        sqlx::query(
+            "INSERT INTO meme_meta (uid, oid, hash, size, pub, tip, eol)
+             VALUES ($1, $2, $3, $4, false, 0, $5)",
+        )
+        .bind(&uid.0[..])
+        .bind(&oid.0[..])
+        .bind(hash.as_bytes().as_slice())
+        .bind(size)
+        .bind(eol)
+        .execute(&self.crdb)
+        .await?;
+
+        Ok(hash.into())
+    }
+
+    /// Schedule deletion of uid, replacing an earlier schedule.
+    pub async fn schedule_account_deletion(
+        &self,
+        uid: &Id,
+        archive: &Hash,
+        due: DateTime<Utc>,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO account_deletions (uid, archive, due) VALUES ($1, $2, $3)
+             ON CONFLICT (uid) DO UPDATE SET archive = excluded.archive, due = excluded.due",
+        )
+        .bind(&uid.0[..])
+        .bind(&archive[..])
+        .bind(due)
+        .execute(&self.crdb)
+        .await?;
+        Ok(())
+    }
+
+    /// Get the archive and due time of a pending deletion of uid.
+    pub async fn get_account_deletion(&self, uid: &Id) -> Result<Option<(Hash, DateTime<Utc>)>> {
+        let row = sqlx::query("SELECT archive, due FROM account_deletions WHERE uid = $1")
+            .bind(&uid.0[..])
+            .fetch_optional(&self.crdb)
+            .await?;
+        row.map(|row| {
+            let archive: Vec<u8> = row.get("archive");
+            let archive = archive.try_into().map_err(|_| Error::ApiParseHash)?;
+            Ok((archive, row.get("due")))
+        })
+        .transpose()
+    }
+
+    /// Cancel scheduled deletion, false if none.
+    /// The archive expires with it, ripperd removes it.
+    pub async fn cancel_account_deletion(&self, uid: &Id) -> Result<bool> {
+        let row = sqlx::query("DELETE FROM account_deletions WHERE uid = $1 RETURNING archive")
+            .bind(&uid.0[..])
+            .fetch_optional(&self.crdb)
+            .await?;
+        let Some(row) = row else {
+            return Ok(false);
+        };
+        sqlx::query(
+            "UPDATE meme_meta SET eol = now() WHERE uid = $1 AND hash = $2 AND eol > now()",
+        )
+        .bind(&uid.0[..])
+        .bind(row.get::<Vec<u8>, _>("archive"))
+        .execute(&self.crdb)
+        .await?;
+        Ok(true)
+    }
+
+    /// Get accounts whose grace period is over.
+    pub async fn get_due_account_deletions(&self) -> Result<Vec<Id>> {
+        let rows = sqlx::query("SELECT uid FROM account_deletions WHERE due < now()")
+            .fetch_all(&self.crdb)
+            .await?;
+        rows.into_iter()
+            .map(|row| Id::try_from(row.get::<Vec<u8>, _>("uid")))
+            .collect()
+    }
+
+    /// Delete every row and object of uid. Credit log entries are kept
+    /// for bookkeeping, with uid replaced by zero.
+    /// Claimed namespaces are released, with the grants in them.
+    /// Safe to retry, the schedule is removed last.
+    pub async fn delete_account(&self, uid: &Id) -> Result<()> {
+        let uid_bytes = &uid.0[..];
+
+        // ScyllaDB
+        self.revoke_all_sessions(uid).await?;
+        if let Some(phone) = self.get_uid_to_phone(uid).await? {
+            if self.get_phone_to_uid(&phone).await? == Some(*uid) {
+                self.scylla
+                    .execute_unpaged(&self.stmts.delete_phone_to_uid, (&phone,))
+                    .await?;
+            }
+            self.scylla
+                .execute_unpaged(&self.stmts.delete_uid_to_phone, (uid_bytes,))
+                .await?;
+        }
+        if let Some(email) = self.get_uid_to_email(uid).await? {
+            if self.get_email_to_uid(&email).await? == Some(*uid) {
+                self.scylla
+                    .execute_unpaged(&self.stmts.delete_email_to_uid, (&email,))
+                    .await?;
+            }
+            self.scylla
+                .execute_unpaged(&self.stmts.delete_uid_to_email, (uid_bytes,))
+                .await?;
+        }
+        let old_phones = sqlx::query(
+            "SELECT old_value FROM identity_log WHERE uid = $1 AND old_value IS NOT NULL",
+        )
+        .bind(uid_bytes)
+        .fetch_all(&self.crdb)
+        .await?;
+        for row in old_phones {
+            let phone: String = row.get("old_value");
+            if self.get_phone_tombstone(&phone).await? == Some(*uid) {
+                self.scylla
+                    .execute_unpaged(&self.stmts.delete_phone_tombstone, (&phone,))
+                    .await?;
+            }
+        }
+        self.del_totp(uid).await?;
+        self.del_anonymous(uid).await?;
+        self.scylla
+            .execute_unpaged(&self.stmts.delete_checkin, (uid_bytes,))
+            .await?;
+        for (peer, hash, _) in self.get_meme_visas_of(uid).await? {
+            self.del_meme_visa(&peer, &hash).await?;
+        }
+
+        // S3, before metadata so that no blob is left unreferenced
+        let memes = sqlx::query("SELECT id, oid FROM meme_meta WHERE uid = $1")
+            .bind(uid_bytes)
+            .fetch_all(&self.crdb)
+            .await?;
+        for row in memes {
+            let id: uuid::Uuid = row.get("id");
+            let oid: Vec<u8> = row.get("oid");
+            self.mr.delete_object(&hex::encode(&oid)).await?;
+            sqlx::query("DELETE FROM meme_meta WHERE id = $1")
+                .bind(id)
+                .execute(&self.crdb)
+                .await?;
+        }
+
+        // CockroachDB
+        let mut tx = self.crdb.begin().await?;
+        sqlx::query("DELETE FROM map_docs WHERE uid = $1")
+            .bind(uid_bytes)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "DELETE FROM map_acl WHERE uid = $1 OR ns IN (SELECT ns FROM map_ns WHERE uid = $1)",
+        )
+        .bind(uid_bytes)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM map_ns WHERE uid = $1")
+            .bind(uid_bytes)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM wasm_genes WHERE uid = $1")
+            .bind(uid_bytes)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM identity_log WHERE uid = $1")
+            .bind(uid_bytes)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM credit_holds WHERE uid = $1")
+            .bind(uid_bytes)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE credit_log SET uid = $2 WHERE uid = $1")
+            .bind(uid_bytes)
+            .bind(&Id::zero().0[..])
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE credit_log SET other_uid = NULL WHERE other_uid = $1")
+            .bind(uid_bytes)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM user_accounts WHERE uid = $1")
+            .bind(uid_bytes)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM account_deletions WHERE uid = $1")
+            .bind(uid_bytes)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
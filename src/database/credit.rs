@@ -1,7 +1,16 @@
 use super::Database;
 use crate::ir::Id;
 use crate::{Error, Result};
+use chrono::{DateTime, Utc};
 use sqlx::Row;
+use uuid::Uuid;
+
+/// Credit debited up front and kept pending until captured or released.
+#[derive(Debug, Clone, Copy)]
+pub struct Hold {
+    pub id: Uuid,
+    pub amount: i64,
+}
 
 impl Database {
     /// Get user's credit balance from CockroachDB.
@@ -88,7 +97,13 @@ impl Database {
     }
 
     /// Log credit transaction (non-blocking).
-    async fn log_credit_transaction(&self, uid: &Id, other: Option<&Id>, amount: i64, note: &str) {
+    pub(super) async fn log_credit_transaction(
+        &self,
+        uid: &Id,
+        other: Option<&Id>,
+        amount: i64,
+        note: &str,
+    ) {
         let crdb = self.crdb.clone();
         let uid = uid.0.to_vec();
         let other = other.map(|id| id.0.to_vec());
@@ -106,4 +121,103 @@ impl Database {
             .await;
         });
     }
+
+    /// Debit n into a persisted hold that ripperd releases after deadline.
+    pub async fn hold_credit(
+        &self,
+        uid: &Id,
+        n: i64,
+        deadline: DateTime<Utc>,
+        note: &str,
+    ) -> Result<Hold> {
+        if n < 0 {
+            return Err(Error::NumCheck);
+        }
+
+        let mut tx = self.crdb.begin().await?;
+
+        // Check and debit in one statement
+        let result = sqlx::query(
+            "UPDATE user_accounts SET credit = credit - $1, updated_at = now()
+             WHERE uid = $2 AND credit - $1 >= $3",
+        )
+        .bind(n)
+        .bind(&uid.0[..])
+        .bind(self.credit_limit)
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(Error::CostInsufficientCredit);
+        }
+
+        // Persist the hold in the same transaction
+        let row = sqlx::query(
+            "INSERT INTO credit_holds (uid, amount, note, deadline) VALUES ($1, $2, $3, $4)
+             RETURNING id",
+        )
+        .bind(&uid.0[..])
+        .bind(n)
+        .bind(note)
+        .bind(deadline)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        self.log_credit_transaction(uid, None, -n, note).await;
+
+        Ok(Hold {
+            id: row.get("id"),
+            amount: n,
+        })
+    }
+
+    /// Settle a hold by keeping used and returning the rest.
+    /// A hold settles only once, so later calls are no-ops.
+    pub async fn capture_hold(&self, hold: &Uuid, used: i64, note: &str) -> Result<()> {
+        let mut tx = self.crdb.begin().await?;
+
+        let row = sqlx::query("DELETE FROM credit_holds WHERE id = $1 RETURNING uid, amount")
+            .bind(hold)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let Some(row) = row else {
+            return Ok(());
+        };
+        let uid: Vec<u8> = row.get("uid");
+        let amount: i64 = row.get("amount");
+
+        let refund = amount - used.clamp(0, amount);
+        if refund > 0 {
+            sqlx::query(
+                "UPDATE user_accounts SET credit = credit + $1, updated_at = now() WHERE uid = $2",
+            )
+            .bind(refund)
+            .bind(&uid)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        if refund > 0 {
+            let uid = Id::try_from(uid)?;
+            self.log_credit_transaction(&uid, None, refund, note).await;
+        }
+
+        Ok(())
+    }
+
+    /// Return the whole hold.
+    pub async fn release_hold(&self, hold: &Uuid, note: &str) -> Result<()> {
+        self.capture_hold(hold, 0, note).await
+    }
+
+    /// Holds whose deadline has passed.
+    pub async fn get_expired_holds(&self) -> Result<Vec<Uuid>> {
+        let rows = sqlx::query("SELECT id FROM credit_holds WHERE deadline < now()")
+            .fetch_all(&self.crdb)
+            .await?;
+        Ok(rows.iter().map(|row| row.get("id")).collect())
+    }
 }
@@ -0,0 +1,164 @@
+use super::Database;
+use super::limit::applied;
+use crate::ir::{Hash, Id};
+use crate::{Error, Result};
+use sqlx::Row;
+
+/// Credits owed with a peer, from the fed ledger:
+/// - Forward: this instance used the peer, paid from its account there.
+/// - Serve: the peer used this instance, paid from its account here.
+/// - Claim: this instance was credited at the peer for serving it.
+/// - Claimed: the peer was credited here for serving this instance.
+///
+/// Owed here is in local credits, owed there is in peer credits.
+const BALANCE: &str = "SELECT
+    COALESCE(SUM(CASE kind WHEN 'Serve' THEN local_amount WHEN 'Claim' THEN -local_amount ELSE 0 END), 0)::INT8 AS owed_here,
+    COALESCE(SUM(CASE kind WHEN 'Forward' THEN peer_amount WHEN 'Claimed' THEN -peer_amount ELSE 0 END), 0)::INT8 AS owed_there
+    FROM fed_ledger WHERE peer = $1";
+
+impl Database {
+    /// Remember the nonce of a signed request for ttl seconds.
+    /// False if it was seen before.
+    pub async fn use_fed_nonce(&self, nonce: &Id, ttl: i64) -> Result<bool> {
+        let result = self
+            .scylla
+            .execute_unpaged(&self.stmts.insert_fed_nonce, (&nonce.0[..], ttl as i32))
+            .await?;
+        applied(result)
+    }
+
+    /// Let peer fetch the meme of uid by hash for ttl seconds.
+    pub async fn set_meme_visa(&self, peer: &Id, hash: &Hash, uid: &Id, ttl: i64) -> Result<()> {
+        self.scylla
+            .execute_unpaged(
+                &self.stmts.insert_meme_visa,
+                (&peer.0[..], &hash[..], &uid.0[..], ttl as i32),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Owner of the meme peer has a visa for.
+    pub async fn get_meme_visa(&self, peer: &Id, hash: &Hash) -> Result<Option<Id>> {
+        let result = self
+            .scylla
+            .execute_unpaged(&self.stmts.select_meme_visa, (&peer.0[..], &hash[..]))
+            .await?;
+        if let Some(row) = result.into_rows_result()?.rows::<(Vec<u8>,)>()?.next() {
+            let (uid,) = row?;
+            return Ok(Some(Id::try_from(uid)?));
+        }
+        Ok(None)
+    }
+
+    /// Visas given by uid, as (peer, hash, seconds left).
+    pub async fn get_meme_visas_of(&self, uid: &Id) -> Result<Vec<(Id, Hash, i32)>> {
+        let result = self
+            .scylla
+            .execute_unpaged(&self.stmts.select_meme_visas_by_uid, (&uid.0[..],))
+            .await?;
+        let mut visas = vec![];
+        for row in result
+            .into_rows_result()?
+            .rows::<(Vec<u8>, Vec<u8>, Option<i32>)>()?
+        {
+            let (peer, hash, ttl) = row?;
+            let hash: Hash = hash.try_into().map_err(|_| Error::MemeHash)?;
+            visas.push((Id::try_from(peer)?, hash, ttl.unwrap_or_default()));
+        }
+        Ok(visas)
+    }
+
+    /// Revoke the visa of peer for the meme by hash.
+    pub async fn del_meme_visa(&self, peer: &Id, hash: &Hash) -> Result<()> {
+        self.scylla
+            .execute_unpaged(&self.stmts.delete_meme_visa, (&peer.0[..], &hash[..]))
+            .await?;
+        Ok(())
+    }
+
+    /// Add an entry to the fed ledger, rate in local credits per peer credit.
+    pub async fn log_fed(
+        &self,
+        peer: &Id,
+        kind: &str,
+        peer_amount: i64,
+        local_amount: i64,
+        rate: f64,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO fed_ledger (peer, kind, peer_amount, local_amount, rate)
+             VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(&peer.0[..])
+        .bind(kind)
+        .bind(peer_amount)
+        .bind(local_amount)
+        .bind(rate)
+        .execute(&self.crdb)
+        .await?;
+        Ok(())
+    }
+
+    /// Credits the peer owes here, and credits owed to the peer there.
+    pub async fn get_fed_balance(&self, peer: &Id) -> Result<(i64, i64)> {
+        let row = sqlx::query(BALANCE)
+            .bind(&peer.0[..])
+            .fetch_one(&self.crdb)
+            .await?;
+        Ok((row.get("owed_here"), row.get("owed_there")))
+    }
+
+    /// Credit uid, the account of peer, for amount peer credits it served.
+    /// Claims beyond what this instance owes are refused.
+    pub async fn accept_fed_claim(
+        &self,
+        peer: &Id,
+        uid: &Id,
+        amount: i64,
+        rate: f64,
+    ) -> Result<i64> {
+        if amount <= 0 {
+            return Err(Error::NumCheck);
+        }
+        let credit = (amount as f64 * rate) as i64;
+
+        let mut tx = self.crdb.begin().await?;
+
+        let row = sqlx::query(BALANCE)
+            .bind(&peer.0[..])
+            .fetch_one(&mut *tx)
+            .await?;
+        let owed_there: i64 = row.get("owed_there");
+        if amount > owed_there {
+            return Err(Error::FedClaim);
+        }
+
+        sqlx::query(
+            "INSERT INTO fed_ledger (peer, kind, peer_amount, local_amount, rate)
+             VALUES ($1, 'Claimed', $2, $3, $4)",
+        )
+        .bind(&peer.0[..])
+        .bind(amount)
+        .bind(credit)
+        .bind(rate)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            "INSERT INTO user_accounts (uid, credit) VALUES ($1, $2)
+             ON CONFLICT (uid) DO UPDATE SET credit = user_accounts.credit + $2, updated_at = now()",
+        )
+        .bind(&uid.0[..])
+        .bind(credit)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        self.log_credit_transaction(uid, Some(peer), credit, "FedCreditClaim")
+            .await;
+
+        Ok(credit)
+    }
+}
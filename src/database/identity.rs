@@ -0,0 +1,111 @@
+use super::Database;
+use crate::Result;
+use crate::ir::Id;
+use scylla::statement::batch::{Batch, BatchType};
+
+impl Database {
+    /// Get the account a phone moved away from during its cooldown.
+    pub async fn get_phone_tombstone(&self, phone: &str) -> Result<Option<Id>> {
+        let result = self
+            .scylla
+            .execute_unpaged(&self.stmts.select_phone_tombstone, (phone,))
+            .await?;
+
+        if let Some(row) = result.into_rows_result()?.rows::<(Vec<u8>,)>()?.next() {
+            let (uid_bytes,) = row?;
+            return Ok(Some(Id::try_from(uid_bytes)?));
+        }
+        Ok(None)
+    }
+
+    /// Atomically move uid from old to new phone. With tombstone, old
+    /// keeps pointing to uid for the cooldown, so that it can recover.
+    /// The change is logged as kind.
+    pub async fn change_phone(
+        &self,
+        uid: &Id,
+        old: Option<&str>,
+        new: &str,
+        tombstone: bool,
+        kind: &str,
+    ) -> Result<()> {
+        let uid_bytes = &uid.0[..];
+        let ttl = self.user_ttl as i32;
+        let cooldown = self.phone_cooldown as i32;
+
+        let mut batch = Batch::new(BatchType::Logged);
+        batch.append_statement(self.stmts.insert_phone_to_uid.clone());
+        batch.append_statement(self.stmts.insert_uid_to_phone.clone());
+        batch.append_statement(self.stmts.delete_phone_tombstone.clone());
+        let set_new = ((new, uid_bytes, ttl), (uid_bytes, new, ttl), (new,));
+
+        match old {
+            Some(old) if tombstone => {
+                batch.append_statement(self.stmts.delete_phone_to_uid.clone());
+                batch.append_statement(self.stmts.insert_phone_tombstone.clone());
+                let (a, b, c) = set_new;
+                self.scylla
+                    .batch(&batch, (a, b, c, (old,), (old, uid_bytes, cooldown)))
+                    .await?;
+            }
+            Some(old) => {
+                batch.append_statement(self.stmts.delete_phone_to_uid.clone());
+                let (a, b, c) = set_new;
+                self.scylla.batch(&batch, (a, b, c, (old,))).await?;
+            }
+            None => {
+                self.scylla.batch(&batch, set_new).await?;
+            }
+        }
+
+        self.log_identity(uid, kind, old, Some(new)).await
+    }
+
+    /// Record an identity change for audit.
+    pub async fn log_identity(
+        &self,
+        uid: &Id,
+        kind: &str,
+        old: Option<&str>,
+        new: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO identity_log (uid, kind, old_value, new_value) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(&uid.0[..])
+        .bind(kind)
+        .bind(old)
+        .bind(new)
+        .execute(&self.crdb)
+        .await?;
+        Ok(())
+    }
+
+    /// Mark uid as anonymous, for as long as its account lives.
+    pub async fn set_anonymous(&self, uid: &Id) -> Result<()> {
+        self.scylla
+            .execute_unpaged(
+                &self.stmts.insert_anonymous,
+                (&uid.0[..], self.user_ttl as i32),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Whether uid has neither phone nor email yet.
+    pub async fn is_anonymous(&self, uid: &Id) -> Result<bool> {
+        let result = self
+            .scylla
+            .execute_unpaged(&self.stmts.select_anonymous, (&uid.0[..],))
+            .await?;
+        Ok(result.into_rows_result()?.rows_num() > 0)
+    }
+
+    /// Upgrade uid to a full account.
+    pub async fn del_anonymous(&self, uid: &Id) -> Result<()> {
+        self.scylla
+            .execute_unpaged(&self.stmts.delete_anonymous, (&uid.0[..],))
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+use super::Database;
+use crate::Result;
+use scylla::response::query_result::QueryResult;
+use scylla::value::{CqlValue, Row};
+
+/// Whether a lightweight transaction was applied, from its first column.
+pub(super) fn applied(result: QueryResult) -> Result<bool> {
+    if let Some(row) = result.into_rows_result()?.rows::<Row>()?.next() {
+        let row = row?;
+        return Ok(matches!(
+            row.columns.first(),
+            Some(Some(CqlValue::Boolean(true)))
+        ));
+    }
+    Ok(false)
+}
+
+impl Database {
+    /// Get the arrival time of a rate limit key, in milliseconds.
+    pub async fn get_rate_tat(&self, key: &str) -> Result<Option<i64>> {
+        let result = self
+            .scylla
+            .execute_unpaged(&self.stmts.select_rate_tat, (key,))
+            .await?;
+
+        if let Some(row) = result.into_rows_result()?.rows::<(Option<i64>,)>()?.next() {
+            let (tat,) = row?;
+            return Ok(tat);
+        }
+        Ok(None)
+    }
+
+    /// Set the arrival time of key if it is still old. False if another
+    /// instance got there first.
+    pub async fn set_rate_tat(
+        &self,
+        key: &str,
+        old: Option<i64>,
+        new: i64,
+        ttl: i64,
+    ) -> Result<bool> {
+        let ttl = ttl as i32;
+        let result = match old {
+            Some(old) => {
+                self.scylla
+                    .execute_unpaged(&self.stmts.update_rate_tat, (ttl, new, key, old))
+                    .await?
+            }
+            None => {
+                self.scylla
+                    .execute_unpaged(&self.stmts.insert_rate_tat, (key, new, ttl))
+                    .await?
+            }
+        };
+        applied(result)
+    }
+}
@@ -33,6 +33,12 @@ impl Ripperd {
             if let Err(error) = self.rip_map1().await {
                 println!("Rip map1 error: {}", error);
             }
+            if let Err(error) = self.rip_holds().await {
+                println!("Rip holds error: {}", error);
+            }
+            if let Err(error) = self.rip_accounts().await {
+                println!("Rip accounts error: {}", error);
+            }
             // Note: Credit log cleanup is no longer needed.
             // TigerBeetle's transfer log is the audit trail.
         }
@@ -84,4 +90,26 @@ impl Ripperd {
 
         Ok(())
     }
+
+    /// Release credit holds left behind by crashed requests.
+    async fn rip_holds(&self) -> Result<()> {
+        for id in self.db.get_expired_holds().await? {
+            if let Err(e) = self.db.release_hold(&id, "CostHoldExpired").await {
+                println!("Rip hold error for {}: {}", id, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Delete accounts whose grace period is over.
+    async fn rip_accounts(&self) -> Result<()> {
+        for uid in self.db.get_due_account_deletions().await? {
+            if let Err(e) = self.db.delete_account(&uid).await {
+                println!("Rip account error for {}: {}", uid, e);
+            }
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,257 @@
+use super::Database;
+use crate::Result;
+use crate::ir::Id;
+use chrono::{DateTime, Utc};
+use scylla::value::CqlTimestamp;
+
+/// Bytes of a session handle.
+const HANDLE_LEN: usize = 8;
+
+/// Revocations at other instances are seen here within this many seconds.
+pub const ACCESS_REVOCATIONS_POLL: u64 = 5;
+
+/// One token of a user's login.
+pub struct UserSession {
+    /// Shared by the tokens of one login.
+    pub handle: Vec<u8>,
+    pub sid: Vec<u8>,
+    /// 0 is access, 1 is refresh, as in the sessions table.
+    pub kind: i8,
+    pub label: String,
+    pub created: DateTime<Utc>,
+    /// Refreshed on sign in, on AuthSessionRefresh and on access.
+    pub last_used: DateTime<Utc>,
+    /// Seconds until the entry expires.
+    pub ttl: i32,
+}
+
+/// A login is named after its refresh token, without revealing it.
+pub fn session_handle(refresh: &[u8]) -> Vec<u8> {
+    blake3::hash(refresh).as_bytes()[..HANDLE_LEN].to_vec()
+}
+
+fn from_cql(ts: CqlTimestamp) -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(ts.0).unwrap_or_else(Utc::now)
+}
+
+impl Database {
+    /// Index a token under the login of refresh, with the TTL of its kind.
+    pub async fn index_session(
+        &self,
+        uid: &Id,
+        refresh: &[u8],
+        sid: &[u8],
+        kind: i8,
+        label: &str,
+        created: DateTime<Utc>,
+    ) -> Result<()> {
+        let ttl = if kind == 0 {
+            self.access_ttl
+        } else {
+            self.refresh_ttl
+        };
+        self.scylla
+            .execute_unpaged(
+                &self.stmts.insert_user_session,
+                (
+                    &uid.0[..],
+                    session_handle(refresh),
+                    sid,
+                    kind,
+                    label,
+                    CqlTimestamp(created.timestamp_millis()),
+                    CqlTimestamp(Utc::now().timestamp_millis()),
+                    ttl as i32,
+                ),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Get label and creation time of the login of refresh.
+    pub async fn get_session_info(
+        &self,
+        uid: &Id,
+        refresh: &[u8],
+    ) -> Result<Option<(String, DateTime<Utc>)>> {
+        let result = self
+            .scylla
+            .execute_unpaged(
+                &self.stmts.select_user_session,
+                (&uid.0[..], session_handle(refresh), refresh),
+            )
+            .await?;
+
+        type Row = (Option<String>, Option<CqlTimestamp>);
+        if let Some(row) = result.into_rows_result()?.rows::<Row>()?.next() {
+            let (label, created) = row?;
+            return Ok(Some((
+                label.unwrap_or_default(),
+                created.map_or_else(Utc::now, from_cql),
+            )));
+        }
+        Ok(None)
+    }
+
+    /// List indexed tokens of uid.
+    pub async fn list_sessions(&self, uid: &Id) -> Result<Vec<UserSession>> {
+        let result = self
+            .scylla
+            .execute_unpaged(&self.stmts.select_user_sessions, (&uid.0[..],))
+            .await?;
+
+        type Row = (
+            Vec<u8>,
+            Vec<u8>,
+            Option<i8>,
+            Option<String>,
+            Option<CqlTimestamp>,
+            Option<CqlTimestamp>,
+            Option<i32>,
+        );
+        let mut sessions = Vec::new();
+        for row in result.into_rows_result()?.rows::<Row>()? {
+            let (handle, sid, kind, label, created, last_used, ttl) = row?;
+            sessions.push(UserSession {
+                handle,
+                sid,
+                kind: kind.unwrap_or_default(),
+                label: label.unwrap_or_default(),
+                created: created.map_or_else(Utc::now, from_cql),
+                last_used: last_used.map_or_else(Utc::now, from_cql),
+                ttl: ttl.unwrap_or_default(),
+            });
+        }
+        Ok(sessions)
+    }
+
+    /// Bump last_used of the login that access belongs to,
+    /// keeping the remaining TTL of each entry.
+    pub async fn touch_session(&self, uid: &Id, access: &[u8]) -> Result<()> {
+        let sessions = self.list_sessions(uid).await?;
+        let Some(handle) = sessions.iter().find(|s| s.sid == access).map(|s| &s.handle) else {
+            return Ok(());
+        };
+        let now = CqlTimestamp(Utc::now().timestamp_millis());
+        for s in sessions.iter().filter(|s| &s.handle == handle && s.ttl > 0) {
+            self.scylla
+                .execute_unpaged(
+                    &self.stmts.update_user_session_used,
+                    (s.ttl, now, &uid.0[..], &s.handle, &s.sid),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Delete the index entry of a token.
+    pub async fn unindex_session(&self, uid: &Id, sid: &[u8]) -> Result<()> {
+        for s in self.list_sessions(uid).await? {
+            if s.sid == sid {
+                self.scylla
+                    .execute_unpaged(
+                        &self.stmts.delete_user_session,
+                        (&uid.0[..], &s.handle, &s.sid),
+                    )
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deny signed access tokens of uid issued until now, to the login
+    /// with handle, or to every login if handle is empty. The entry
+    /// expires with the last token it denies.
+    pub async fn revoke_access(&self, uid: &Id, handle: &[u8]) -> Result<()> {
+        let now = Utc::now().timestamp();
+        self.scylla
+            .execute_unpaged(
+                &self.stmts.insert_access_revocation,
+                (&uid.0[..], handle, now, self.access_ttl as i32),
+            )
+            .await?;
+        self.note_access_revocation(*uid, handle.to_vec(), now);
+        Ok(())
+    }
+
+    fn note_access_revocation(&self, uid: Id, handle: Vec<u8>, since: i64) {
+        let mut revocations = self.access_revocations.write().unwrap();
+        let entry = revocations.entry((uid, handle)).or_default();
+        *entry = since.max(*entry);
+    }
+
+    /// Whether a signed access token of uid, issued at iat to the login
+    /// with handle, was revoked. Checked in process, without a round trip.
+    pub fn access_revoked(&self, uid: &Id, handle: &[u8], iat: i64) -> bool {
+        let revocations = self.access_revocations.read().unwrap();
+        [&[][..], handle].iter().any(|handle| {
+            revocations
+                .get(&(*uid, handle.to_vec()))
+                .is_some_and(|since| iat <= *since)
+        })
+    }
+
+    /// Load revocations made at any instance, and forget expired ones.
+    pub async fn load_access_revocations(&self) -> Result<()> {
+        let result = self
+            .scylla
+            .execute_unpaged(&self.stmts.select_access_revocations, &[])
+            .await?;
+        for row in result
+            .into_rows_result()?
+            .rows::<(Vec<u8>, Vec<u8>, i64)>()?
+        {
+            let (uid, handle, since) = row?;
+            self.note_access_revocation(Id::try_from(uid)?, handle, since);
+        }
+        let expired = Utc::now().timestamp() - self.access_ttl;
+        self.access_revocations
+            .write()
+            .unwrap()
+            .retain(|_, since| *since > expired);
+        Ok(())
+    }
+
+    /// Keep revocations in process up to date, every ACCESS_REVOCATIONS_POLL.
+    pub async fn poll_access_revocations(&self) {
+        let mut interval =
+            tokio::time::interval(std::time::Duration::from_secs(ACCESS_REVOCATIONS_POLL));
+        loop {
+            interval.tick().await;
+            if let Err(error) = self.load_access_revocations().await {
+                println!("Access revocations error: {}", error);
+            }
+        }
+    }
+
+    /// Delete all tokens of the login with handle. False if none.
+    pub async fn revoke_session(&self, uid: &Id, handle: &[u8]) -> Result<bool> {
+        self.revoke_access(uid, handle).await?;
+        let mut found = false;
+        for s in self.list_sessions(uid).await? {
+            if s.handle == handle {
+                found = true;
+                self.del_session(&s.sid).await?;
+                self.scylla
+                    .execute_unpaged(
+                        &self.stmts.delete_user_session,
+                        (&uid.0[..], &s.handle, &s.sid),
+                    )
+                    .await?;
+            }
+        }
+        Ok(found)
+    }
+
+    /// Delete all tokens of uid.
+    pub async fn revoke_all_sessions(&self, uid: &Id) -> Result<()> {
+        self.revoke_access(uid, &[]).await?;
+        for s in self.list_sessions(uid).await? {
+            self.del_session(&s.sid).await?;
+        }
+        self.scylla
+            .execute_unpaged(&self.stmts.delete_user_sessions, (&uid.0[..],))
+            .await?;
+        Ok(())
+    }
+}
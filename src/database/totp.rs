@@ -0,0 +1,83 @@
+use super::Database;
+use crate::Result;
+use crate::ir::Id;
+
+/// TOTP enrollment of a user. Rows have no TTL, so that the factor
+/// never silently expires while the account lives.
+pub struct Totp {
+    pub secret: Vec<u8>,
+    /// False until the first code is verified.
+    pub enabled: bool,
+    /// Last accepted step, to prevent replays.
+    pub last_step: i64,
+    /// Hashes of unused recovery codes.
+    pub recovery: Vec<Vec<u8>>,
+}
+
+impl Database {
+    /// Start a pending enrollment, replacing the old one.
+    pub async fn set_totp(&self, uid: &Id, secret: &[u8], recovery: Vec<Vec<u8>>) -> Result<()> {
+        self.scylla
+            .execute_unpaged(&self.stmts.insert_totp, (&uid.0[..], secret, recovery))
+            .await?;
+        Ok(())
+    }
+
+    /// Get TOTP enrollment of uid.
+    pub async fn get_totp(&self, uid: &Id) -> Result<Option<Totp>> {
+        let result = self
+            .scylla
+            .execute_unpaged(&self.stmts.select_totp, (&uid.0[..],))
+            .await?;
+
+        type Row = (
+            Option<Vec<u8>>,
+            Option<bool>,
+            Option<i64>,
+            Option<Vec<Vec<u8>>>,
+        );
+        if let Some(row) = result.into_rows_result()?.rows::<Row>()?.next() {
+            if let (Some(secret), enabled, last_step, recovery) = row? {
+                return Ok(Some(Totp {
+                    secret,
+                    enabled: enabled.unwrap_or_default(),
+                    last_step: last_step.unwrap_or_default(),
+                    recovery: recovery.unwrap_or_default(),
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Record the last accepted step.
+    pub async fn set_totp_step(&self, uid: &Id, step: i64) -> Result<()> {
+        self.scylla
+            .execute_unpaged(&self.stmts.update_totp_step, (step, &uid.0[..]))
+            .await?;
+        Ok(())
+    }
+
+    /// Finish enrollment at step.
+    pub async fn enable_totp(&self, uid: &Id, step: i64) -> Result<()> {
+        self.scylla
+            .execute_unpaged(&self.stmts.update_totp_enabled, (step, &uid.0[..]))
+            .await?;
+        Ok(())
+    }
+
+    /// Consume a recovery code by its hash.
+    pub async fn del_totp_recovery(&self, uid: &Id, hash: &[u8]) -> Result<()> {
+        self.scylla
+            .execute_unpaged(&self.stmts.update_totp_recovery, (vec![hash], &uid.0[..]))
+            .await?;
+        Ok(())
+    }
+
+    /// Remove TOTP from uid.
+    pub async fn del_totp(&self, uid: &Id) -> Result<()> {
+        self.scylla
+            .execute_unpaged(&self.stmts.delete_totp, (&uid.0[..],))
+            .await?;
+        Ok(())
+    }
+}
@@ -10,6 +10,10 @@ pub enum Error {
     ApiMissingEntry,
     ApiUnknownQueryType,
     ApiMissingQueryType,
+    ApiPayloadTooLarge,
+
+    LimitExceeded(u64),
+    LimitRule(String),
 
     AuthInvalidAccessToken,
     AuthInvalidRefreshToken,
@@ -17,6 +21,24 @@ pub enum Error {
     AuthInvalidPhone,
     AuthInvalidUid,
     AuthTokensMismatch,
+    AuthSmsProvider,
+    AuthSmsSignature,
+    AuthInvalidEmail,
+    AuthInvalidEmailToken,
+    AuthEmailNotVerified,
+    AuthMailTransport,
+    AuthMail,
+    AuthTotpRequired,
+    AuthTotpInvalid,
+    AuthTotpNotEnrolled,
+    AuthInvalidSessionHandle,
+    AuthAccountNotScheduled,
+    AuthPhoneTaken,
+    AuthPhoneCooldown,
+    AuthAccessExpired,
+    AuthScope,
+    AuthAnonymousDisabled,
+    AuthAnonymous,
 
     CostInsufficientCredit,
     CostTime,
@@ -27,15 +49,30 @@ pub enum Error {
     CostCheckInTooEarly,
 
     Fed,
+    FedPeer(String),
+    FedSignature,
+    FedReplay,
+    FedRate,
+    FedClaim,
 
     Gene,
     GeneInvalidId,
     GeneMapNotFound,
     GeneMapExpired,
+    GeneMapSort,
+    GeneMapCursor,
+    GeneMapVersion,
+    GeneMapPatch,
+    GeneMapBatch,
+    GeneMapKey,
+    GeneMapSchema(String),
+    GeneWasm,
+    GeneSubscribe,
 
     MemeNotFound,
     MemePut,
     MemeGet,
+    MemeHash,
 
     ScyllaQuery(Box<scylla::errors::ExecutionError>),
     ScyllaRows(Box<scylla::response::query_result::IntoRowsResultError>),
@@ -49,6 +86,7 @@ pub enum Error {
 
     Todo,
     GeoDim,
+    GeoRange,
     Logical,
     Namespace,
     NumCheck,
@@ -1,29 +1,589 @@
-//! Big TODO here.
-//! Memes can't be redirected.
+//! Forward queries to peer instances.
+//! This instance pays the peer from its own account there,
+//! and charges the user the exchanged amount here.
+//! Forwarded queries are signed, so peers need not share databases.
+//! Memes can't be redirected, but they can be cloned.
+//!
+//! Credits are exchanged at the middle of a static band per peer.
+//! What a peer used here is claimed back periodically, and credited to
+//! this instance's account there, so usage in both directions balances.
 
 use crate::config::Config;
-use crate::gene::Gene;
+use crate::database::{Database, Hold};
+use crate::gene::Genes;
 use crate::ir::{Costs, Id, Query, Reply};
+use crate::meme::Meme;
 use crate::{Error, Result};
+use chrono::Utc;
+use ed25519_dalek::VerifyingKey;
+use reqwest::Response;
+use reqwest::header::HeaderMap;
+use sign::{FedSig, Keys};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant, sleep};
+use tokio_stream::StreamExt;
+
+pub mod sign;
+
+/// Path where public keys are announced, no account needed.
+pub const FED_KEYS_PATH: &str = "/fed/keys";
+
+/// Keys of a peer are fetched again for an unknown kid after this.
+const KEYS_REFETCH: Duration = Duration::from_secs(60);
+
+/// Plan for queries this instance sends on its own behalf.
+const PLAN: Costs = Costs {
+    time: 5_000_000,
+    space: 0,
+    traffic: 100_000,
+    tip: 0,
+};
+
+/// Public keys of a peer by kid.
+type PeerKeys = Vec<(String, VerifyingKey)>;
 
 pub struct Fed {
-    gene: &'static Gene,
+    gene: &'static Genes,
+    meme: &'static Meme,
+    db: &'static Database,
+    members: &'static HashMap<Id, String>,
+    /// Refresh tokens at peers.
+    refresh: HashMap<Id, String>,
+    /// Peers by their account here.
+    accounts: HashMap<Id, Id>,
+    /// Cached access tokens at peers.
+    access: Mutex<HashMap<Id, String>>,
+    client: reqwest::Client,
+    fed_id: Id,
+    keys: Option<Keys>,
+    /// Announced public keys of peers, and when they were fetched.
+    peer_keys: Mutex<HashMap<Id, (Instant, PeerKeys)>>,
+    rates: &'static HashMap<Id, (f64, f64)>,
+    settle_interval: u64,
 }
 
 impl Fed {
-    pub fn new(_config: &Config, gene: &'static Gene) -> Fed {
-        Fed { gene }
+    pub fn new(
+        config: &Config,
+        db: &'static Database,
+        meme: &'static Meme,
+        gene: &'static Genes,
+    ) -> Fed {
+        let refresh = config
+            .fed_refresh
+            .split(',')
+            .filter_map(|entry| {
+                let (id, refresh) = entry.split_once('=')?;
+                Some((
+                    Id::from_str(id).expect("Invalid fed id"),
+                    refresh.to_string(),
+                ))
+            })
+            .collect();
+        Fed {
+            gene,
+            meme,
+            db,
+            members: config.fed_members,
+            refresh,
+            accounts: config
+                .fed_accounts
+                .iter()
+                .map(|(peer, uid)| (*uid, *peer))
+                .collect(),
+            access: Mutex::new(HashMap::new()),
+            client: reqwest::Client::new(),
+            fed_id: config.fed_id,
+            keys: Keys::new(&config.fed_keys),
+            peer_keys: Mutex::new(HashMap::new()),
+            rates: config.fed_rates,
+            settle_interval: config.fed_settle_interval,
+        }
     }
+
     pub async fn handle(
         &self,
         query: Query,
         uid: &Id,
         changes: Costs,
-        deadline: tokio::time::Instant,
+        deadline: Instant,
+        hold: &Hold,
+    ) -> Result<Reply> {
+        let peer = match query.get_sig() {
+            Some(sig) => {
+                self.check_sig(&query, sig).await?;
+                Some(sig.from)
+            }
+            None => None,
+        };
+        // Accounts of peers only take queries signed by them
+        if self
+            .accounts
+            .get(uid)
+            .is_some_and(|owner| peer != Some(*owner))
+        {
+            return Err(Error::FedSignature);
+        }
+        match query {
+            Query::FedCreditClaim { amount, rate, .. } => {
+                let peer = peer.ok_or(Error::FedSignature)?;
+                self.claimed(&peer, uid, amount, rate, changes).await
+            }
+            Query::FedMemeClone { .. } => {
+                self.clone_meme(query, uid, changes, deadline, hold).await
+            }
+            query => match (*query.get_fed(), peer) {
+                (Some(fed), _) => self.forward(query, &fed, changes, deadline, hold).await,
+                (None, Some(peer)) => self.serve(query, &peer, uid, changes, deadline, hold).await,
+                (None, None) => self.gene.handle(query, uid, changes, deadline, hold).await,
+            },
+        }
+    }
+
+    /// Send query to the peer, and stream its reply back.
+    /// What the peer charged is captured from the hold.
+    async fn forward(
+        &self,
+        query: Query,
+        fed: &Id,
+        changes: Costs,
+        deadline: Instant,
+        hold: &Hold,
+    ) -> Result<Reply> {
+        let url = self.members.get(fed).ok_or(Error::Fed)?;
+        let rate = self.rate(fed);
+        let plan = exchange(&changes, 1.0 / rate);
+        let headers = forward_headers(&query, &plan)?;
+        let response = self.call(fed, url, &headers, deadline).await?;
+
+        let kind = header(response.headers(), "type")?;
+        let changes = peer_changes(response.headers())?;
+        let peer_used =
+            plan.sum().ok_or(Error::NumCheck)? - changes.sum().ok_or(Error::NumCheck)?;
+        let changes = exchange(&changes, rate);
+        let used = (hold.amount - changes.sum().ok_or(Error::NumCheck)?).clamp(0, hold.amount);
+        self.db.capture_hold(&hold.id, used, "FedCapture").await?;
+        if peer_used > 0 {
+            self.db
+                .log_fed(fed, "Forward", peer_used, used, rate)
+                .await?;
+        }
+
+        Ok(Reply::Fed {
+            kind,
+            changes,
+            raw: Box::pin(response.bytes_stream()),
+        })
+    }
+
+    /// Pull a meme from a peer into the bucket, checked against its hash.
+    /// The peer is paid like a forwarded MemeGet, space is paid here.
+    /// Private memes need a visa from their owner at the peer.
+    async fn clone_meme(
+        &self,
+        query: Query,
+        uid: &Id,
+        changes: Costs,
+        deadline: Instant,
+        hold: &Hold,
+    ) -> Result<Reply> {
+        let Query::FedMemeClone {
+            head,
+            hash,
+            days,
+            public,
+        } = query
+        else {
+            return Err(Error::Logical);
+        };
+        let fed = head.fed.ok_or(Error::Fed)?;
+        let url = self.members.get(&fed).ok_or(Error::Fed)?;
+        let rate = self.rate(&fed);
+        let plan = exchange(
+            &Costs {
+                space: 0,
+                ..changes
+            },
+            1.0 / rate,
+        );
+        let mut headers = plan_headers("MemeGet", &plan);
+        headers.push(("hash", hex::encode(hash)));
+        headers.push(("public", public.to_string()));
+        let response = self.call(&fed, url, &headers, deadline).await?;
+
+        let left = peer_changes(response.headers())?;
+        let peer_used = plan.sum().ok_or(Error::NumCheck)? - left.sum().ok_or(Error::NumCheck)?;
+        let left = exchange(&left, rate);
+        let mut changes = Costs {
+            space: changes.space,
+            ..left
+        };
+        let raw = response
+            .bytes_stream()
+            .map(|data| data.map_err(|_| Error::Fed));
+        let result = self
+            .meme
+            .put_stream(uid, &mut changes, deadline, days, raw, Some(&hash))
+            .await;
+
+        // Time passes at both ends, keep the larger charge.
+        changes.time = changes.time.min(left.time);
+        let used = (hold.amount - changes.sum().ok_or(Error::NumCheck)?).clamp(0, hold.amount);
+        self.db.capture_hold(&hold.id, used, "FedCapture").await?;
+        if peer_used > 0 {
+            let local = (peer_used as f64 * rate) as i64;
+            self.db
+                .log_fed(&fed, "Forward", peer_used, local, rate)
+                .await?;
+        }
+
+        Ok(Reply::FedMemeClone {
+            changes,
+            hash: result?,
+        })
+    }
+
+    /// Handle a signed query of peer, and record what it used.
+    /// A visa lets the peer read a private meme as its owner.
+    async fn serve(
+        &self,
+        query: Query,
+        peer: &Id,
+        uid: &Id,
+        changes: Costs,
+        deadline: Instant,
+        hold: &Hold,
+    ) -> Result<Reply> {
+        let uid = match &query {
+            Query::MemeGet {
+                hash,
+                public: false,
+                ..
+            } => self.db.get_meme_visa(peer, hash).await?.unwrap_or(*uid),
+            _ => *uid,
+        };
+        let reply = self
+            .gene
+            .handle(query, &uid, changes, deadline, hold)
+            .await?;
+        self.served(peer, &changes, reply.get_changes()).await?;
+        Ok(reply)
+    }
+
+    /// Send to the peer, again with a new access token if it expired.
+    /// The error of a failed peer is passed through. It reports no charge,
+    /// so nothing is captured here, and Cost charges the time it took.
+    async fn call(
+        &self,
+        fed: &Id,
+        url: &str,
+        headers: &[(&'static str, String)],
+        deadline: Instant,
+    ) -> Result<Response> {
+        let mut response = self.send(fed, url, headers, deadline).await;
+        let expired = |r: &Response| {
+            matches!(
+                peer_error(r),
+                Some("AuthInvalidAccessToken" | "AuthAccessExpired")
+            )
+        };
+        if matches!(&response, Ok(r) if expired(r)) {
+            self.access.lock().unwrap().remove(fed);
+            response = self.send(fed, url, headers, deadline).await;
+        }
+        let response = response?;
+        match peer_error(&response) {
+            Some(error) => Err(Error::FedPeer(error.to_string())),
+            None => Ok(response),
+        }
+    }
+
+    async fn send(
+        &self,
+        fed: &Id,
+        url: &str,
+        headers: &[(&'static str, String)],
+        deadline: Instant,
+    ) -> Result<Response> {
+        let timeout = deadline
+            .checked_duration_since(Instant::now())
+            .ok_or(Error::CostTime)?;
+        let access = self.get_access(fed, url).await?;
+        let mut builder = self
+            .client
+            .post(url)
+            .timeout(timeout)
+            .header("access", &access);
+        for (k, v) in headers {
+            builder = builder.header(*k, v);
+        }
+        if let Some(keys) = &self.keys {
+            let sig = keys.sign(&self.fed_id, fed, Utc::now().timestamp(), &access, headers);
+            for (k, v) in sig.headers() {
+                builder = builder.header(k, v);
+            }
+        }
+        builder.send().await.map_err(|_| Error::Fed)
+    }
+
+    /// Verify a query forwarded by a peer, once.
+    async fn check_sig(&self, query: &Query, sig: &FedSig) -> Result<()> {
+        let headers = forward_headers(query, &query.get_costs())?;
+        let key = self.peer_key(&sig.from, &sig.kid).await?;
+        let access = query.get_access().to_string();
+        sign::verify(
+            sig,
+            &key,
+            &self.fed_id,
+            Utc::now().timestamp(),
+            &access,
+            &headers,
+        )?;
+        if !self.db.use_fed_nonce(&sig.nonce, 2 * sign::WINDOW).await? {
+            return Err(Error::FedReplay);
+        }
+        Ok(())
+    }
+
+    /// Public key kid of peer, fetched again on rotation,
+    /// at most once per KEYS_REFETCH so forged kids cost little.
+    async fn peer_key(&self, peer: &Id, kid: &str) -> Result<VerifyingKey> {
+        let url = self.members.get(peer).ok_or(Error::FedSignature)?;
+        let find = |keys: &[(String, VerifyingKey)]| {
+            keys.iter().find(|(k, _)| k == kid).map(|(_, key)| *key)
+        };
+        {
+            let mut peer_keys = self.peer_keys.lock().unwrap();
+            let keys = match peer_keys.get(peer) {
+                Some((fetched, keys)) => {
+                    if let Some(key) = find(keys) {
+                        return Ok(key);
+                    }
+                    if fetched.elapsed() < KEYS_REFETCH {
+                        return Err(Error::FedSignature);
+                    }
+                    keys.clone()
+                }
+                None => Vec::new(),
+            };
+            // Others wait out this fetch, even if it fails
+            peer_keys.insert(*peer, (Instant::now(), keys));
+        }
+        let keys = self.fetch_keys(url).await?;
+        let key = find(&keys);
+        self.peer_keys
+            .lock()
+            .unwrap()
+            .insert(*peer, (Instant::now(), keys));
+        key.ok_or(Error::FedSignature)
+    }
+
+    /// Read the keys announced at url, without an account there.
+    async fn fetch_keys(&self, url: &str) -> Result<PeerKeys> {
+        let response = self
+            .client
+            .get(format!("{}{}", url.trim_end_matches('/'), FED_KEYS_PATH))
+            .timeout(Duration::from_secs(5))
+            .send()
+            .await
+            .map_err(|_| Error::Fed)?;
+        let keys = response.text().await.map_err(|_| Error::Fed)?;
+        let keys: Vec<String> = serde_json::from_str(&keys)?;
+        Ok(sign::parse_public(&keys))
+    }
+
+    /// Exchange rate band of peer, at par if unlisted.
+    fn band(&self, peer: &Id) -> (f64, f64) {
+        self.rates.get(peer).copied().unwrap_or((1.0, 1.0))
+    }
+
+    /// Local credits per credit of peer, the middle of its band.
+    fn rate(&self, peer: &Id) -> f64 {
+        let (low, high) = self.band(peer);
+        (low + high) / 2.0
+    }
+
+    /// Record what a signed query of peer used here.
+    async fn served(&self, peer: &Id, plan: &Costs, changes: Option<Costs>) -> Result<()> {
+        let Some(changes) = changes else {
+            return Ok(());
+        };
+        let used = plan.sum().ok_or(Error::NumCheck)? - changes.sum().ok_or(Error::NumCheck)?;
+        if used > 0 {
+            let rate = self.rate(peer);
+            let peer_amount = (used as f64 / rate) as i64;
+            self.db
+                .log_fed(peer, "Serve", peer_amount, used, rate)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Credit the account of peer for what it served this instance,
+    /// if the rate it asks for is within its band.
+    async fn claimed(
+        &self,
+        peer: &Id,
+        uid: &Id,
+        amount: i64,
+        rate: f64,
+        changes: Costs,
     ) -> Result<Reply> {
-        match query.get_fed() {
-            Some(_) => Ok(Reply::Error { error: Error::Fed }),
-            None => self.gene.handle(query, uid, changes, deadline).await,
+        let (low, high) = self.band(peer);
+        if !(low..=high).contains(&rate) {
+            return Err(Error::FedRate);
+        }
+        let credit = self.db.accept_fed_claim(peer, uid, amount, rate).await?;
+        Ok(Reply::FedCreditClaim { changes, credit })
+    }
+
+    /// Settlement daemon. Reports the balance with each peer,
+    /// and claims what the peer owes.
+    pub async fn settle(&self) {
+        if self.settle_interval == 0 {
+            return;
+        }
+        loop {
+            sleep(Duration::from_secs(self.settle_interval)).await;
+            for peer in self.refresh.keys() {
+                if let Err(error) = self.settle_peer(peer).await {
+                    println!("Fed settlement error {}: {}", peer, error);
+                }
+            }
+        }
+    }
+
+    /// Log the balance with peer as key=value pairs, then claim what it owes.
+    async fn settle_peer(&self, peer: &Id) -> Result<()> {
+        let (owed_here, owed_there) = self.db.get_fed_balance(peer).await?;
+        if owed_here == 0 && owed_there == 0 {
+            return Ok(());
         }
+        let claimed = if owed_here > 0 && self.keys.is_some() {
+            self.claim(peer, owed_here).await
+        } else {
+            Ok(0)
+        };
+        println!(
+            "Fed settlement peer={} owed_here={} owed_there={} claimed={} rate={}",
+            peer,
+            owed_here,
+            owed_there,
+            claimed.as_ref().unwrap_or(&0),
+            self.rate(peer)
+        );
+        claimed.map(|_| ())
     }
+
+    /// Claim amount owed here from peer, return the credit it gave.
+    async fn claim(&self, peer: &Id, amount: i64) -> Result<i64> {
+        // The peer credits at its own rate, which is the inverse of ours.
+        let url = self.members.get(peer).ok_or(Error::Fed)?;
+        let rate = 1.0 / self.rate(peer);
+        let mut headers = plan_headers("FedCreditClaim", &PLAN);
+        headers.push(("amount", amount.to_string()));
+        headers.push(("rate", rate.to_string()));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let response = self.send(peer, url, &headers, deadline).await?;
+        if peer_error(&response).is_some() {
+            return Err(Error::FedClaim);
+        }
+        let credit = header(response.headers(), "credit")?
+            .parse()
+            .map_err(|_| Error::Fed)?;
+        self.db.log_fed(peer, "Claim", credit, amount, rate).await?;
+        Ok(credit)
+    }
+
+    /// Access token of this instance at the peer.
+    async fn get_access(&self, fed: &Id, url: &str) -> Result<String> {
+        if let Some(access) = self.access.lock().unwrap().get(fed) {
+            return Ok(access.clone());
+        }
+        let refresh = self.refresh.get(fed).ok_or(Error::Fed)?;
+        let response = self
+            .client
+            .post(url)
+            .header("type", "AuthSessionRefresh")
+            .header("refresh", refresh)
+            .send()
+            .await
+            .map_err(|_| Error::Fed)?;
+        if peer_error(&response).is_some() {
+            return Err(Error::Fed);
+        }
+        let access = header(response.headers(), "access")?;
+        self.access.lock().unwrap().insert(*fed, access.clone());
+        Ok(access)
+    }
+}
+
+/// Headers of a query to forward, with the plan left for the peer.
+fn forward_headers(query: &Query, changes: &Costs) -> Result<Vec<(&'static str, String)>> {
+    let mut headers = plan_headers(query.into(), changes);
+    match query {
+        Query::GeneMeta { gid, .. } => headers.push(("gid", gid.clone())),
+        Query::GeneCall { gid, arg, .. } => {
+            headers.push(("gid", gid.clone()));
+            headers.push(("arg", arg.clone()));
+        }
+        Query::MemeMeta { hash, .. } => headers.push(("hash", hex::encode(hash))),
+        Query::MemeGet { hash, public, .. } => {
+            headers.push(("hash", hex::encode(hash)));
+            headers.push(("public", public.to_string()));
+        }
+        Query::FedCreditClaim { amount, rate, .. } => {
+            headers.push(("amount", amount.to_string()));
+            headers.push(("rate", rate.to_string()));
+        }
+        _ => return Err(Error::Fed),
+    }
+    Ok(headers)
+}
+
+/// Type and plan headers.
+fn plan_headers(kind: &str, plan: &Costs) -> Vec<(&'static str, String)> {
+    vec![
+        ("type", kind.to_string()),
+        ("time", plan.time.to_string()),
+        ("space", plan.space.to_string()),
+        ("traffic", plan.traffic.to_string()),
+        ("tip", plan.tip.to_string()),
+    ]
+}
+
+/// Costs converted at rate, rounded down.
+fn exchange(costs: &Costs, rate: f64) -> Costs {
+    let x = |n: i64| (n as f64 * rate) as i64;
+    Costs {
+        time: x(costs.time),
+        space: x(costs.space),
+        traffic: x(costs.traffic),
+        tip: x(costs.tip),
+    }
+}
+
+/// Costs left, as reported by the peer.
+fn peer_changes(headers: &HeaderMap) -> Result<Costs> {
+    let cost = |key: &str| -> Result<i64> { header(headers, key)?.parse().map_err(|_| Error::Fed) };
+    Ok(Costs {
+        time: cost("time")?,
+        space: cost("space")?,
+        traffic: cost("traffic")?,
+        tip: cost("tip")?,
+    })
+}
+
+fn peer_error(response: &Response) -> Option<&str> {
+    response.headers().get("error")?.to_str().ok()
+}
+
+fn header(headers: &HeaderMap, key: &str) -> Result<String> {
+    headers
+        .get(key)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+        .ok_or(Error::Fed)
 }
@@ -0,0 +1,197 @@
+//! Signed inter-instance requests, Ed25519.
+//!
+//! The forwarded headers are signed with a timestamp, a nonce and a hash
+//! of the access header, so a peer can tell who sent a query, and on whose
+//! account, without sharing any database with it.
+//! Public keys are announced at FED_KEYS_PATH, which needs no account,
+//! and through info_1 as part of the config.
+
+use crate::ir::{Id, Query};
+use crate::{Error, Result};
+use ed25519_dalek::{Signature, Signer as _, SigningKey, Verifier, VerifyingKey};
+use hyper::{Request, body::Incoming};
+
+/// Seconds a signed request stays valid, in both directions of clock skew.
+pub const WINDOW: i64 = 60;
+
+/// Signature headers of a forwarded query.
+#[derive(Debug, Clone)]
+pub struct FedSig {
+    pub from: Id,
+    pub kid: String,
+    pub ts: i64,
+    pub nonce: Id,
+    pub sig: String,
+}
+
+impl FedSig {
+    /// Signature headers of request, None if unsigned or malformed.
+    pub fn opt(req: &Request<Incoming>) -> Option<FedSig> {
+        Some(FedSig {
+            from: Id::opt(req, "fed-from")?,
+            kid: Query::retrieve(req, "fed-kid").ok()?.to_string(),
+            ts: Query::retrieve(req, "fed-ts").ok()?.parse().ok()?,
+            nonce: Id::opt(req, "fed-nonce")?,
+            sig: Query::retrieve(req, "fed-sig").ok()?.to_string(),
+        })
+    }
+
+    /// Headers to send along the forwarded query.
+    pub fn headers(&self) -> [(&'static str, String); 5] {
+        [
+            ("fed-from", self.from.to_string()),
+            ("fed-kid", self.kid.clone()),
+            ("fed-ts", self.ts.to_string()),
+            ("fed-nonce", self.nonce.to_string()),
+            ("fed-sig", self.sig.clone()),
+        ]
+    }
+}
+
+/// Signs with the first key. All of them are announced,
+/// so a new key can be put in front before the old one is dropped.
+pub struct Keys {
+    keys: Vec<(String, SigningKey)>,
+}
+
+impl Keys {
+    /// Parse keys like "kid:seed,kid:seed" with 32 byte hex seeds,
+    /// None if there is none.
+    pub fn new(keys: &str) -> Option<Keys> {
+        let keys: Vec<_> = keys
+            .split(',')
+            .filter_map(|k| k.trim().split_once(':'))
+            .filter(|(kid, _)| !kid.is_empty())
+            .map(|(kid, seed)| {
+                let seed: [u8; 32] = hex::FromHex::from_hex(seed).expect("Invalid fed key seed");
+                (kid.to_string(), SigningKey::from_bytes(&seed))
+            })
+            .collect();
+        if keys.is_empty() {
+            None
+        } else {
+            Some(Keys { keys })
+        }
+    }
+
+    /// Public keys like "kid:hex", as announced by info_1.
+    pub fn public(&self) -> Vec<String> {
+        self.keys
+            .iter()
+            .map(|(kid, key)| format!("{}:{}", kid, hex::encode(key.verifying_key().as_bytes())))
+            .collect()
+    }
+
+    /// Sign a request from this instance to peer, sent with access.
+    pub fn sign(
+        &self,
+        from: &Id,
+        to: &Id,
+        now: i64,
+        access: &str,
+        headers: &[(&str, String)],
+    ) -> FedSig {
+        let (kid, key) = &self.keys[0];
+        let nonce = {
+            let mut rng = rand::rng();
+            Id::rand(&mut rng)
+        };
+        let signature = key.sign(payload(from, to, now, &nonce, access, headers).as_bytes());
+        FedSig {
+            from: *from,
+            kid: kid.clone(),
+            ts: now,
+            nonce,
+            sig: hex::encode(signature.to_bytes()),
+        }
+    }
+}
+
+/// Parse announced public keys like "kid:hex".
+pub fn parse_public(keys: &[String]) -> Vec<(String, VerifyingKey)> {
+    keys.iter()
+        .filter_map(|k| {
+            let (kid, key) = k.split_once(':')?;
+            let key: [u8; 32] = hex::FromHex::from_hex(key).ok()?;
+            Some((kid.to_string(), VerifyingKey::from_bytes(&key).ok()?))
+        })
+        .collect()
+}
+
+/// Check the signature of a request to this instance at unix time now.
+/// Replays are left to the caller, by remembering nonces for the window.
+pub fn verify(
+    sig: &FedSig,
+    key: &VerifyingKey,
+    to: &Id,
+    now: i64,
+    access: &str,
+    headers: &[(&str, String)],
+) -> Result<()> {
+    if (now - sig.ts).abs() > WINDOW {
+        return Err(Error::FedSignature);
+    }
+    let bytes: [u8; 64] = hex::FromHex::from_hex(&sig.sig).map_err(|_| Error::FedSignature)?;
+    key.verify(
+        payload(&sig.from, to, sig.ts, &sig.nonce, access, headers).as_bytes(),
+        &Signature::from_bytes(&bytes),
+    )
+    .map_err(|_| Error::FedSignature)
+}
+
+/// One line per field, then the forwarded headers in order.
+/// The access header is hashed, it may be a long signed token.
+fn payload(
+    from: &Id,
+    to: &Id,
+    ts: i64,
+    nonce: &Id,
+    access: &str,
+    headers: &[(&str, String)],
+) -> String {
+    let access = blake3::hash(access.as_bytes()).to_hex();
+    let mut s = format!("{}\n{}\n{}\n{}\n{}\n", from, to, ts, nonce, access);
+    for (k, v) in headers {
+        s.push_str(&format!("{}:{}\n", k, v));
+    }
+    s
+}
+
+#[test]
+fn test_sign_verify() {
+    let keys = Keys::new(&format!("k1:{}", hex::encode([1; 32]))).unwrap();
+    let public = parse_public(&keys.public());
+    let (from, to) = (Id([1; 16]), Id([2; 16]));
+    let headers = [("type", "GeneCall".to_string()), ("arg", "{}".to_string())];
+
+    let sig = keys.sign(&from, &to, 1000, "a", &headers);
+    assert_eq!(sig.kid, "k1");
+    assert!(verify(&sig, &public[0].1, &to, 1010, "a", &headers).is_ok());
+
+    // Tampered, misdirected, stale or on another account
+    let tampered = [("type", "GeneCall".to_string()), ("arg", "[]".to_string())];
+    assert!(verify(&sig, &public[0].1, &to, 1010, "a", &tampered).is_err());
+    assert!(verify(&sig, &public[0].1, &from, 1010, "a", &headers).is_err());
+    assert!(verify(&sig, &public[0].1, &to, 1000 + WINDOW + 1, "a", &headers).is_err());
+    assert!(verify(&sig, &public[0].1, &to, 1010, "b", &headers).is_err());
+}
+
+#[test]
+fn test_rotation() {
+    let old = Keys::new(&format!("k1:{}", hex::encode([1; 32]))).unwrap();
+    let new = Keys::new(&format!(
+        "k2:{},k1:{}",
+        hex::encode([2; 32]),
+        hex::encode([1; 32])
+    ))
+    .unwrap();
+    let public = parse_public(&new.public());
+    assert_eq!(public.len(), 2);
+
+    // Old signatures verify with the announced old key
+    let (from, to) = (Id([1; 16]), Id([2; 16]));
+    let sig = old.sign(&from, &to, 0, "", &[]);
+    let (_, key) = public.iter().find(|(kid, _)| *kid == sig.kid).unwrap();
+    assert!(verify(&sig, key, &to, 0, "", &[]).is_ok());
+    assert_eq!(new.sign(&from, &to, 0, "", &[]).kid, "k2");
+}
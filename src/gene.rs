@@ -1,37 +1,148 @@
 //! Genes are just functions.
+//! Each one implements the Gene trait, and is registered at startup.
 
+use crate::body::GeneStream;
 use crate::config::Config;
-use crate::database::Database;
+use crate::database::{Database, Hold};
 use crate::ir::{Costs, Id, Query, Reply};
 use crate::meme::Meme;
 use crate::{Error, Result, cost_macros};
+use bytes::Bytes;
 use serde::Serialize;
 use std::collections::HashMap;
-use tokio::time::{Duration, Instant};
+use std::future::Future;
+use std::pin::Pin;
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant, interval_at, sleep_until};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
 
 mod info;
 mod map;
 mod msg;
+mod wasm;
 
-pub struct Gene {
+pub type GeneFuture<'a> = Pin<Box<dyn Future<Output = Result<String>> + Send + 'a>>;
+
+/// Events of a subscription, as JSON.
+pub type Feed = Pin<Box<dyn Stream<Item = String> + Send>>;
+
+pub type FeedFuture<'a> = Pin<Box<dyn Future<Output = Result<Feed>> + Send + 'a>>;
+
+/// Keep-alive comments on idle feeds, so closed connections are noticed.
+const FEED_PING: Duration = Duration::from_secs(15);
+
+/// A function callable by GeneCall.
+pub trait Gene: Send + Sync {
+    fn meta(&self) -> GeneMeta;
+
+    /// Like "map_1", the name and the version.
+    fn id(&self) -> String {
+        let meta = self.meta();
+        format!("{}_{}", meta.name, meta.version)
+    }
+
+    /// Charged on top of time, space and traffic.
+    fn pricing(&self) -> Pricing {
+        Pricing::default()
+    }
+
+    /// Call with arg, spending from cx.changes.
+    fn call<'a>(&'a self, cx: Context<'a>) -> GeneFuture<'a>;
+
+    /// Subscribe with arg, for GeneSubscribe.
+    /// The feed is pushed until it ends, or the time or traffic runs out.
+    fn subscribe<'a>(&'a self, _cx: Context<'a>) -> FeedFuture<'a> {
+        Box::pin(async { Err(Error::GeneSubscribe) })
+    }
+}
+
+/// What a gene call may use.
+pub struct Context<'a> {
+    pub uid: &'a Id,
+    pub arg: &'a str,
+    pub changes: &'a mut Costs,
+    pub deadline: Instant,
+    pub db: &'static Database,
+    pub meme: &'static Meme,
+    pub genes: &'a Registry,
+}
+
+#[derive(Serialize, Clone, Copy, Default)]
+pub struct Pricing {
+    /// Flat credits per call, taken from the time left.
+    pub call: i64,
+}
+
+/// Genes by id.
+#[derive(Default)]
+pub struct Registry {
+    genes: HashMap<String, Box<dyn Gene>>,
+}
+
+impl Registry {
+    /// Genes shipped with this crate.
+    pub fn builtin(c: &Config) -> Registry {
+        let mut registry = Registry::default();
+        registry.register(info::V1::new(c));
+        registry.register(map::V1::new(c));
+        registry.register(msg::V1::new(c));
+        registry.register(wasm::V1);
+        registry
+    }
+
+    /// Add gene, panic if its id is taken.
+    pub fn register(&mut self, gene: impl Gene + 'static) {
+        let id = gene.id();
+        if self.genes.insert(id.clone(), Box::new(gene)).is_some() {
+            panic!("Gene registered twice: {}", id);
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&dyn Gene> {
+        self.genes.get(id).map(|gene| gene.as_ref())
+    }
+
+    /// Metadata of all genes, by id.
+    pub fn metas(&self) -> HashMap<String, GeneMeta> {
+        self.genes
+            .iter()
+            .map(|(id, gene)| (id.clone(), full_meta(gene.as_ref())))
+            .collect()
+    }
+}
+
+/// Meta with pricing.
+fn full_meta(gene: &dyn Gene) -> GeneMeta {
+    GeneMeta {
+        pricing: gene.pricing(),
+        ..gene.meta()
+    }
+}
+
+/// The gene layer, dispatching to the registry, then to WASM genes.
+pub struct Genes {
     meme: &'static Meme,
-    config_json: String,
     db: &'static Database,
-    metas: &'static HashMap<String, GeneMeta>,
+    registry: Registry,
+    runtime: wasm::Runtime,
     time_cost: i64,
-    space_cost_doc: i64,
     traffic_cost: i64,
 }
 
-impl Gene {
-    pub fn new(c: &'static Config, db: &'static Database, meme: &'static Meme) -> Gene {
-        Gene {
+impl Genes {
+    pub fn new(
+        c: &'static Config,
+        db: &'static Database,
+        meme: &'static Meme,
+        registry: Registry,
+    ) -> Genes {
+        Genes {
             meme,
-            config_json: serde_json::to_string_pretty(c).unwrap_or_default(),
             db,
-            metas: c.gene_metas,
+            registry,
+            runtime: wasm::Runtime::new(c),
             time_cost: c.time_cost,
-            space_cost_doc: c.space_cost_doc,
             traffic_cost: c.traffic_cost,
         }
     }
@@ -43,69 +154,119 @@ impl Gene {
         uid: &Id,
         mut changes: Costs,
         deadline: Instant,
+        hold: &Hold,
     ) -> Result<Reply> {
-        cost_macros!(self, uid, changes, deadline);
+        cost_macros!(self, hold, changes, deadline);
 
         let reply = self
-            .handle_ignore_error(query, uid, changes, deadline)
+            .handle_ignore_error(query, uid, changes, deadline, hold)
             .await;
         if reply.is_err() {
             time!();
-            refund!();
+            capture!();
         }
         reply
     }
 
-    /// Refund Ok(_)s, and leave Err(_)s to be refunded in the upstream.
+    /// Capture Ok(_)s, and leave Err(_)s to be captured in the upstream.
     async fn handle_ignore_error(
         &self,
         query: Query,
         uid: &Id,
         mut changes: Costs,
         deadline: Instant,
+        hold: &Hold,
     ) -> Result<Reply> {
-        cost_macros!(self, uid, changes, deadline);
+        cost_macros!(self, hold, changes, deadline);
 
         match query {
             Query::GeneMeta { head: _, gid } => {
-                let meta =
-                    serde_json::to_string(&self.metas.get(&gid).ok_or(Error::GeneInvalidId)?)
-                        .unwrap();
-                traffic_time_refund!(meta);
+                let wasm;
+                let gene: &dyn Gene = match self.registry.get(&gid) {
+                    Some(gene) => gene,
+                    None => {
+                        wasm = self.runtime.gene(self.db, &gid).await?;
+                        wasm.as_ref().ok_or(Error::GeneInvalidId)?
+                    }
+                };
+                let meta = serde_json::to_string(&full_meta(gene)).unwrap();
+                traffic_time_capture!(meta);
                 Ok(Reply::GeneMeta { changes, meta })
             }
 
             Query::GeneCall { head: _, gid, arg } => {
-                macro_rules! map_1_cx {
-                    () => {
-                        map::V1Context {
-                            uid,
-                            arg: &arg,
-                            changes: &mut changes,
-                            _deadline: deadline,
-                            space_cost: self.space_cost_doc,
-                            traffic_cost: self.traffic_cost,
-                            db: self.db,
-                        }
-                    };
+                let wasm;
+                let gene: &dyn Gene = match self.registry.get(&gid) {
+                    Some(gene) => gene,
+                    None => {
+                        wasm = self.runtime.gene(self.db, &gid).await?;
+                        wasm.as_ref().ok_or(Error::GeneInvalidId)?
+                    }
+                };
+                let result = gene
+                    .call(Context {
+                        uid,
+                        arg: &arg,
+                        changes: &mut changes,
+                        deadline,
+                        db: self.db,
+                        meme: self.meme,
+                        genes: &self.registry,
+                    })
+                    .await?;
+
+                // Keep what the gene metered, like WASM fuel, if it's more than the clock.
+                let metered = changes.time;
+                traffic!(result);
+                time!();
+                changes.time = changes.time.min(metered);
+                let fee = gene.pricing().call;
+                if fee > changes.time {
+                    return Err(Error::CostTime);
                 }
+                changes.time -= fee;
+                capture!();
+                Ok(Reply::GeneCall { changes, result })
+            }
 
-                let result = match gid.as_str() {
-                    "info_1" => info::v1(uid, &arg, self.config_json.clone()).await,
-                    "map_1" => map::v1(map_1_cx!(), false).await?,
-                    "msg_1" => msg::v1(map_1_cx!()).await?,
-                    _ => {
-                        return Err(Error::GeneInvalidId);
+            Query::GeneSubscribe { head: _, gid, arg } => {
+                let wasm;
+                let gene: &dyn Gene = match self.registry.get(&gid) {
+                    Some(gene) => gene,
+                    None => {
+                        wasm = self.runtime.gene(self.db, &gid).await?;
+                        wasm.as_ref().ok_or(Error::GeneInvalidId)?
                     }
                 };
+                let feed = gene
+                    .subscribe(Context {
+                        uid,
+                        arg: &arg,
+                        changes: &mut changes,
+                        deadline,
+                        db: self.db,
+                        meme: self.meme,
+                        genes: &self.registry,
+                    })
+                    .await?;
 
-                traffic_time_refund!(result);
-                Ok(Reply::GeneCall { changes, result })
+                time!();
+                let fee = gene.pricing().call;
+                if fee > changes.time {
+                    return Err(Error::CostTime);
+                }
+                changes.time -= fee;
+
+                // The hold stays open, the feed captures it when it ends.
+                let raw = self.push(feed, changes, deadline, *hold, fee);
+                changes.time = 0;
+                changes.traffic = 0;
+                Ok(Reply::GeneSubscribe { changes, raw })
             }
 
             Query::MemeMeta { head: _, hash } => {
                 let meta = self.meme.get_meta(uid, deadline, &hash).await?;
-                traffic_time_refund!(meta);
+                traffic_time_capture!(meta);
                 Ok(Reply::MemeMeta { changes, meta })
             }
 
@@ -114,7 +275,7 @@ impl Gene {
                     .meme
                     .put_meme(uid, &mut changes, deadline, days, raw)
                     .await;
-                refund!();
+                capture!();
                 reply
             }
 
@@ -127,54 +288,107 @@ impl Gene {
                     .meme
                     .get_meme(uid, &mut changes, deadline, hash, public)
                     .await;
-                refund!();
+                capture!();
                 reply
             }
 
+            Query::FedMemeVisa {
+                head: _,
+                hash,
+                peer,
+                ttl,
+            } => {
+                self.meme.put_visa(uid, &hash, &peer, ttl).await?;
+                time!();
+                capture!();
+                Ok(Reply::FedMemeVisa { changes })
+            }
+
             _ => Err(Error::Logical), // This arm should be unreachable.
         }
     }
 }
 
+impl Genes {
+    /// Push the feed as server-sent events until the deadline or out of traffic,
+    /// then capture the hold by the time and traffic used. After a crash,
+    /// the hold is left to ripperd.
+    fn push(
+        &self,
+        mut feed: Feed,
+        mut changes: Costs,
+        deadline: Instant,
+        hold: Hold,
+        fee: i64,
+    ) -> GeneStream {
+        let (db, time_cost, traffic_cost) = (self.db, self.time_cost, self.traffic_cost);
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let mut ping = interval_at(Instant::now() + FEED_PING, FEED_PING);
+            loop {
+                let event = tokio::select! {
+                    _ = tx.closed() => break,
+                    _ = sleep_until(deadline) => break,
+                    _ = ping.tick() => ":\n\n".to_string(),
+                    event = feed.next() => match event {
+                        Some(event) => format!("data: {}\n\n", event),
+                        None => break,
+                    },
+                };
+                let cost = event.len() as i64 * traffic_cost;
+                if cost > changes.traffic {
+                    break;
+                }
+                changes.traffic -= cost;
+                if tx.send(Bytes::from(event)).await.is_err() {
+                    break;
+                }
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            changes.time = (remaining.as_millis() as i64 * time_cost - fee).max(0);
+            let used = changes.sum().map_or(hold.amount, |left| hold.amount - left);
+            if let Err(error) = db.capture_hold(&hold.id, used, "CostCapture").await {
+                println!("Capture hold error for {}: {}", hold.id, error);
+            }
+        });
+        Box::pin(ReceiverStream::new(rx))
+    }
+}
+
 #[derive(Serialize)]
 pub struct GeneMeta {
     /// Naming convention: snake_case
-    name: String,
+    pub name: String,
 
     /// Increment on breaking changes.
-    version: usize,
+    pub version: usize,
 
     /// Man page.
-    description: String,
+    pub description: String,
+
+    /// Filled in by the registry.
+    pub pricing: Pricing,
 }
 
 impl GeneMeta {
-    pub fn new_map() -> HashMap<String, GeneMeta> {
-        HashMap::from([
-            (
-                "info_1".into(),
-                GeneMeta {
-                    name: "info".into(),
-                    version: 1,
-                    description: "Information about this server.".into(),
-                },
-            ),
-            (
-                "map_1".into(),
-                GeneMeta {
-                    name: "map".into(),
-                    version: 1,
-                    description: "Mapping abstraction backed by CockroachDB.".into(),
-                },
-            ),
-            (
-                "msg_1".into(),
-                GeneMeta {
-                    name: "msg".into(),
-                    version: 1,
-                    description: "Messaging another user.".into(),
-                },
-            ),
-        ])
+    pub fn new(name: &str, version: usize, description: &str) -> GeneMeta {
+        GeneMeta {
+            name: name.into(),
+            version,
+            description: description.into(),
+            pricing: Pricing::default(),
+        }
     }
 }
+
+#[test]
+fn test_registry() {
+    let registry = Registry::builtin(&Config::new());
+    let metas = registry.metas();
+    assert_eq!(metas.len(), 4);
+    assert_eq!(metas["map_1"].name, "map");
+    assert!(registry.get("info_1").is_some());
+    assert!(registry.get("info_2").is_none());
+    assert!(registry.get("wasm_1").is_some());
+}
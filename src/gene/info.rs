@@ -1,10 +1,34 @@
 //! Info gene returns information about this VOxOV instance,
 //! including the maintainer, credit rate, and gene list.
-//! The gene list is generated by macros,
+//! The gene list is generated from the registry,
 //! and others are in the config struct.
 
-use crate::ir::Id;
+use super::{Context, Gene, GeneFuture, GeneMeta};
+use crate::config::Config;
+use serde_json::Value;
 
-pub async fn v1(_uid: &Id, _arg: &str, c: String) -> String {
-    c
+pub struct V1 {
+    config: Value,
+}
+
+impl V1 {
+    pub fn new(c: &Config) -> V1 {
+        V1 {
+            config: serde_json::to_value(c).unwrap_or_default(),
+        }
+    }
+}
+
+impl Gene for V1 {
+    fn meta(&self) -> GeneMeta {
+        GeneMeta::new("info", 1, "Information about this server.")
+    }
+
+    fn call<'a>(&'a self, cx: Context<'a>) -> GeneFuture<'a> {
+        Box::pin(async move {
+            let mut info = self.config.clone();
+            info["gene_metas"] = serde_json::to_value(cx.genes.metas())?;
+            Ok(serde_json::to_string_pretty(&info).unwrap_or_default())
+        })
+    }
 }
@@ -1,2 +1,2 @@
 mod map_1;
-pub use map_1::{V1Context, v1};
+pub use map_1::{V1, V1Context, v1};
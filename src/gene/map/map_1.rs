@@ -11,6 +11,8 @@
 //! - tip: price for get.
 //! - size: the size of doc.
 //!
+//! - version: incremented on each change.
+//!
 //! id and uid are immutable.
 //! pub is managed by the censor gene.
 //! eol is set in request, and it can be extended.
@@ -20,24 +22,82 @@
 //! - ns: namespace.
 //! - i0-i7: indexed keys (JSONB).
 //! - geo_lon, geo_lat: geospatial information.
+//!
+//! # Get
+//!
+//! Filters on managed and indexed fields are bound as parameters.
+//! Docs are visible if public or owned, tips are paid for others' docs.
+//! Pages are sorted by _sort then _id, and _next is the _cursor of the next page.
+//! _geo filters by a radius, sortable by _dist, or by a bounding box.
+//!
+//! # Schema
+//!
+//! A doc in the _schema namespace sets the JSON Schema of the ns in its _0,
+//! checked on Put and Patch. Only admins of a claimed ns set it, and any of
+//! them may replace it with a Put without _id, or Drop it.
+//! Docs are checked as returned by Get, without managed fields,
+//! and with indexed keys that aren't null.
+//!
+//! # Namespaces
+//!
+//! Anyone may write to an unclaimed namespace. A Claim makes the uid its admin,
+//! who may Grant read, write or admin to uids, and read or write to the public.
+//! Readers see all docs in it, writers Put and Patch their own,
+//! and admins Drop any and set its schema.
+//!
+//! # Aggregate
+//!
+//! Counts docs by the filters of Get, with stats of an indexed key,
+//! optionally grouped by another. Charged by docs, not bytes.
+//! Others' docs with a tip are left out, Get them to pay it.
+//!
+//! # Batch
+//!
+//! Each call is a transaction, and a Batch runs many requests in one.
+//! If any fails, nothing is changed or charged.
+//!
+//! # Subscribe
+//!
+//! GeneSubscribe takes the filters of Get with an _ns, and pushes the docs
+//! that are Put or Patched with _op, or only _id and _op if Dropped.
+//! Changes are matched in memory, and only others' private docs ask the
+//! database whether they are visible. A lost change or failed check is
+//! pushed as an _error, so subscribers know to Get again.
+//!
+//! Changes are fed from this process, not from other instances: behind a
+//! load balancer, subscribers miss changes written through other instances.
 
 #![allow(clippy::just_underscores_and_digits)]
 
-use crate::database::Database;
+use crate::config::Config;
+use crate::database::{Database, MapChange};
+use crate::gene::{Context, Feed, FeedFuture, Gene, GeneFuture, GeneMeta};
 use crate::ir::{Costs, Id};
 use crate::{Error, Result};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
 use chrono::serde::{ts_seconds, ts_seconds_option};
 use chrono::{DateTime, Duration, Utc};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
-use sqlx::Row;
+use sqlx::postgres::PgRow;
+use sqlx::{Postgres, QueryBuilder, Row, Transaction};
+use std::cmp::Ordering;
 use std::collections::BTreeMap as Map;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
 use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
 use uuid::Uuid;
 
+/// Docs per Get by default.
+const GET_N: u64 = 100;
+
+/// Most docs per Get.
+const GET_N_MAX: u64 = 1000;
+
 #[derive(Deserialize, Debug)]
 struct Put {
-    _type: String,
     _id: Option<String>, // UUID string
     // Uid is managed by auth.
 
@@ -48,13 +108,21 @@ struct Put {
     // Size is counted by backend.
     _ns: Option<String>,
 
+    #[serde(default)]
     _0: Value,
+    #[serde(default)]
     _1: Value,
+    #[serde(default)]
     _2: Value,
+    #[serde(default)]
     _3: Value,
+    #[serde(default)]
     _4: Value,
+    #[serde(default)]
     _5: Value,
+    #[serde(default)]
     _6: Value,
+    #[serde(default)]
     _7: Value,
 
     _geo: Option<Vec<f64>>,
@@ -63,16 +131,17 @@ struct Put {
     v: Map<String, Value>,
 }
 
+/// Filters are ANDed. A field alone is an equality,
+/// and with the one ending in _ it's the range [_x, _x_).
 #[derive(Deserialize, Debug)]
 struct Get {
-    _type: String,
     _id: Option<String>,
     _uid: Option<String>,
     _pub: Option<bool>,
 
-    #[serde(with = "ts_seconds_option")]
+    #[serde(default, with = "ts_seconds_option")]
     _eol: Option<DateTime<Utc>>,
-    #[serde(with = "ts_seconds_option")]
+    #[serde(default, with = "ts_seconds_option")]
     _eol_: Option<DateTime<Utc>>,
 
     _tip: Option<i64>,
@@ -105,24 +174,338 @@ struct Get {
     /// Max doc count.
     _n: Option<u64>,
 
+    /// Radius: [lon, lat, meters], with _dist in docs.
+    /// Bounding box: [lon_min, lat_min, lon_max, lat_max],
+    /// across the antimeridian if lon_min > lon_max.
     _geo: Option<Vec<f64>>,
 
-    /// Selected fields.
+    /// Selected fields, _id is always returned.
     _v: Option<Vec<String>>,
+
+    /// Sort by a managed field or indexed key, then by _id.
+    _sort: Option<String>,
+    _desc: Option<bool>,
+
+    /// Continue after the last doc of a page, from _next.
+    _cursor: Option<String>,
+}
+
+impl Get {
+    /// Max doc count, capped.
+    fn n(&self) -> u64 {
+        self._n.unwrap_or(GET_N).min(GET_N_MAX)
+    }
+
+    fn sort(&self) -> &str {
+        self._sort.as_deref().unwrap_or("_id")
+    }
+
+    fn desc(&self) -> bool {
+        self._desc.unwrap_or_default()
+    }
+
+    fn geo(&self) -> Result<Option<Geo>> {
+        self._geo.as_deref().map(Geo::parse).transpose()
+    }
+}
+
+/// Mean earth radius in meters.
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+/// Longitude and latitude in degrees.
+fn check_geo(lon: f64, lat: f64) -> Result<()> {
+    if (-180.0..=180.0).contains(&lon) && (-90.0..=90.0).contains(&lat) {
+        Ok(())
+    } else {
+        Err(Error::GeoRange)
+    }
+}
+
+/// Geo filter of Get.
+#[derive(Clone, Copy)]
+enum Geo {
+    Radius {
+        lon: f64,
+        lat: f64,
+        meters: f64,
+    },
+    Box {
+        lon_min: f64,
+        lat_min: f64,
+        lon_max: f64,
+        lat_max: f64,
+    },
+}
+
+impl Geo {
+    fn parse(geo: &[f64]) -> Result<Geo> {
+        match *geo {
+            [lon, lat, meters] => {
+                check_geo(lon, lat)?;
+                if !(meters.is_finite() && meters > 0.0) {
+                    return Err(Error::GeoRange);
+                }
+                Ok(Geo::Radius { lon, lat, meters })
+            }
+            [lon_min, lat_min, lon_max, lat_max] => {
+                check_geo(lon_min, lat_min)?;
+                check_geo(lon_max, lat_max)?;
+                if lat_min > lat_max {
+                    return Err(Error::GeoRange);
+                }
+                Ok(Geo::Box {
+                    lon_min,
+                    lat_min,
+                    lon_max,
+                    lat_max,
+                })
+            }
+            _ => Err(Error::GeoDim),
+        }
+    }
+
+    /// Center of a radius.
+    fn center(&self) -> Option<(f64, f64)> {
+        match *self {
+            Geo::Radius { lon, lat, .. } => Some((lon, lat)),
+            Geo::Box { .. } => None,
+        }
+    }
+
+    /// Whether a point is in, as push_filters.
+    fn contains(&self, (geo_lon, geo_lat): (f64, f64)) -> bool {
+        match *self {
+            Geo::Radius { lon, lat, meters } => dist(lon, lat, geo_lon, geo_lat) <= meters,
+            Geo::Box {
+                lon_min,
+                lat_min,
+                lon_max,
+                lat_max,
+            } => {
+                (lat_min..=lat_max).contains(&geo_lat)
+                    && if lon_min <= lon_max {
+                        (lon_min..=lon_max).contains(&geo_lon)
+                    } else {
+                        geo_lon >= lon_min || geo_lon <= lon_max
+                    }
+            }
+        }
+    }
+}
+
+/// Meters from (lon, lat) to (geo_lon, geo_lat), as push_dist.
+fn dist(lon: f64, lat: f64, geo_lon: f64, geo_lat: f64) -> f64 {
+    let a = ((geo_lat - lat).to_radians() / 2.0).sin().powi(2)
+        + lat.to_radians().cos()
+            * geo_lat.to_radians().cos()
+            * ((geo_lon - lon).to_radians() / 2.0).sin().powi(2);
+    EARTH_RADIUS * 2.0 * a.sqrt().min(1.0).asin()
+}
+
+/// Haversine distance in meters from lon, lat to docs.
+fn push_dist(query: &mut QueryBuilder<'static, Postgres>, lon: f64, lat: f64) {
+    query
+        .push(format_args!(
+            "{} * 2 * asin(least(1, sqrt(pow(sin(radians(geo_lat - ",
+            EARTH_RADIUS
+        ))
+        .push_bind(lat)
+        .push(") / 2), 2) + cos(radians(")
+        .push_bind(lat)
+        .push(")) * cos(radians(geo_lat)) * pow(sin(radians(geo_lon - ")
+        .push_bind(lon)
+        .push(") / 2), 2))))");
+}
+
+/// Docs in a box, across the antimeridian if lon_min > lon_max.
+fn push_box(
+    query: &mut QueryBuilder<'static, Postgres>,
+    lon_min: f64,
+    lat_min: f64,
+    lon_max: f64,
+    lat_max: f64,
+) {
+    query
+        .push(" AND geo_lat BETWEEN ")
+        .push_bind(lat_min)
+        .push(" AND ")
+        .push_bind(lat_max);
+    if lon_min <= lon_max {
+        query
+            .push(" AND geo_lon BETWEEN ")
+            .push_bind(lon_min)
+            .push(" AND ")
+            .push_bind(lon_max);
+    } else {
+        query
+            .push(" AND (geo_lon >= ")
+            .push_bind(lon_min)
+            .push(" OR geo_lon <= ")
+            .push_bind(lon_max)
+            .push(")");
+    }
+}
+
+/// Position in a sorted Get, opaque to clients.
+#[derive(Serialize, Deserialize, Debug)]
+struct Cursor {
+    sort: String,
+    desc: bool,
+    value: Value,
+    id: String,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap())
+    }
+
+    fn decode(cursor: &str) -> Result<Cursor> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(cursor)
+            .map_err(|_| Error::GeneMapCursor)?;
+        serde_json::from_slice(&bytes).map_err(|_| Error::GeneMapCursor)
+    }
+}
+
+/// Sortable fields and their expressions.
+fn sort_expr(field: &str) -> Result<&'static str> {
+    Ok(match field {
+        "_id" => "id",
+        "_eol" => "eol",
+        "_tip" => "tip",
+        "_size" => "size",
+        "_ns" => "ns",
+        "_0" => "COALESCE(i0, 'null'::JSONB)",
+        "_1" => "COALESCE(i1, 'null'::JSONB)",
+        "_2" => "COALESCE(i2, 'null'::JSONB)",
+        "_3" => "COALESCE(i3, 'null'::JSONB)",
+        "_4" => "COALESCE(i4, 'null'::JSONB)",
+        "_5" => "COALESCE(i5, 'null'::JSONB)",
+        "_6" => "COALESCE(i6, 'null'::JSONB)",
+        "_7" => "COALESCE(i7, 'null'::JSONB)",
+        _ => return Err(Error::GeneMapSort),
+    })
 }
 
 #[derive(Deserialize, Debug)]
 struct Drop {
-    _type: String,
     _id: Option<String>,
 }
 
+/// Change a doc in place, in order: merge, ops, then incr.
+/// Keys are body fields and _0 to _7, others are kept.
+#[derive(Deserialize, Debug)]
+struct Patch {
+    _id: String,
+
+    /// Fail unless the doc is at this version.
+    _version: Option<i64>,
+
+    /// JSON Merge Patch, RFC 7386.
+    _merge: Option<Value>,
+
+    /// JSON Patch, RFC 6902.
+    _ops: Option<json_patch::Patch>,
+
+    /// Add numbers to fields, missing ones count as 0.
+    _incr: Option<Map<String, Value>>,
+}
+
+/// Count docs by the filters of Get, optionally in groups,
+/// with the sum, min, max and avg of a numeric key.
+#[derive(Deserialize, Debug)]
+struct Aggregate {
+    /// Indexed key to sum, like "_0". Non-numbers are skipped.
+    _of: Option<String>,
+
+    /// Indexed key to group by.
+    _by: Option<String>,
+
+    #[serde(flatten)]
+    filter: Get,
+}
+
+/// Claim a namespace without docs or a schema of others, to be its admin.
+#[derive(Deserialize, Debug)]
+struct Claim {
+    _ns: String,
+}
+
+/// Set the level of a uid, or of the public if no _uid, in a claimed namespace.
+/// No level to revoke. Admins only, and the public can't be an admin.
+#[derive(Deserialize, Debug)]
+struct Grant {
+    _ns: String,
+    _uid: Option<String>,
+    _level: Option<Level>,
+}
+
+/// Access to a namespace, each level includes the ones before.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+enum Level {
+    Nil,
+    Read,
+    Write,
+    Admin,
+}
+
+impl Level {
+    fn parse(level: &str) -> Level {
+        match level {
+            "read" => Level::Read,
+            "write" => Level::Write,
+            "admin" => Level::Admin,
+            _ => Level::Nil,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Level::Nil => "",
+            Level::Read => "read",
+            Level::Write => "write",
+            Level::Admin => "admin",
+        }
+    }
+}
+
+/// Retries of a transaction that CockroachDB failed to serialize.
+const RETRY_MAX: u32 = 3;
+
+/// Wait before a retry, times the retry number.
+const RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// Requests in one transaction, all or nothing.
+#[derive(Deserialize, Debug)]
+struct Batch {
+    _ops: Vec<Request>,
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(tag = "_type")]
 enum Request {
     Put(Box<Put>),
     Get(Box<Get>),
+    Patch(Box<Patch>),
     Drop(Drop),
+    Aggregate(Box<Aggregate>),
+    Claim(Claim),
+    Grant(Grant),
+    Batch(Batch),
+}
+
+/// Most requests per Batch.
+const BATCH_MAX: usize = 100;
+
+/// The transaction of a call, with tips to pay, space to refund
+/// and changes to feed once it's committed.
+struct Tx {
+    conn: Transaction<'static, Postgres>,
+    tips: Vec<(Id, i64)>,
+    refund: i64,
+    feed: Vec<MapChange>,
 }
 
 pub struct V1Context<'a> {
@@ -135,17 +518,163 @@ pub struct V1Context<'a> {
     pub db: &'static Database,
 }
 
-pub async fn v1(cx: V1Context<'_>, internal: bool) -> Result<String> {
-    let request: Request = serde_json::from_str(cx.arg)?;
+impl<'a> V1Context<'a> {
+    /// Context of a gene call, with the prices of map.
+    pub fn new(cx: Context<'a>, space_cost: i64, traffic_cost: i64) -> V1Context<'a> {
+        V1Context {
+            uid: cx.uid,
+            arg: cx.arg,
+            changes: cx.changes,
+            _deadline: cx.deadline,
+            space_cost,
+            traffic_cost,
+            db: cx.db,
+        }
+    }
+}
+
+pub struct V1 {
+    space_cost: i64,
+    traffic_cost: i64,
+}
+
+impl V1 {
+    pub fn new(c: &Config) -> V1 {
+        V1 {
+            space_cost: c.space_cost_doc,
+            traffic_cost: c.traffic_cost,
+        }
+    }
+}
+
+impl Gene for V1 {
+    fn meta(&self) -> GeneMeta {
+        GeneMeta::new("map", 1, "Mapping abstraction backed by CockroachDB.")
+    }
+
+    fn call<'a>(&'a self, cx: Context<'a>) -> GeneFuture<'a> {
+        Box::pin(v1(
+            V1Context::new(cx, self.space_cost, self.traffic_cost),
+            false,
+        ))
+    }
 
+    fn subscribe<'a>(&'a self, cx: Context<'a>) -> FeedFuture<'a> {
+        Box::pin(subscribe(V1Context::new(
+            cx,
+            self.space_cost,
+            self.traffic_cost,
+        )))
+    }
+}
+
+pub async fn v1(mut cx: V1Context<'_>, internal: bool) -> Result<String> {
+    // Changes are kept only if the transaction is committed.
+    let changes = *cx.changes;
+    let mut retries = 0;
+    let (result, tips, refund, feed) = loop {
+        let request: Request = serde_json::from_str(cx.arg)?;
+        let result = async {
+            let mut tx = Tx {
+                conn: cx.db.crdb.begin().await?,
+                tips: vec![],
+                refund: 0,
+                feed: vec![],
+            };
+            let result = match request {
+                Request::Batch(batch) => handle_batch(&mut cx, &mut tx, batch, internal).await?,
+                request => handle(&mut cx, &mut tx, request, internal).await?,
+            };
+            tx.conn.commit().await?;
+            Ok::<_, Error>((result, tx.tips, tx.refund, tx.feed))
+        }
+        .await;
+        match result {
+            Ok(result) => break result,
+            Err(error) => {
+                *cx.changes = changes;
+                if retries == RETRY_MAX || !serialization_failure(&error) {
+                    return Err(error);
+                }
+                retries += 1;
+                tokio::time::sleep(RETRY_DELAY * retries).await;
+            }
+        }
+    };
+
+    // No subscribers is fine.
+    for change in feed {
+        let _ = cx.db.map_feed.send(change);
+    }
+
+    // Refunds are paid apart from the hold of the call, which they may exceed.
+    if refund > 0 {
+        cx.db
+            .incr_credit(cx.uid, None, refund, "GeneMap1Refund")
+            .await?;
+    }
+
+    for (doc_uid, tip) in tips {
+        cx.db
+            .incr_credit(&doc_uid, Some(cx.uid), tip, "GeneMap1Tip")
+            .await?;
+    }
+    Ok(result)
+}
+
+/// CockroachDB asks to retry the transaction.
+fn serialization_failure(error: &Error) -> bool {
+    match error {
+        Error::Sqlx(error) => error
+            .as_database_error()
+            .and_then(|error| error.code())
+            .is_some_and(|code| code == "40001"),
+        _ => false,
+    }
+}
+
+async fn handle(
+    cx: &mut V1Context<'_>,
+    tx: &mut Tx,
+    request: Request,
+    internal: bool,
+) -> Result<String> {
     match request {
-        Request::Put(request) => handle_put(cx, *request, internal).await,
-        Request::Get(request) => handle_get(cx, *request, internal).await,
-        Request::Drop(request) => handle_drop(cx, request).await,
+        Request::Put(request) => handle_put(cx, tx, *request, internal).await,
+        Request::Get(request) => handle_get(cx, tx, *request, internal).await,
+        Request::Patch(request) => handle_patch(cx, tx, *request).await,
+        Request::Drop(request) => handle_drop(cx, tx, request).await,
+        Request::Claim(request) => handle_claim(cx, tx, request).await,
+        Request::Grant(request) => handle_grant(cx, tx, request).await,
+        Request::Aggregate(request) => handle_aggregate(cx, tx, *request, internal).await,
+        Request::Batch(_) => Err(Error::GeneMapBatch),
+    }
+}
+
+/// Results in the order of requests.
+async fn handle_batch(
+    cx: &mut V1Context<'_>,
+    tx: &mut Tx,
+    batch: Batch,
+    internal: bool,
+) -> Result<String> {
+    if batch._ops.len() > BATCH_MAX {
+        return Err(Error::GeneMapBatch);
+    }
+    let mut results = vec![];
+    for request in batch._ops {
+        let result = handle(cx, tx, request, internal).await?;
+        results.push(serde_json::from_str::<Value>(&result)?);
     }
+    Ok(Value::Array(results).to_string())
 }
 
-async fn handle_put(cx: V1Context<'_>, request: Put, internal: bool) -> Result<String> {
+async fn handle_put(
+    cx: &mut V1Context<'_>,
+    tx: &mut Tx,
+    request: Put,
+    internal: bool,
+) -> Result<String> {
     let ttl = request._eol - Utc::now();
     if ttl < Duration::days(1) {
         return Err(Error::CostTime);
@@ -156,16 +685,20 @@ async fn handle_put(cx: V1Context<'_>, request: Put, internal: bool) -> Result<S
         return Err(Error::CostTip);
     }
 
-    let ns = request._ns.unwrap_or_default();
-    if !internal && !ns.is_empty() && ns.starts_with('_') {
+    let ns = request._ns.clone().unwrap_or_default();
+    if !internal && ns.starts_with('_') && ns != SCHEMA_NS {
+        return Err(Error::Namespace);
+    }
+    if !internal && ns_level(tx, cx.uid, &ns).await? < Level::Write {
         return Err(Error::Namespace);
     }
 
     let (geo_lon, geo_lat) = if let Some(geo) = &request._geo {
-        if geo.len() != 2 {
+        let [lon, lat] = geo[..] else {
             return Err(Error::GeoDim);
-        }
-        (Some(geo[0]), Some(geo[1]))
+        };
+        check_geo(lon, lat)?;
+        (Some(lon), Some(lat))
     } else {
         (None, None)
     };
@@ -176,6 +709,28 @@ async fn handle_put(cx: V1Context<'_>, request: Put, internal: bool) -> Result<S
         }
     }
 
+    if ns == SCHEMA_NS {
+        put_schema(tx, cx.uid, &request).await?;
+    } else {
+        let mut view: serde_json::Map<String, Value> = request.v.clone().into_iter().collect();
+        let keys = [
+            &request._0,
+            &request._1,
+            &request._2,
+            &request._3,
+            &request._4,
+            &request._5,
+            &request._6,
+            &request._7,
+        ];
+        for (key, value) in KEYS.iter().zip(keys) {
+            if !value.is_null() {
+                view.insert(key.to_string(), value.clone());
+            }
+        }
+        check_schema(cx, tx, &ns, &Value::Object(view)).await?;
+    }
+
     // Convert indexed fields to JSONB
     let i0 = serde_json::to_value(&request._0)?;
     let i1 = serde_json::to_value(&request._1)?;
@@ -207,30 +762,24 @@ async fn handle_put(cx: V1Context<'_>, request: Put, internal: bool) -> Result<S
         let id = Uuid::parse_str(&id_str).map_err(|_| Error::GeneMapNotFound)?;
 
         // First get old document for refund calculation
-        let old_row = sqlx::query("SELECT eol, size FROM map_docs WHERE id = $1 AND uid = $2")
-            .bind(id)
-            .bind(&cx.uid.0[..])
-            .fetch_optional(&cx.db.crdb)
-            .await?;
+        let old_row =
+            sqlx::query("SELECT eol, size, paid FROM map_docs WHERE id = $1 AND uid = $2")
+                .bind(id)
+                .bind(&cx.uid.0[..])
+                .fetch_optional(&mut *tx.conn)
+                .await?;
 
         if let Some(old) = old_row {
-            let old_eol: DateTime<Utc> = old.get("eol");
-            let old_size: i64 = old.get("size");
-            let now = Utc::now();
-            if now < old_eol {
-                let ttl = old_eol - now;
-                let space_refund = (old_size / 1024) * ttl.num_days() * cx.space_cost;
-                cx.changes.space += space_refund;
-            }
+            tx.refund += space_refund(cx, &old);
         }
 
         // Replace document
-        sqlx::query(
+        let row = sqlx::query(
             "UPDATE map_docs SET 
              pub = false, eol = $1, tip = $2, ns = $3, size = $4,
              i0 = $5, i1 = $6, i2 = $7, i3 = $8, i4 = $9, i5 = $10, i6 = $11, i7 = $12,
-             geo_lon = $13, geo_lat = $14, body = $15
-             WHERE id = $16 AND uid = $17",
+             geo_lon = $13, geo_lat = $14, body = $15, version = version + 1, paid = $18
+             WHERE id = $16 AND uid = $17 RETURNING *",
         )
         .bind(request._eol)
         .bind(tip)
@@ -249,13 +798,18 @@ async fn handle_put(cx: V1Context<'_>, request: Put, internal: bool) -> Result<S
         .bind(&body)
         .bind(id)
         .bind(&cx.uid.0[..])
-        .execute(&cx.db.crdb)
+        .bind(space)
+        .fetch_optional(&mut *tx.conn)
         .await?;
+        if let Some(row) = row {
+            tx.feed.push(feed_change("Put", &row));
+        }
     } else {
         // Insert new document
-        sqlx::query(
-            "INSERT INTO map_docs (uid, pub, eol, tip, ns, size, i0, i1, i2, i3, i4, i5, i6, i7, geo_lon, geo_lat, body)
-             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17)",
+        let row = sqlx::query(
+            "INSERT INTO map_docs (uid, pub, eol, tip, ns, size, i0, i1, i2, i3, i4, i5, i6, i7, geo_lon, geo_lat, body, paid)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18)
+             RETURNING *",
         )
         .bind(&cx.uid.0[..])
         .bind(false)
@@ -274,197 +828,1046 @@ async fn handle_put(cx: V1Context<'_>, request: Put, internal: bool) -> Result<S
         .bind(geo_lon)
         .bind(geo_lat)
         .bind(&body)
-        .execute(&cx.db.crdb)
+        .bind(space)
+        .fetch_one(&mut *tx.conn)
         .await?;
+        tx.feed.push(feed_change("Put", &row));
     }
 
     Ok("{}".into())
 }
 
-async fn handle_get(cx: V1Context<'_>, request: Get, internal: bool) -> Result<String> {
-    // Build dynamic query
-    let mut query = String::from(
-        "SELECT id, uid, pub, eol, tip, ns, size, i0, i1, i2, i3, i4, i5, i6, i7, geo_lon, geo_lat, body FROM map_docs WHERE 1=1",
-    );
-    let mut param_idx = 1;
+/// WHERE conditions of Get, after "WHERE true".
+/// Others' private docs are only visible to internal genes, or by the ACL of
+/// their namespace. This holds for every session, not only anonymous ones:
+/// pub used to be checked only when _uid named someone else, so a Get
+/// without _uid returned anyone's private docs.
+fn push_filters(
+    query: &mut QueryBuilder<'static, Postgres>,
+    uid: &Id,
+    request: &Get,
+    internal: bool,
+    geo: Option<&Geo>,
+) -> Result<()> {
+    if !internal {
+        query
+            .push(" AND (pub = true OR uid = ")
+            .push_bind(uid.0.to_vec())
+            .push(
+                " OR EXISTS (SELECT 1 FROM map_ns n LEFT JOIN map_acl a ON a.ns = n.ns AND a.uid = ",
+            )
+            .push_bind(uid.0.to_vec())
+            .push(" WHERE n.ns = map_docs.ns AND (n.uid = ")
+            .push_bind(uid.0.to_vec())
+            .push(" OR n.public != '' OR a.level IS NOT NULL)))");
+    }
 
-    if let Some(id_str) = &request._id {
-        if Uuid::parse_str(id_str).is_ok() {
-            query.push_str(&format!(" AND id = ${}", param_idx));
-            param_idx += 1;
-        }
+    if let Some(id) = &request._id {
+        let id = Uuid::parse_str(id).map_err(|_| Error::GeneMapNotFound)?;
+        query.push(" AND id = ").push_bind(id);
+    }
+    if let Some(doc_uid) = &request._uid {
+        let doc_uid = Id::try_from(doc_uid.as_str())?;
+        query.push(" AND uid = ").push_bind(doc_uid.0.to_vec());
+    }
+    if let Some(is_pub) = request._pub {
+        query.push(" AND pub = ").push_bind(is_pub);
     }
 
-    if !internal {
-        if let Some(doc_uid) = &request._uid {
-            if cx.uid.to_string() == *doc_uid {
-                if request._pub.is_some() {
-                    query.push_str(&format!(" AND pub = ${}", param_idx));
-                    param_idx += 1;
+    macro_rules! range {
+        ($col:literal, $begin:expr, $end:expr) => {
+            match (&$begin, &$end) {
+                (Some(begin), Some(end)) => {
+                    query
+                        .push(concat!(" AND ", $col, " >= "))
+                        .push_bind(begin.clone())
+                        .push(concat!(" AND ", $col, " < "))
+                        .push_bind(end.clone());
+                }
+                (Some(begin), None) => {
+                    query
+                        .push(concat!(" AND ", $col, " = "))
+                        .push_bind(begin.clone());
                 }
+                (None, Some(end)) => {
+                    query
+                        .push(concat!(" AND ", $col, " < "))
+                        .push_bind(end.clone());
+                }
+                (None, None) => {}
+            }
+        };
+    }
+
+    range!("eol", request._eol, request._eol_);
+    range!("tip", request._tip, request._tip_);
+    range!("size", request._size, request._size_);
+    range!("ns", request._ns, request._ns_);
+    range!("i0", request._0, request._0_);
+    range!("i1", request._1, request._1_);
+    range!("i2", request._2, request._2_);
+    range!("i3", request._3, request._3_);
+    range!("i4", request._4, request._4_);
+    range!("i5", request._5, request._5_);
+    range!("i6", request._6, request._6_);
+    range!("i7", request._7, request._7_);
+
+    // Boxes use the (ns, geo_lat, geo_lon) index, radii are boxed first.
+    match geo.copied() {
+        Some(Geo::Box {
+            lon_min,
+            lat_min,
+            lon_max,
+            lat_max,
+        }) => {
+            push_box(query, lon_min, lat_min, lon_max, lat_max);
+        }
+        Some(Geo::Radius { lon, lat, meters }) => {
+            let angle = meters / EARTH_RADIUS;
+            let dlat = angle.to_degrees();
+            let dlon = (angle.sin() / lat.to_radians().cos()).asin().to_degrees();
+            if dlon.is_finite() && lat + dlat < 90.0 && lat - dlat > -90.0 {
+                let lon_min = lon - dlon + if lon - dlon < -180.0 { 360.0 } else { 0.0 };
+                let lon_max = lon + dlon - if lon + dlon > 180.0 { 360.0 } else { 0.0 };
+                push_box(query, lon_min, lat - dlat, lon_max, lat + dlat);
             } else {
-                query.push_str(" AND pub = true");
+                // Around a pole, every longitude.
+                query
+                    .push(" AND geo_lat BETWEEN ")
+                    .push_bind((lat - dlat).max(-90.0))
+                    .push(" AND ")
+                    .push_bind((lat + dlat).min(90.0));
             }
-            query.push_str(&format!(" AND uid = ${}", param_idx));
-            param_idx += 1;
+            query.push(" AND ");
+            push_dist(query, lon, lat);
+            query.push(" <= ").push_bind(meters);
         }
+        None => {}
     }
 
-    // Build range filters
-    macro_rules! add_range_filter {
-        ($col:expr, $begin:expr, $end:expr) => {
-            if $begin.is_some() {
-                if $end.is_some() {
-                    query.push_str(&format!(
-                        " AND {} > ${} AND {} < ${}",
-                        $col,
-                        param_idx,
-                        $col,
-                        param_idx + 1
-                    ));
-                    param_idx += 2;
-                } else {
-                    query.push_str(&format!(" AND {} = ${}", $col, param_idx));
-                    param_idx += 1;
-                }
-            }
-        };
+    Ok(())
+}
+
+/// Select docs by the filters of request, unordered.
+fn select_query(
+    uid: &Id,
+    request: &Get,
+    internal: bool,
+) -> Result<QueryBuilder<'static, Postgres>> {
+    let geo = request.geo()?;
+
+    let mut query = QueryBuilder::new(
+        "SELECT id, uid, pub, eol, tip, ns, size, i0, i1, i2, i3, i4, i5, i6, i7, geo_lon, geo_lat, body, version",
+    );
+    if let Some((lon, lat)) = geo.as_ref().and_then(Geo::center) {
+        query.push(", ");
+        push_dist(&mut query, lon, lat);
+        query.push(" AS dist");
     }
+    query.push(" FROM map_docs WHERE true");
+
+    push_filters(&mut query, uid, request, internal, geo.as_ref())?;
+    Ok(query)
+}
 
-    // Add filters for eol, tip, size, ns
-    add_range_filter!("eol", request._eol, request._eol_);
-    add_range_filter!("tip", request._tip, request._tip_);
-    add_range_filter!("size", request._size, request._size_);
+/// Select a page of docs by the filters of request.
+fn get_query(uid: &Id, request: &Get, internal: bool) -> Result<QueryBuilder<'static, Postgres>> {
+    let center = request.geo()?.as_ref().and_then(Geo::center);
+    let mut query = select_query(uid, request, internal)?;
 
-    if request._ns.is_some() {
-        if request._ns_.is_some() {
-            query.push_str(&format!(
-                " AND ns > ${} AND ns < ${}",
-                param_idx,
-                param_idx + 1
-            ));
-            param_idx += 2;
+    // Keyset pagination, stable as docs come and go.
+    let (sort, desc) = (request.sort(), request.desc());
+    let expr = match (sort, center) {
+        ("_dist", Some(_)) => "dist",
+        ("_dist", None) => return Err(Error::GeneMapSort),
+        _ => sort_expr(sort)?,
+    };
+    let (cmp, order) = if desc {
+        (" < ", " DESC")
+    } else {
+        (" > ", " ASC")
+    };
+    if let Some(cursor) = &request._cursor {
+        let cursor = Cursor::decode(cursor)?;
+        if cursor.sort != sort || cursor.desc != desc {
+            return Err(Error::GeneMapCursor);
+        }
+        let id = Uuid::parse_str(&cursor.id).map_err(|_| Error::GeneMapCursor)?;
+        if sort == "_id" {
+            query.push(" AND id").push(cmp).push_bind(id);
         } else {
-            query.push_str(&format!(" AND ns = ${}", param_idx));
-            param_idx += 1;
+            query.push(" AND (");
+            match center {
+                Some((lon, lat)) if sort == "_dist" => push_dist(&mut query, lon, lat),
+                _ => {
+                    query.push(expr);
+                }
+            }
+            query.push(", id)").push(cmp).push("(");
+            match (sort, cursor.value) {
+                ("_eol", Value::Number(value)) => {
+                    let eol = value
+                        .as_i64()
+                        .and_then(|eol| DateTime::from_timestamp(eol, 0))
+                        .ok_or(Error::GeneMapCursor)?;
+                    query.push_bind(eol);
+                }
+                ("_tip" | "_size", Value::Number(value)) => {
+                    query.push_bind(value.as_i64().ok_or(Error::GeneMapCursor)?);
+                }
+                ("_dist", Value::Number(value)) => {
+                    query.push_bind(value.as_f64().ok_or(Error::GeneMapCursor)?);
+                }
+                ("_ns", Value::String(value)) => {
+                    query.push_bind(value);
+                }
+                ("_eol" | "_tip" | "_size" | "_ns" | "_dist", _) => {
+                    return Err(Error::GeneMapCursor);
+                }
+                (_, value) => {
+                    query.push_bind(value);
+                }
+            }
+            query.push(", ").push_bind(id).push(")");
         }
     }
-    let _ = param_idx;
+    query.push(format_args!(" ORDER BY {}{}, id{}", expr, order, order));
 
-    // Add limit
-    if let Some(n) = request._n {
-        query.push_str(&format!(" LIMIT {}", n));
-    } else {
-        query.push_str(" LIMIT 100"); // Default limit
-    }
+    query.push(" LIMIT ").push_bind(request.n() as i64);
+
+    Ok(query)
+}
 
-    // Execute query - simplified version without dynamic binding
-    // For a full implementation, we'd need to use sqlx::QueryBuilder
-    let rows = sqlx::query(&query).fetch_all(&cx.db.crdb).await?;
+async fn handle_get(
+    cx: &mut V1Context<'_>,
+    tx: &mut Tx,
+    request: Get,
+    internal: bool,
+) -> Result<String> {
+    let rows = get_query(cx.uid, &request, internal)?
+        .build()
+        .fetch_all(&mut *tx.conn)
+        .await?;
+    let mut full = rows.len() as u64 == request.n();
 
     let mut result = json!({});
-    let mut i = 0;
+    let mut last = None;
     let mut s = cx.changes.traffic / cx.traffic_cost;
 
-    for row in rows {
+    for (i, row) in rows.into_iter().enumerate() {
         let id: Uuid = row.get("id");
         let uid_bytes: Vec<u8> = row.get("uid");
-        let is_pub: bool = row.get("pub");
-        let eol: DateTime<Utc> = row.get("eol");
         let tip: i64 = row.get("tip");
-        let ns: String = row.get("ns");
-        let size: i64 = row.get("size");
-        let body: Value = row.get("body");
+        let mut doc = row_doc(&row);
+        let doc_map = doc.as_object_mut().unwrap();
+
+        let value = doc_map.get(request.sort()).cloned().unwrap_or_default();
+
+        // Keep selected fields
+        if let Some(v) = &request._v {
+            doc_map.retain(|k, _| k == "_id" || v.contains(k));
+        }
 
         // Size check
-        if size > s {
+        let len = doc.to_string().len() as i64;
+        if len > s {
             return Err(Error::CostTraffic);
         }
-        s -= size;
+        s -= len;
+
+        // Tip check and payment, free for own docs
+        if uid_bytes != cx.uid.0[..] {
+            if tip > cx.changes.tip {
+                result["_error"] = json!("tip");
+                result["_error_id"] = json!(id.to_string());
+                result["_error_tip"] = json!(tip);
+                full = true;
+                break;
+            }
+            cx.changes.tip -= tip;
 
-        // Skip if document belongs to requesting user
-        if uid_bytes == cx.uid.0[..] {
-            continue;
+            let mut doc_uid = Id::zero();
+            doc_uid.0.copy_from_slice(&uid_bytes);
+            tx.tips.push((doc_uid, tip));
         }
 
-        // Tip check and payment
-        if tip > cx.changes.tip {
-            result["_error"] = json!("tip");
-            result["_error_id"] = json!(id.to_string());
-            result["_error_tip"] = json!(tip);
-            break;
+        result[i.to_string()] = doc;
+        last = Some((value, id));
+    }
+
+    // More docs may follow.
+    if let (true, Some((value, id))) = (full, last) {
+        let cursor = Cursor {
+            sort: request.sort().into(),
+            desc: request.desc(),
+            value,
+            id: id.to_string(),
+        };
+        result["_next"] = cursor.encode().into();
+    }
+
+    Ok(result.to_string())
+}
+
+/// Doc of a row selected by select_query, with body fields and _dist.
+fn row_doc(row: &PgRow) -> Value {
+    let id: Uuid = row.get("id");
+    let uid_bytes: Vec<u8> = row.get("uid");
+    let eol: DateTime<Utc> = row.get("eol");
+    let body: Value = row.get("body");
+    let geo_lon: Option<f64> = row.get("geo_lon");
+    let geo_lat: Option<f64> = row.get("geo_lat");
+    let geo = match (geo_lon, geo_lat) {
+        (Some(lon), Some(lat)) => json!([lon, lat]),
+        _ => Value::Null,
+    };
+
+    // Build document JSON
+    let mut doc = json!({
+        "_id": id.to_string(),
+        "_uid": hex::encode(&uid_bytes),
+        "_pub": row.get::<bool, _>("pub"),
+        "_eol": eol.timestamp(),
+        "_tip": row.get::<i64, _>("tip"),
+        "_ns": row.get::<String, _>("ns"),
+        "_size": row.get::<i64, _>("size"),
+        "_version": row.get::<i64, _>("version"),
+        "_0": row.get::<Option<Value>, _>("i0"),
+        "_1": row.get::<Option<Value>, _>("i1"),
+        "_2": row.get::<Option<Value>, _>("i2"),
+        "_3": row.get::<Option<Value>, _>("i3"),
+        "_4": row.get::<Option<Value>, _>("i4"),
+        "_5": row.get::<Option<Value>, _>("i5"),
+        "_6": row.get::<Option<Value>, _>("i6"),
+        "_7": row.get::<Option<Value>, _>("i7"),
+        "_geo": geo,
+    });
+
+    // Merge body fields
+    let doc_map = doc.as_object_mut().unwrap();
+    if let Value::Object(body_obj) = body {
+        for (k, v) in body_obj {
+            doc_map.insert(k, v);
         }
-        cx.changes.tip -= tip;
+    }
+    if let Ok(dist) = row.try_get::<f64, _>("dist") {
+        doc_map.insert("_dist".into(), dist.into());
+    }
+    doc
+}
 
-        let mut doc_uid = Id::zero();
-        doc_uid.0.copy_from_slice(&uid_bytes);
-        cx.db
-            .incr_credit(&doc_uid, Some(cx.uid), tip, "GeneMap1Tip")
+/// Change of a doc to feed once committed, from a row with all its columns.
+fn feed_change(op: &'static str, row: &PgRow) -> MapChange {
+    let mut uid = Id::zero();
+    uid.0.copy_from_slice(&row.get::<Vec<u8>, _>("uid"));
+    MapChange {
+        op,
+        id: row.get("id"),
+        uid,
+        ns: row.get("ns"),
+        is_pub: row.get("pub"),
+        doc: Arc::new(row_doc(row)),
+    }
+}
+
+/// Namespace of JSON Schemas, a doc per ns with _0 as the ns and the schema in "schema".
+const SCHEMA_NS: &str = "_schema";
+
+/// A schema is put by admins of a claimed ns. Schemas of ns put by other
+/// admins are replaced, without refund as on Drop.
+async fn put_schema(tx: &mut Tx, uid: &Id, request: &Put) -> Result<()> {
+    let ns = match &request._0 {
+        Value::String(ns) if !ns.is_empty() && !ns.starts_with('_') => ns,
+        _ => return Err(Error::Namespace),
+    };
+    if !claimed(tx, ns).await? || ns_level(tx, uid, ns).await? < Level::Admin {
+        return Err(Error::Namespace);
+    }
+    let schema = request
+        .v
+        .get("schema")
+        .ok_or_else(|| Error::GeneMapSchema("Missing schema".into()))?;
+    jsonschema::validator_for(schema).map_err(|e| Error::GeneMapSchema(e.to_string()))?;
+
+    let replaced =
+        sqlx::query("DELETE FROM map_docs WHERE ns = $1 AND i0 = $2 AND uid != $3 RETURNING *")
+            .bind(SCHEMA_NS)
+            .bind(json!(ns))
+            .bind(&uid.0[..])
+            .fetch_all(&mut *tx.conn)
             .await?;
+    for row in &replaced {
+        tx.feed.push(feed_change("Drop", row));
+    }
+
+    let id = match &request._id {
+        Some(id) => Some(Uuid::parse_str(id).map_err(|_| Error::GeneMapNotFound)?),
+        None => None,
+    };
+    let taken = sqlx::query(
+        "SELECT 1 FROM map_docs WHERE ns = $1 AND i0 = $2 AND id IS DISTINCT FROM $3 LIMIT 1",
+    )
+    .bind(SCHEMA_NS)
+    .bind(json!(ns))
+    .bind(id)
+    .fetch_optional(&mut *tx.conn)
+    .await?;
+    if taken.is_some() {
+        return Err(Error::Namespace);
+    }
+    Ok(())
+}
 
-        // Build document JSON
-        let doc = json!({
-            "_id": id.to_string(),
-            "_uid": hex::encode(&uid_bytes),
-            "_pub": is_pub,
-            "_eol": eol.timestamp(),
-            "_tip": tip,
-            "_ns": ns,
-            "_size": size,
-            "_0": row.try_get::<Value, _>("i0").ok(),
-            "_1": row.try_get::<Value, _>("i1").ok(),
-            "_2": row.try_get::<Value, _>("i2").ok(),
-            "_3": row.try_get::<Value, _>("i3").ok(),
-            "_4": row.try_get::<Value, _>("i4").ok(),
-            "_5": row.try_get::<Value, _>("i5").ok(),
-            "_6": row.try_get::<Value, _>("i6").ok(),
-            "_7": row.try_get::<Value, _>("i7").ok(),
-        });
-
-        // Merge body fields
-        let mut doc_map = doc.as_object().unwrap().clone();
-        if let Value::Object(body_obj) = body {
-            for (k, v) in body_obj {
-                doc_map.insert(k, v);
+/// Schemas are compiled again when there are more than this.
+const SCHEMAS_MAX: usize = 10_000;
+
+/// Check doc against the schema of ns, if any.
+async fn check_schema(cx: &V1Context<'_>, tx: &mut Tx, ns: &str, doc: &Value) -> Result<()> {
+    if ns.is_empty() || ns.starts_with('_') {
+        return Ok(());
+    }
+    let row = sqlx::query("SELECT id, version FROM map_docs WHERE ns = $1 AND i0 = $2 LIMIT 1")
+        .bind(SCHEMA_NS)
+        .bind(json!(ns))
+        .fetch_optional(&mut *tx.conn)
+        .await?;
+    let Some(row) = row else {
+        return Ok(());
+    };
+    let (id, version): (Uuid, i64) = (row.get("id"), row.get("version"));
+
+    let cached = cx.db.map_schemas.lock().unwrap().get(ns).cloned();
+    let validator = match cached {
+        Some((i, v, validator)) if i == id && v == version => validator,
+        _ => {
+            let body: Value = sqlx::query("SELECT body FROM map_docs WHERE id = $1")
+                .bind(id)
+                .fetch_one(&mut *tx.conn)
+                .await?
+                .get("body");
+            let validator = Arc::new(
+                jsonschema::validator_for(&body["schema"])
+                    .map_err(|e| Error::GeneMapSchema(e.to_string()))?,
+            );
+            let mut schemas = cx.db.map_schemas.lock().unwrap();
+            if schemas.len() > SCHEMAS_MAX {
+                schemas.clear();
             }
+            schemas.insert(ns.to_string(), (id, version, validator.clone()));
+            validator
         }
+    };
+    let errors: Vec<String> = validator
+        .iter_errors(doc)
+        .map(|e| format!("{}: {}", e.instance_path, e))
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::GeneMapSchema(errors.join("\n")))
+    }
+}
+
+/// Indexed keys as named in docs.
+const KEYS: [&str; 8] = ["_0", "_1", "_2", "_3", "_4", "_5", "_6", "_7"];
+
+/// Column of an indexed key.
+fn key_column(key: &str) -> Result<&'static str> {
+    const COLUMNS: [&str; 8] = ["i0", "i1", "i2", "i3", "i4", "i5", "i6", "i7"];
+    KEYS.iter()
+        .position(|k| *k == key)
+        .map(|i| COLUMNS[i])
+        .ok_or(Error::GeneMapKey)
+}
 
-        result[i.to_string()] = Value::Object(doc_map);
-        i += 1;
+/// Traffic charged per doc aggregated, in bytes.
+const AGGREGATE_ROW: i64 = 64;
+
+/// Most groups per Aggregate.
+const AGGREGATE_GROUPS: i64 = 1000;
+
+async fn handle_aggregate(
+    cx: &mut V1Context<'_>,
+    tx: &mut Tx,
+    request: Aggregate,
+    internal: bool,
+) -> Result<String> {
+    let of = request._of.as_deref().map(key_column).transpose()?;
+    let by = request._by.as_deref().map(key_column).transpose()?;
+    let geo = request.filter.geo()?;
+
+    let mut query = QueryBuilder::new("SELECT ");
+    if let Some(by) = by {
+        query.push(format_args!("COALESCE({}, 'null'::JSONB) AS key, ", by));
+    }
+    query.push("count(*) AS count, sum(count(*)) OVER ()::INT8 AS total");
+    if let Some(of) = of {
+        let n =
+            format!("CASE WHEN jsonb_typeof({of}) = 'number' THEN ({of} #>> '{{}}')::FLOAT8 END");
+        query.push(format_args!(
+            ", sum({n}) AS sum, min({n}) AS min, max({n}) AS max, avg({n}) AS avg"
+        ));
+    }
+    query.push(" FROM map_docs WHERE true");
+    push_filters(&mut query, cx.uid, &request.filter, internal, geo.as_ref())?;
+    // Groups and min or max would tell the keys of tipped docs for free.
+    if !internal {
+        query
+            .push(" AND (tip = 0 OR uid = ")
+            .push_bind(cx.uid.0.to_vec())
+            .push(")");
     }
+    if by.is_some() {
+        query
+            .push(" GROUP BY 1 ORDER BY 1 LIMIT ")
+            .push_bind(AGGREGATE_GROUPS);
+    }
+    let rows = query.build().fetch_all(&mut *tx.conn).await?;
+
+    // Paid by docs scanned, not bytes returned.
+    let total: i64 = rows.first().map(|row| row.get("total")).unwrap_or_default();
+    let traffic = total
+        .checked_mul(AGGREGATE_ROW * cx.traffic_cost)
+        .ok_or(Error::NumCheck)?;
+    if traffic > cx.changes.traffic {
+        return Err(Error::CostTraffic);
+    }
+    cx.changes.traffic -= traffic;
 
+    let stats = |row: &sqlx::postgres::PgRow| {
+        let mut stats = json!({"count": row.get::<i64, _>("count")});
+        if of.is_some() {
+            for k in ["sum", "min", "max", "avg"] {
+                stats[k] = json!(row.get::<Option<f64>, _>(k));
+            }
+        }
+        stats
+    };
+    let result = if by.is_some() {
+        let groups: Vec<Value> = rows
+            .iter()
+            .map(|row| {
+                let mut group = stats(row);
+                group["key"] = row.get::<Value, _>("key");
+                group
+            })
+            .collect();
+        json!({ "groups": groups })
+    } else {
+        rows.first()
+            .map(stats)
+            .unwrap_or_else(|| json!({"count": 0}))
+    };
     Ok(result.to_string())
 }
 
-async fn handle_drop(cx: V1Context<'_>, request: Drop) -> Result<String> {
+async fn handle_patch(cx: &mut V1Context<'_>, tx: &mut Tx, request: Patch) -> Result<String> {
+    let id = Uuid::parse_str(&request._id).map_err(|_| Error::GeneMapNotFound)?;
+
+    // Lock the doc, so increments don't race.
+    let row = sqlx::query(
+        "SELECT eol, size, version, i0, i1, i2, i3, i4, i5, i6, i7, body, ns, pub, paid FROM map_docs
+         WHERE id = $1 AND uid = $2 FOR UPDATE",
+    )
+    .bind(id)
+    .bind(&cx.uid.0[..])
+    .fetch_optional(&mut *tx.conn)
+    .await?
+    .ok_or(Error::GeneMapNotFound)?;
+
+    let eol: DateTime<Utc> = row.get("eol");
+    let old_size: i64 = row.get("size");
+    let version: i64 = row.get("version");
+    if request._version.is_some_and(|v| v != version) {
+        return Err(Error::GeneMapVersion);
+    }
+    let ttl = eol - Utc::now();
+    if ttl <= Duration::zero() {
+        return Err(Error::GeneMapExpired);
+    }
+
+    // Body fields and indexed keys in one object.
+    let mut doc = match row.get::<Option<Value>, _>("body") {
+        Some(Value::Object(body)) => body,
+        _ => Default::default(),
+    };
+    for (i, key) in KEYS.iter().enumerate() {
+        if let Some(value) = row.get::<Option<Value>, _>(i + 3) {
+            doc.insert(key.to_string(), value);
+        }
+    }
+    let mut doc = Value::Object(doc);
+
+    if let Some(merge) = &request._merge {
+        json_patch::merge(&mut doc, merge);
+    }
+    if let Some(ops) = &request._ops {
+        json_patch::patch(&mut doc, &ops.0).map_err(|_| Error::GeneMapPatch)?;
+    }
+    let Value::Object(mut doc) = doc else {
+        return Err(Error::GeneMapPatch);
+    };
+    for (k, by) in request._incr.unwrap_or_default() {
+        let value = doc.get(&k).cloned().unwrap_or(json!(0));
+        let sum = match (value.as_i64(), by.as_i64()) {
+            (Some(a), Some(b)) => json!(a.checked_add(b).ok_or(Error::NumCheck)?),
+            _ => match (value.as_f64(), by.as_f64()) {
+                (Some(a), Some(b)) => json!(a + b),
+                _ => return Err(Error::GeneMapPatch),
+            },
+        };
+        doc.insert(k, sum);
+    }
+
+    let view = doc
+        .iter()
+        .filter(|(k, v)| !(v.is_null() && KEYS.contains(&k.as_str())))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    let ns: String = row.get("ns");
+    if ns_level(tx, cx.uid, &ns).await? < Level::Write {
+        return Err(Error::Namespace);
+    }
+    check_schema(cx, tx, &ns, &Value::Object(view)).await?;
+
+    // Split back.
+    let keys: Vec<Option<Value>> = KEYS.iter().map(|key| doc.remove(*key)).collect();
+    for k in doc.keys() {
+        if k.starts_with('_') {
+            return Err(Error::ReservedKey);
+        }
+    }
+    let body = Value::Object(doc);
+    let size = serde_json::to_string(&body)?.len() as i64 + 200; // as Put
+
+    // Pay or refund the size delta until eol, refunds up to what was paid.
+    let kb = (size + 1023) / 1024 - (old_size + 1023) / 1024;
+    let space = kb
+        .checked_mul(ttl.num_days())
+        .and_then(|space| space.checked_mul(cx.space_cost))
+        .ok_or(Error::NumCheck)?;
+    if space > cx.changes.space {
+        return Err(Error::CostSpace);
+    }
+    let paid: i64 = row.get("paid");
+    let (charge, refund) = if space > 0 {
+        (space, 0)
+    } else {
+        (0, (-space).min(paid))
+    };
+
+    let mut query = QueryBuilder::<Postgres>::new("UPDATE map_docs SET body = ");
+    query.push_bind(body).push(", size = ").push_bind(size);
+    query.push(", paid = ").push_bind(paid + charge - refund);
+    for (i, value) in keys.into_iter().enumerate() {
+        query.push(format_args!(", i{} = ", i)).push_bind(value);
+    }
+    query
+        .push(", version = version + 1 WHERE id = ")
+        .push_bind(id)
+        .push(" RETURNING *");
+    let row = query.build().fetch_one(&mut *tx.conn).await?;
+    let version: i64 = row.get("version");
+    cx.changes.space -= charge;
+    tx.refund += refund;
+    tx.feed.push(feed_change("Patch", &row));
+
+    Ok(json!({"_id": id.to_string(), "_version": version}).to_string())
+}
+
+/// Refund of the space of a doc until eol, up to what was paid for it.
+/// The row has eol, size and paid.
+fn space_refund(cx: &V1Context<'_>, row: &PgRow) -> i64 {
+    let eol: DateTime<Utc> = row.get("eol");
+    let size: i64 = row.get("size");
+    let paid: i64 = row.get("paid");
+    let ttl = eol - Utc::now();
+    if ttl <= Duration::zero() {
+        return 0;
+    }
+    ((size / 1024) * ttl.num_days() * cx.space_cost).clamp(0, paid)
+}
+
+async fn handle_drop(cx: &mut V1Context<'_>, tx: &mut Tx, request: Drop) -> Result<String> {
     let id = request._id.ok_or(Error::GeneMapNotFound)?;
     let id = Uuid::parse_str(&id).map_err(|_| Error::GeneMapNotFound)?;
 
     // Get document for refund calculation
-    let row = sqlx::query("SELECT eol, size FROM map_docs WHERE id = $1 AND uid = $2")
+    let row = sqlx::query("SELECT uid, ns, i0, eol, size, pub, paid FROM map_docs WHERE id = $1")
         .bind(id)
-        .bind(&cx.uid.0[..])
-        .fetch_optional(&cx.db.crdb)
+        .fetch_optional(&mut *tx.conn)
         .await?
         .ok_or(Error::GeneMapNotFound)?;
 
-    let eol: DateTime<Utc> = row.get("eol");
-    let size: i64 = row.get("size");
+    let uid: Vec<u8> = row.get("uid");
+    let mut ns: String = row.get("ns");
+    if ns == SCHEMA_NS {
+        // Schemas are admined in the ns they are for.
+        ns = match row.get::<Option<Value>, _>("i0") {
+            Some(Value::String(of)) => of,
+            _ => return Err(Error::GeneMapNotFound),
+        };
+    }
 
-    // Calculate refund
-    let now = Utc::now();
-    if now < eol {
-        let ttl = eol - now;
-        let space_refund = (size / 1024) * ttl.num_days() * cx.space_cost;
-        cx.changes.space += space_refund;
+    if uid == cx.uid.0[..] {
+        tx.refund += space_refund(cx, &row);
+    } else if ns_level(tx, cx.uid, &ns).await? < Level::Admin {
+        // Admins drop docs of others, without refund.
+        return Err(Error::GeneMapNotFound);
     }
 
     // Delete document
-    sqlx::query("DELETE FROM map_docs WHERE id = $1 AND uid = $2")
+    let row = sqlx::query("DELETE FROM map_docs WHERE id = $1 RETURNING *")
         .bind(id)
+        .fetch_one(&mut *tx.conn)
+        .await?;
+    tx.feed.push(feed_change("Drop", &row));
+
+    Ok("{}".into())
+}
+
+/// Whether ns is claimed.
+async fn claimed(tx: &mut Tx, ns: &str) -> Result<bool> {
+    let row = sqlx::query("SELECT 1 FROM map_ns WHERE ns = $1")
+        .bind(ns)
+        .fetch_optional(&mut *tx.conn)
+        .await?;
+    Ok(row.is_some())
+}
+
+/// Level of uid in ns. Anyone may write to unclaimed namespaces.
+async fn ns_level(tx: &mut Tx, uid: &Id, ns: &str) -> Result<Level> {
+    let row = sqlx::query(
+        "SELECT n.uid = $2 AS owner, n.public, a.level FROM map_ns n
+         LEFT JOIN map_acl a ON a.ns = n.ns AND a.uid = $2 WHERE n.ns = $1",
+    )
+    .bind(ns)
+    .bind(&uid.0[..])
+    .fetch_optional(&mut *tx.conn)
+    .await?;
+    let Some(row) = row else {
+        return Ok(Level::Write);
+    };
+    if row.get::<bool, _>("owner") {
+        return Ok(Level::Admin);
+    }
+    let public = Level::parse(row.get("public"));
+    let level = Level::parse(row.get::<Option<&str>, _>("level").unwrap_or_default());
+    Ok(public.max(level))
+}
+
+async fn handle_claim(cx: &mut V1Context<'_>, tx: &mut Tx, request: Claim) -> Result<String> {
+    let ns = request._ns;
+    if ns.is_empty() || ns.starts_with('_') {
+        return Err(Error::Namespace);
+    }
+    let others = sqlx::query("SELECT 1 FROM map_docs WHERE ns = $1 AND uid != $2 LIMIT 1")
+        .bind(&ns)
         .bind(&cx.uid.0[..])
-        .execute(&cx.db.crdb)
+        .fetch_optional(&mut *tx.conn)
         .await?;
+    if others.is_some() {
+        return Err(Error::Namespace);
+    }
+    // Nor a schema set by others before it was claimed.
+    let schema =
+        sqlx::query("SELECT 1 FROM map_docs WHERE ns = $1 AND i0 = $2 AND uid != $3 LIMIT 1")
+            .bind(SCHEMA_NS)
+            .bind(json!(ns))
+            .bind(&cx.uid.0[..])
+            .fetch_optional(&mut *tx.conn)
+            .await?;
+    if schema.is_some() {
+        return Err(Error::Namespace);
+    }
+    let result = sqlx::query("INSERT INTO map_ns (ns, uid) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+        .bind(&ns)
+        .bind(&cx.uid.0[..])
+        .execute(&mut *tx.conn)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(Error::Namespace);
+    }
+    Ok("{}".into())
+}
 
+async fn handle_grant(cx: &mut V1Context<'_>, tx: &mut Tx, request: Grant) -> Result<String> {
+    let ns = request._ns;
+    if !claimed(tx, &ns).await? || ns_level(tx, cx.uid, &ns).await? < Level::Admin {
+        return Err(Error::Namespace);
+    }
+    let level = request._level.unwrap_or(Level::Nil);
+    match request._uid {
+        None => {
+            if level == Level::Admin {
+                return Err(Error::Namespace);
+            }
+            sqlx::query("UPDATE map_ns SET public = $2 WHERE ns = $1")
+                .bind(&ns)
+                .bind(level.as_str())
+                .execute(&mut *tx.conn)
+                .await?;
+        }
+        Some(uid) => {
+            let uid = Id::try_from(uid.as_str())?;
+            if level == Level::Nil {
+                sqlx::query("DELETE FROM map_acl WHERE ns = $1 AND uid = $2")
+                    .bind(&ns)
+                    .bind(&uid.0[..])
+                    .execute(&mut *tx.conn)
+                    .await?;
+            } else {
+                sqlx::query(
+                    "INSERT INTO map_acl (ns, uid, level) VALUES ($1, $2, $3)
+                     ON CONFLICT (ns, uid) DO UPDATE SET level = excluded.level",
+                )
+                .bind(&ns)
+                .bind(&uid.0[..])
+                .bind(level.as_str())
+                .execute(&mut *tx.conn)
+                .await?;
+            }
+        }
+    }
     Ok("{}".into())
 }
+
+/// Push changes of docs in _ns matching the filters of a Get.
+async fn subscribe(cx: V1Context<'_>) -> Result<Feed> {
+    let request: Get = serde_json::from_str(cx.arg)?;
+    let ns = match (&request._ns, &request._ns_) {
+        (Some(ns), None) => ns.clone(),
+        _ => return Err(Error::Namespace),
+    };
+    select_query(cx.uid, &request, false)?;
+    let geo = request.geo()?;
+
+    let (uid, db) = (*cx.uid, cx.db);
+    let mut changes = db.map_feed.subscribe();
+    let (tx, rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        loop {
+            let event = tokio::select! {
+                _ = tx.closed() => break,
+                change = changes.recv() => match change {
+                    Ok(change) if change.ns == ns && matches(&request, geo.as_ref(), &change) => {
+                        let id = change.id.to_string();
+                        match change_event(db, &uid, &request, geo.as_ref(), change).await {
+                            Ok(Some(event)) => event,
+                            Ok(None) => continue,
+                            Err(error) => json!({"_error": error.to_string(), "_id": id}),
+                        }
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(lost)) => json!({"_error": "lagged", "_lost": lost}),
+                    Err(RecvError::Closed) => break,
+                },
+            };
+            if tx.send(event.to_string()).await.is_err() {
+                break;
+            }
+        }
+    });
+    Ok(Box::pin(ReceiverStream::new(rx)))
+}
+
+/// Whether the doc of a change passes the filters of request, as push_filters.
+/// Keys that are not set match no filter, like NULL in SQL.
+fn matches(request: &Get, geo: Option<&Geo>, change: &MapChange) -> bool {
+    let doc = &change.doc;
+    let int = |k: &str| doc[k].as_i64().unwrap_or_default();
+    let keys = [
+        (&request._0, &request._0_),
+        (&request._1, &request._1_),
+        (&request._2, &request._2_),
+        (&request._3, &request._3_),
+        (&request._4, &request._4_),
+        (&request._5, &request._5_),
+        (&request._6, &request._6_),
+        (&request._7, &request._7_),
+    ];
+    request
+        ._id
+        .as_ref()
+        .is_none_or(|id| Uuid::parse_str(id).is_ok_and(|id| id == change.id))
+        && request
+            ._uid
+            .as_ref()
+            .is_none_or(|uid| Id::try_from(uid.as_str()).is_ok_and(|uid| uid.0 == change.uid.0))
+        && request._pub.is_none_or(|is_pub| is_pub == change.is_pub)
+        && in_range(
+            &int("_eol"),
+            request._eol.map(|eol| eol.timestamp()).as_ref(),
+            request._eol_.map(|eol| eol.timestamp()).as_ref(),
+            Ord::cmp,
+        )
+        && in_range(
+            &int("_tip"),
+            request._tip.as_ref(),
+            request._tip_.as_ref(),
+            Ord::cmp,
+        )
+        && in_range(
+            &int("_size"),
+            request._size.as_ref(),
+            request._size_.as_ref(),
+            Ord::cmp,
+        )
+        && in_range(
+            &change.ns,
+            request._ns.as_ref(),
+            request._ns_.as_ref(),
+            Ord::cmp,
+        )
+        && keys.iter().zip(KEYS).all(|((begin, end), key)| {
+            (begin.is_none() && end.is_none())
+                || !doc[key].is_null()
+                    && in_range(&doc[key], begin.as_ref(), end.as_ref(), jsonb_cmp)
+        })
+        && geo.is_none_or(|geo| doc_geo(doc).is_some_and(|point| geo.contains(point)))
+}
+
+/// Whether value is in [begin, end), or equals begin alone.
+fn in_range<T: ?Sized>(
+    value: &T,
+    begin: Option<&T>,
+    end: Option<&T>,
+    cmp: impl Fn(&T, &T) -> Ordering,
+) -> bool {
+    begin.is_none_or(|begin| match end {
+        Some(_) => cmp(value, begin).is_ge(),
+        None => cmp(value, begin).is_eq(),
+    }) && end.is_none_or(|end| cmp(value, end).is_lt())
+}
+
+/// Order of JSONB: null < strings < numbers < booleans < arrays < objects,
+/// with arrays and objects by length first.
+fn jsonb_cmp(a: &Value, b: &Value) -> Ordering {
+    let rank = |value: &Value| match value {
+        Value::Null => 0,
+        Value::String(_) => 1,
+        Value::Number(_) => 2,
+        Value::Bool(_) => 3,
+        Value::Array(_) => 4,
+        Value::Object(_) => 5,
+    };
+    match (a, b) {
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Number(a), Value::Number(b)) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Array(a), Value::Array(b)) => a.len().cmp(&b.len()).then_with(|| {
+            a.iter()
+                .zip(b)
+                .map(|(a, b)| jsonb_cmp(a, b))
+                .find(|order| order.is_ne())
+                .unwrap_or(Ordering::Equal)
+        }),
+        (Value::Object(a), Value::Object(b)) => a.len().cmp(&b.len()).then_with(|| {
+            a.iter()
+                .zip(b)
+                .map(|((ka, a), (kb, b))| ka.cmp(kb).then_with(|| jsonb_cmp(a, b)))
+                .find(|order| order.is_ne())
+                .unwrap_or(Ordering::Equal)
+        }),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+/// Longitude and latitude of a doc, if set.
+fn doc_geo(doc: &Value) -> Option<(f64, f64)> {
+    match doc["_geo"].as_array()?.as_slice() {
+        [lon, lat] => Some((lon.as_f64()?, lat.as_f64()?)),
+        _ => None,
+    }
+}
+
+/// The doc of a change with _op, if visible, after matches.
+/// Docs of others with a tip have only _id and _tip, Get them to pay it.
+/// Drops have only _id and _op, as the doc is gone.
+async fn change_event(
+    db: &'static Database,
+    uid: &Id,
+    request: &Get,
+    geo: Option<&Geo>,
+    change: MapChange,
+) -> Result<Option<Value>> {
+    // Others' private docs are visible by the ACL of their namespace.
+    let visible = change.is_pub || change.uid.0 == uid.0 || {
+        let mut tx = Tx {
+            conn: db.crdb.begin().await?,
+            tips: vec![],
+            refund: 0,
+            feed: vec![],
+        };
+        claimed(&mut tx, &change.ns).await?
+            && ns_level(&mut tx, uid, &change.ns).await? >= Level::Read
+    };
+    if !visible {
+        return Ok(None);
+    }
+    if change.op == "Drop" {
+        return Ok(Some(
+            json!({"_id": change.id.to_string(), "_op": change.op}),
+        ));
+    }
+
+    let mut doc = Arc::unwrap_or_clone(change.doc);
+    let center = geo.and_then(Geo::center);
+    let dist = center
+        .zip(doc_geo(&doc))
+        .map(|((lon, lat), (geo_lon, geo_lat))| dist(lon, lat, geo_lon, geo_lat));
+    let doc_map = doc.as_object_mut().unwrap();
+    if let Some(dist) = dist {
+        doc_map.insert("_dist".into(), dist.into());
+    }
+    let tip = doc_map["_tip"].as_i64().unwrap_or_default();
+    if tip > 0 && change.uid.0 != uid.0 {
+        doc_map.retain(|k, _| k == "_id" || k == "_tip");
+    } else if let Some(v) = &request._v {
+        doc_map.retain(|k, _| k == "_id" || v.contains(k));
+    }
+    doc_map.insert("_op".into(), change.op.into());
+    Ok(Some(doc))
+}
+
+#[test]
+fn test_matches() {
+    let change = |doc: Value| MapChange {
+        op: "Drop",
+        id: Uuid::nil(),
+        uid: Id::zero(),
+        ns: "ns".into(),
+        is_pub: false,
+        doc: Arc::new(doc),
+    };
+    let get = |filter: Value| serde_json::from_value::<Get>(filter).unwrap();
+    let doc = change(
+        json!({"_eol": 100, "_tip": 0, "_size": 300, "_0": "a", "_1": 2, "_geo": [0.0, 0.0]}),
+    );
+
+    assert!(matches(&get(json!({"_ns": "ns", "_0": "a"})), None, &doc));
+    assert!(!matches(&get(json!({"_ns": "ns", "_0": "b"})), None, &doc));
+    assert!(matches(&get(json!({"_1": 1, "_1_": 3})), None, &doc));
+    assert!(!matches(&get(json!({"_1": 2, "_1_": 2})), None, &doc));
+    assert!(!matches(&get(json!({"_2": "a"})), None, &doc));
+    assert!(matches(
+        &get(json!({"_eol_": 101, "_size": 300})),
+        None,
+        &doc
+    ));
+    assert!(!matches(&get(json!({"_pub": true})), None, &doc));
+
+    let near = Geo::parse(&[0.0, 0.1, 20_000.0]).unwrap();
+    let far = Geo::parse(&[10.0, 10.0, 20_000.0]).unwrap();
+    let across = Geo::parse(&[170.0, -1.0, -170.0, 1.0]).unwrap();
+    assert!(matches(&get(json!({})), Some(&near), &doc));
+    assert!(!matches(&get(json!({})), Some(&far), &doc));
+    assert!(!matches(&get(json!({})), Some(&across), &doc));
+
+    assert_eq!(jsonb_cmp(&json!("z"), &json!(1)), Ordering::Less);
+    assert_eq!(jsonb_cmp(&json!(2), &json!(10.5)), Ordering::Less);
+    assert_eq!(jsonb_cmp(&json!([9]), &json!([1, 2])), Ordering::Less);
+}
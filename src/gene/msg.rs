@@ -1,2 +1,2 @@
 mod msg_1;
-pub use msg_1::v1;
+pub use msg_1::V1;
@@ -1,9 +1,12 @@
 //! Message v1
 //!
 //! Both FROM and TO can delete the message.
+//! Sent and Receive page by the time sent, continued by the cursor in _next.
 //! No public flag needed, but TO can report.
 
-use crate::{Error, Result, gene::map, ir::Id};
+use crate::config::Config;
+use crate::gene::{Context, Gene, GeneFuture, GeneMeta, map};
+use crate::{Error, Result, ir::Id};
 use chrono::{DateTime, Utc};
 use serde::Deserialize;
 use serde_json::json;
@@ -47,6 +50,9 @@ struct Sent {
     size_: Option<i64>,
     r#type: Option<String>,
     n: Option<u64>,
+    /// Newest first.
+    desc: Option<bool>,
+    cursor: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -65,6 +71,9 @@ struct Receive {
     size_: Option<i64>,
     r#type: Option<String>,
     n: Option<u64>,
+    /// Newest first.
+    desc: Option<bool>,
+    cursor: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -99,7 +108,35 @@ enum Request {
     Report(Report),
 }
 
-pub async fn v1(mut cx: map::V1Context<'_>) -> Result<String> {
+pub struct V1 {
+    space_cost: i64,
+    traffic_cost: i64,
+}
+
+impl V1 {
+    pub fn new(c: &Config) -> V1 {
+        V1 {
+            space_cost: c.space_cost_doc,
+            traffic_cost: c.traffic_cost,
+        }
+    }
+}
+
+impl Gene for V1 {
+    fn meta(&self) -> GeneMeta {
+        GeneMeta::new("msg", 1, "Messaging another user.")
+    }
+
+    fn call<'a>(&'a self, cx: Context<'a>) -> GeneFuture<'a> {
+        Box::pin(v1(map::V1Context::new(
+            cx,
+            self.space_cost,
+            self.traffic_cost,
+        )))
+    }
+}
+
+async fn v1(mut cx: map::V1Context<'_>) -> Result<String> {
     let db = &cx.db;
 
     let request: Request = serde_json::from_str(cx.arg)?;
@@ -172,6 +209,9 @@ pub async fn v1(mut cx: map::V1Context<'_>) -> Result<String> {
                 "_size_": request.size_,
                 TYPE: request.r#type,
                 "_n": request.n,
+                "_sort": SENT,
+                "_desc": request.desc,
+                "_cursor": request.cursor,
             })
             .to_string();
 
@@ -198,6 +238,9 @@ pub async fn v1(mut cx: map::V1Context<'_>) -> Result<String> {
                 "_size_": request.size_,
                 TYPE: request.r#type,
                 "_n": request.n,
+                "_sort": SENT,
+                "_desc": request.desc,
+                "_cursor": request.cursor,
             })
             .to_string();
 
@@ -0,0 +1,497 @@
+//! WASM genes
+//!
+//! Users upload a WebAssembly module as a meme, then register it by
+//! wasm_1 under an id like "name_1". It runs in wasmi with fuel taken
+//! from time, and memory capped and paid by space.
+//!
+//! # Guest ABI
+//!
+//! The module exports:
+//!
+//! - memory
+//! - alloc(len: i32) -> i32: room for the host to write len bytes.
+//! - call(ptr: i32, len: i32) -> i64: handle the arg at ptr.
+//!
+//! Strings are UTF-8, returned as ptr << 32 | len.
+//! The module may import from "voxov", all under the caller's uid:
+//!
+//! - map_get(ptr: i32, len: i32) -> i64: map_1 Get.
+//! - map_put(ptr: i32, len: i32) -> i64: map_1 Put.
+//! - meme_get(hash: i32, public: i32) -> i64: a meme by the 32 bytes at hash.
+//!
+//! A failed host function traps, and its error is the reply.
+
+use super::{Context, Gene, GeneFuture, GeneMeta, map};
+use crate::config::Config;
+use crate::database::Database;
+use crate::ir::{Costs, Hash, Id};
+use crate::meme::Meme;
+use crate::{Error, Result};
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::Row;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::runtime::Handle;
+use tokio::time::Instant;
+use wasmi::core::TrapCode;
+use wasmi::{
+    Caller, Engine, Extern, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+};
+
+/// Bytes per WASM memory page.
+const PAGE: usize = 64 << 10;
+
+/// Largest module, in bytes.
+const MODULE_MAX: usize = 16 << 20;
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "_type")]
+enum Request {
+    Register {
+        id: String,
+        hash: String,
+        description: Option<String>,
+    },
+    Drop {
+        id: String,
+    },
+}
+
+/// Registers WASM genes.
+pub struct V1;
+
+impl Gene for V1 {
+    fn meta(&self) -> GeneMeta {
+        GeneMeta::new("wasm", 1, "Register a WASM meme as a gene.")
+    }
+
+    fn call<'a>(&'a self, cx: Context<'a>) -> GeneFuture<'a> {
+        Box::pin(async move {
+            match serde_json::from_str(cx.arg)? {
+                Request::Register {
+                    id,
+                    hash,
+                    description,
+                } => {
+                    if !valid_id(&id) || cx.genes.get(&id).is_some() {
+                        return Err(Error::GeneInvalidId);
+                    }
+                    let hash: Hash =
+                        hex::FromHex::from_hex(&hash).map_err(|_| Error::GeneInvalidId)?;
+
+                    // Fail early on modules that don't compile.
+                    let bytes = load(cx.db, cx.uid, &hash).await?;
+                    Module::validate(&Engine::default(), &bytes[..])
+                        .map_err(|_| Error::GeneWasm)?;
+
+                    // Ids are kept by their first owner.
+                    let result = sqlx::query(
+                        "INSERT INTO wasm_genes (id, uid, hash, description) VALUES ($1, $2, $3, $4)
+                         ON CONFLICT (id) DO UPDATE SET hash = $3, description = $4
+                         WHERE wasm_genes.uid = $2",
+                    )
+                    .bind(&id)
+                    .bind(&cx.uid.0[..])
+                    .bind(&hash[..])
+                    .bind(description.unwrap_or_default())
+                    .execute(&cx.db.crdb)
+                    .await?;
+                    if result.rows_affected() == 0 {
+                        return Err(Error::GeneInvalidId);
+                    }
+                    Ok("{}".into())
+                }
+
+                Request::Drop { id } => {
+                    let result = sqlx::query("DELETE FROM wasm_genes WHERE id = $1 AND uid = $2")
+                        .bind(&id)
+                        .bind(&cx.uid.0[..])
+                        .execute(&cx.db.crdb)
+                        .await?;
+                    if result.rows_affected() == 0 {
+                        return Err(Error::GeneInvalidId);
+                    }
+                    Ok("{}".into())
+                }
+            }
+        })
+    }
+}
+
+/// Like "name_1", in lowercase.
+fn valid_id(id: &str) -> bool {
+    match id.rsplit_once('_') {
+        Some((name, version)) => {
+            !name.is_empty()
+                && name
+                    .bytes()
+                    .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+                && version.parse::<usize>().is_ok()
+        }
+        None => false,
+    }
+}
+
+/// Bytes of the module meme, public or owned by uid.
+async fn load(db: &Database, uid: &Id, hash: &Hash) -> Result<Vec<u8>> {
+    let row = sqlx::query(
+        "SELECT oid, size FROM meme_meta WHERE hash = $1 AND (pub = true OR uid = $2) LIMIT 1",
+    )
+    .bind(&hash[..])
+    .bind(&uid.0[..])
+    .fetch_optional(&db.crdb)
+    .await?
+    .ok_or(Error::MemeNotFound)?;
+    let oid: Vec<u8> = row.get("oid");
+    let size: i64 = row.get("size");
+    if size as usize > MODULE_MAX {
+        return Err(Error::GeneWasm);
+    }
+    let object = db.mr.get_object(hex::encode(&oid)).await?;
+    Ok(object.bytes().to_vec())
+}
+
+/// Compiled modules and prices, shared by WASM genes.
+pub struct Runtime {
+    engine: Engine,
+    modules: Mutex<HashMap<Hash, Arc<Module>>>,
+    fuel_cost: i64,
+    memory_cost: i64,
+    memory_max: usize,
+    space_cost: i64,
+    traffic_cost: i64,
+}
+
+impl Runtime {
+    pub fn new(c: &Config) -> Runtime {
+        let mut config = wasmi::Config::default();
+        config.consume_fuel(true);
+        Runtime {
+            engine: Engine::new(&config),
+            modules: Mutex::new(HashMap::new()),
+            fuel_cost: c.wasm_fuel_cost.max(1),
+            memory_cost: c.wasm_memory_cost,
+            memory_max: c.wasm_memory_max,
+            space_cost: c.space_cost_doc,
+            traffic_cost: c.traffic_cost,
+        }
+    }
+
+    /// Registered gene by id.
+    pub async fn gene(&self, db: &Database, id: &str) -> Result<Option<WasmGene<'_>>> {
+        let row = sqlx::query("SELECT uid, hash, description FROM wasm_genes WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&db.crdb)
+            .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let uid: Vec<u8> = row.get("uid");
+        let hash: Vec<u8> = row.get("hash");
+        Ok(Some(WasmGene {
+            runtime: self,
+            id: id.to_string(),
+            owner: Id::try_from(uid)?,
+            hash: hash.try_into().map_err(|_| Error::GeneWasm)?,
+            description: row.get("description"),
+        }))
+    }
+
+    /// Compile once per hash.
+    async fn module(&self, db: &Database, owner: &Id, hash: &Hash) -> Result<Arc<Module>> {
+        let cached = self.modules.lock().unwrap().get(hash).cloned();
+        if let Some(module) = cached {
+            return Ok(module);
+        }
+        let bytes = load(db, owner, hash).await?;
+        let module = Module::new(&self.engine, &bytes[..]).map_err(|_| Error::GeneWasm)?;
+        let module = Arc::new(module);
+        self.modules.lock().unwrap().insert(*hash, module.clone());
+        Ok(module)
+    }
+}
+
+/// A gene registered by a user.
+pub struct WasmGene<'a> {
+    runtime: &'a Runtime,
+    id: String,
+    owner: Id,
+    hash: Hash,
+    description: String,
+}
+
+impl Gene for WasmGene<'_> {
+    fn meta(&self) -> GeneMeta {
+        let (name, version) = self.id.rsplit_once('_').unwrap_or((&self.id, "0"));
+        GeneMeta::new(name, version.parse().unwrap_or(0), &self.description)
+    }
+
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn call<'a>(&'a self, cx: Context<'a>) -> GeneFuture<'a> {
+        Box::pin(async move {
+            let runtime = self.runtime;
+            let module = runtime.module(cx.db, &self.owner, &self.hash).await?;
+            let memory = match runtime.memory_cost {
+                0 => runtime.memory_max,
+                cost => (cx.changes.space / cost).max(0) as usize * PAGE,
+            }
+            .min(runtime.memory_max);
+            let host = Host {
+                uid: *cx.uid,
+                changes: *cx.changes,
+                deadline: cx.deadline,
+                db: cx.db,
+                meme: cx.meme,
+                space_cost: runtime.space_cost,
+                traffic_cost: runtime.traffic_cost,
+                memory,
+                pages: 0,
+                limits: StoreLimitsBuilder::new()
+                    .memory_size(memory)
+                    .instances(1)
+                    .build(),
+                handle: Handle::current(),
+                error: None,
+            };
+            let engine = runtime.engine.clone();
+            let arg = cx.arg.to_string();
+            let (fuel_cost, memory_cost) = (runtime.fuel_cost, runtime.memory_cost);
+
+            // Host functions block on the database, so off the async workers.
+            let (result, changes) = tokio::task::spawn_blocking(move || {
+                run(&engine, &module, host, &arg, fuel_cost, memory_cost)
+            })
+            .await
+            .map_err(|_| Error::GeneWasm)?;
+            *cx.changes = changes;
+            result
+        })
+    }
+}
+
+struct Host {
+    uid: Id,
+    changes: Costs,
+    deadline: Instant,
+    db: &'static Database,
+    meme: &'static Meme,
+    space_cost: i64,
+    traffic_cost: i64,
+    /// Largest memory in bytes.
+    memory: usize,
+    /// Pages of memory reached.
+    pages: usize,
+    limits: StoreLimits,
+    handle: Handle,
+    /// Why a host function trapped.
+    error: Option<Error>,
+}
+
+/// Call the module with arg, return the result and the changes left.
+fn run(
+    engine: &Engine,
+    module: &Module,
+    host: Host,
+    arg: &str,
+    fuel_cost: i64,
+    memory_cost: i64,
+) -> (Result<String>, Costs) {
+    let fuel = fuel_of(host.changes.time, fuel_cost);
+    let mut store = Store::new(engine, host);
+    store.limiter(|host| &mut host.limits);
+    let result = match store.set_fuel(fuel) {
+        Ok(()) => exec(engine, module, &mut store, arg),
+        Err(_) => Err(Error::GeneWasm),
+    };
+
+    // Fuel is paid from time, and the memory reached from space.
+    let used = fuel - store.get_fuel().unwrap_or(0);
+    let host = store.data();
+    let mut changes = host.changes;
+    changes.time -= time_of(used, fuel_cost);
+    changes.space -= host.pages as i64 * memory_cost;
+    let result = match result {
+        Ok(_) if changes.time < 0 => Err(Error::CostTime),
+        Ok(_) if changes.space < 0 => Err(Error::CostSpace),
+        result => result,
+    };
+    (result, changes)
+}
+
+/// Fuel that time buys, saturating instead of overflowing.
+fn fuel_of(time: i64, fuel_cost: i64) -> u64 {
+    (time.max(0).saturating_mul(1000) / fuel_cost.max(1)) as u64
+}
+
+/// Time that used fuel costs, rounded up, saturating instead of overflowing.
+fn time_of(used: u64, fuel_cost: i64) -> i64 {
+    let used = i64::try_from(used).unwrap_or(i64::MAX);
+    used.saturating_mul(fuel_cost).saturating_add(999) / 1000
+}
+
+fn exec(engine: &Engine, module: &Module, store: &mut Store<Host>, arg: &str) -> Result<String> {
+    let instance = linker(engine)?
+        .instantiate(&mut *store, module)
+        .and_then(|pre| pre.start(&mut *store))
+        .map_err(|_| Error::GeneWasm)?;
+    let memory = instance
+        .get_memory(&*store, "memory")
+        .ok_or(Error::GeneWasm)?;
+    let alloc = instance
+        .get_typed_func::<i32, i32>(&*store, "alloc")
+        .map_err(|_| Error::GeneWasm)?;
+    let call = instance
+        .get_typed_func::<(i32, i32), i64>(&*store, "call")
+        .map_err(|_| Error::GeneWasm)?;
+
+    let len = i32::try_from(arg.len()).map_err(|_| Error::GeneWasm)?;
+    let packed = alloc.call(&mut *store, len).and_then(|ptr| {
+        memory.write(&mut *store, ptr as u32 as usize, arg.as_bytes())?;
+        call.call(&mut *store, (ptr, len))
+    });
+    store.data_mut().pages = memory.data(&*store).len().div_ceil(PAGE);
+    let packed = packed.map_err(|error| match store.data_mut().error.take() {
+        Some(error) => error,
+        None if error.as_trap_code() == Some(TrapCode::OutOfFuel) => Error::CostTime,
+        None => Error::GeneWasm,
+    })?;
+    let bytes = read(memory, &*store, packed)?;
+    String::from_utf8(bytes).map_err(|_| Error::GeneWasm)
+}
+
+/// Bytes at ptr << 32 | len.
+fn read(memory: Memory, store: impl wasmi::AsContext, packed: i64) -> Result<Vec<u8>> {
+    let (ptr, len) = ((packed >> 32) as u32 as usize, packed as u32 as usize);
+    let mut bytes = vec![0; len];
+    memory
+        .read(store, ptr, &mut bytes)
+        .map_err(|_| Error::GeneWasm)?;
+    Ok(bytes)
+}
+
+/// Copy bytes into the guest by its alloc, return ptr << 32 | len.
+fn give(caller: &mut Caller<'_, Host>, bytes: &[u8]) -> std::result::Result<i64, wasmi::Error> {
+    let len = i32::try_from(bytes.len()).map_err(|_| trap(caller, Error::GeneWasm))?;
+    let alloc = caller
+        .get_export("alloc")
+        .and_then(Extern::into_func)
+        .ok_or_else(|| trap(caller, Error::GeneWasm))?
+        .typed::<i32, i32>(&*caller)?;
+    let ptr = alloc.call(&mut *caller, len)?;
+    memory(caller)?.write(&mut *caller, ptr as u32 as usize, bytes)?;
+    Ok(((ptr as u32 as i64) << 32) | len as u32 as i64)
+}
+
+fn memory(caller: &mut Caller<'_, Host>) -> std::result::Result<Memory, wasmi::Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| trap(caller, Error::GeneWasm))
+}
+
+/// Keep error for the reply, and trap.
+fn trap(caller: &mut Caller<'_, Host>, error: Error) -> wasmi::Error {
+    let message = error.to_string();
+    caller.data_mut().error = Some(error);
+    wasmi::Error::new(message)
+}
+
+/// Host functions in "voxov".
+fn linker(engine: &Engine) -> Result<Linker<Host>> {
+    let mut linker = Linker::new(engine);
+    linker
+        .func_wrap(
+            "voxov",
+            "map_get",
+            |caller: Caller<'_, Host>, ptr: i32, len: i32| map_call(caller, ptr, len, "Get"),
+        )
+        .and_then(|linker| {
+            linker.func_wrap(
+                "voxov",
+                "map_put",
+                |caller: Caller<'_, Host>, ptr: i32, len: i32| map_call(caller, ptr, len, "Put"),
+            )
+        })
+        .and_then(|linker| linker.func_wrap("voxov", "meme_get", meme_get))
+        .map_err(|_| Error::GeneWasm)?;
+    Ok(linker)
+}
+
+/// Call map_1 with the request at ptr, as _type.
+fn map_call(
+    mut caller: Caller<'_, Host>,
+    ptr: i32,
+    len: i32,
+    _type: &str,
+) -> std::result::Result<i64, wasmi::Error> {
+    let packed = ((ptr as u32 as i64) << 32) | len as u32 as i64;
+    let bytes = read(memory(&mut caller)?, &caller, packed).map_err(|e| trap(&mut caller, e))?;
+    let mut request: Value = match serde_json::from_slice(&bytes) {
+        Ok(Value::Object(request)) => Value::Object(request),
+        _ => return Err(trap(&mut caller, Error::GeneWasm)),
+    };
+    request["_type"] = _type.into();
+    let arg = request.to_string();
+
+    let host = caller.data();
+    let mut changes = host.changes;
+    let cx = map::V1Context {
+        uid: &host.uid,
+        arg: &arg,
+        changes: &mut changes,
+        _deadline: host.deadline,
+        space_cost: host.space_cost,
+        traffic_cost: host.traffic_cost,
+        db: host.db,
+    };
+    let result = host.handle.block_on(map::v1(cx, false));
+    caller.data_mut().changes = changes;
+    match result {
+        Ok(result) => give(&mut caller, result.as_bytes()),
+        Err(error) => Err(trap(&mut caller, error)),
+    }
+}
+
+/// Read the meme by the hash at ptr.
+fn meme_get(
+    mut caller: Caller<'_, Host>,
+    ptr: i32,
+    public: i32,
+) -> std::result::Result<i64, wasmi::Error> {
+    let packed = ((ptr as u32 as i64) << 32) | 32;
+    let bytes = read(memory(&mut caller)?, &caller, packed).map_err(|e| trap(&mut caller, e))?;
+    let hash: Hash = bytes.try_into().unwrap();
+
+    let host = caller.data();
+    let mut changes = host.changes;
+    let result = host.handle.block_on(host.meme.read_meme(
+        &host.uid,
+        &mut changes,
+        host.deadline,
+        hash,
+        public != 0,
+        host.memory,
+    ));
+    caller.data_mut().changes = changes;
+    match result {
+        Ok(data) => give(&mut caller, &data),
+        Err(error) => Err(trap(&mut caller, error)),
+    }
+}
+
+#[test]
+fn test_fuel() {
+    assert_eq!(fuel_of(-1, 10), 0);
+    assert_eq!(fuel_of(1, 10), 100);
+    assert_eq!(fuel_of(i64::MAX, 1), i64::MAX as u64);
+    assert_eq!(fuel_of(i64::MAX, 1000), i64::MAX as u64 / 1000);
+    assert_eq!(time_of(100, 10), 1);
+    assert_eq!(time_of(101, 10), 2);
+    assert_eq!(time_of(u64::MAX, 1000), i64::MAX / 1000);
+    let fuel = fuel_of(i64::MAX, 7);
+    assert!(time_of(fuel, 7) <= i64::MAX / 1000);
+}
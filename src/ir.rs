@@ -3,14 +3,17 @@
 
 pub type Hash = [u8; 32]; // BLAKE3
 
+pub mod access;
 pub mod id;
 pub mod query;
 pub mod reply;
 
+pub use access::Access;
 pub use id::{IDL, Id};
 pub use query::Query;
 pub use reply::Reply;
 
+use crate::fed::sign::FedSig;
 use crate::{Error, Result};
 use hex::FromHex;
 use hyper::{Request, body::Incoming};
@@ -26,9 +29,11 @@ pub struct Costs {
 
 #[derive(Debug)]
 pub struct Head {
-    pub access: Id,
+    pub access: Access,
     pub costs: Costs,
     pub fed: Option<Id>,
+    /// Set when a peer instance forwarded the query.
+    pub sig: Option<FedSig>,
 }
 
 impl Costs {
@@ -51,9 +56,10 @@ impl Costs {
 impl Head {
     pub fn try_get(req: &Request<Incoming>) -> Result<Self> {
         Ok(Head {
-            access: Id::try_get(req, "access")?,
+            access: Access::try_get(req, "access")?,
             costs: Costs::try_get(req)?,
             fed: Id::opt(req, "fed"),
+            sig: FedSig::opt(req),
         })
     }
 }
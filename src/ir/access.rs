@@ -0,0 +1,46 @@
+use super::{Id, Query};
+use crate::{Error, Result};
+use core::fmt;
+use hyper::{Request, body::Incoming};
+use std::str::FromStr;
+
+/// Access token, either a random id looked up in ScyllaDB,
+/// or a self-contained token signed by the server.
+#[derive(Debug, Clone)]
+pub enum Access {
+    Opaque(Id),
+    Signed(String),
+}
+
+impl FromStr for Access {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        if s.contains('.') {
+            Ok(Access::Signed(s.to_string()))
+        } else {
+            Ok(Access::Opaque(Id::from_str(s)?))
+        }
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::Opaque(id) => write!(f, "{}", id),
+            Access::Signed(token) => write!(f, "{}", token),
+        }
+    }
+}
+
+impl Access {
+    /// The id stored in ScyllaDB, None for signed tokens.
+    pub fn opaque(&self) -> Option<&Id> {
+        match self {
+            Access::Opaque(id) => Some(id),
+            Access::Signed(_) => None,
+        }
+    }
+    pub fn try_get(req: &Request<Incoming>, key: &str) -> Result<Self> {
+        Access::from_str(Query::retrieve(req, key)?)
+    }
+}
@@ -11,7 +11,7 @@ use std::str::FromStr;
 pub const IDL: usize = 16;
 const ID0: [u8; IDL] = [0_u8; IDL];
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Id(pub [u8; IDL]);
 
 impl FromStr for Id {
@@ -45,12 +45,6 @@ impl TryFrom<Vec<u8>> for Id {
     }
 }
 
-impl PartialEq for Id {
-    fn eq(&self, other: &Self) -> bool {
-        self.0 == other.0
-    }
-}
-
 impl Id {
     pub fn zero() -> Self {
         Id(ID0)
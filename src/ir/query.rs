@@ -1,40 +1,101 @@
-use super::{Costs, Hash, Head, Id, try_get, try_get_hash};
+use super::{Access, Costs, Hash, Head, Id, try_get, try_get_hash};
+use crate::fed::sign::FedSig;
 use crate::{Error, Result};
 use hyper::{Request, body::Incoming};
 use std::pin::Pin;
+use strum_macros::IntoStaticStr;
 
 type OptionId = Option<Id>;
+type OptionString = Option<String>;
 
 pub type QueryBody = Pin<Box<Incoming>>;
 
-#[derive(Debug)]
+#[derive(Debug, IntoStaticStr)]
 pub enum Query {
     AuthSessionStart,
     AuthSessionRefresh {
         refresh: Id,
+        scope: OptionString,
     },
     AuthSessionEnd {
-        access: Id,
+        access: Access,
         option_refresh: OptionId,
     },
+    AuthSessionList {
+        access: Access,
+    },
+    AuthSessionRevoke {
+        access: Access,
+        handle: String,
+        totp: OptionString,
+    },
+    AuthSessionRevokeAll {
+        access: Access,
+        totp: OptionString,
+    },
+    AuthSessionAnonymous {
+        access: Access,
+        refresh: Id,
+        label: OptionString,
+    },
     AuthSmsSendTo {
-        access: Id,
+        access: Access,
     },
     AuthSmsSent {
-        access: Id,
+        access: Access,
         refresh: Id,
         phone: String,
         message: Id,
+        totp: OptionString,
+        label: OptionString,
+    },
+    AuthPhoneChange {
+        access: Access,
+        phone: String,
+        message: Id,
+        totp: OptionString,
+    },
+    AuthEmailStart {
+        access: Access,
+        email: String,
+    },
+    AuthEmailVerify {
+        access: Access,
+        refresh: Id,
+        token: Id,
+        code: OptionId,
+        totp: OptionString,
+        label: OptionString,
+    },
+    AuthAccountDelete {
+        access: Access,
+        totp: OptionString,
+    },
+    AuthAccountDeleteCancel {
+        access: Access,
+    },
+    AuthTotpEnroll {
+        access: Access,
+        totp: OptionString,
+    },
+    AuthTotpVerify {
+        access: Access,
+        code: String,
+    },
+    AuthTotpDisable {
+        access: Access,
+        code: String,
     },
     CostPay {
-        access: Id,
+        access: Access,
         vendor: Id,
+        totp: OptionString,
     },
     CostGet {
-        access: Id,
+        access: Access,
     },
     CostCheckIn {
-        access: Id,
+        access: Access,
     },
     GeneMeta {
         head: Head,
@@ -45,6 +106,11 @@ pub enum Query {
         gid: String,
         arg: String,
     },
+    GeneSubscribe {
+        head: Head,
+        gid: String,
+        arg: String,
+    },
     MemeMeta {
         head: Head,
         hash: Hash,
@@ -59,12 +125,28 @@ pub enum Query {
         hash: Hash,
         public: bool,
     },
-    //TODO: FedMemeClone, FedMemeVisa, FedCreditClaim
+    FedCreditClaim {
+        head: Head,
+        amount: i64,
+        rate: f64,
+    },
+    FedMemeClone {
+        head: Head,
+        hash: Hash,
+        days: u64,
+        public: bool,
+    },
+    FedMemeVisa {
+        head: Head,
+        hash: Hash,
+        peer: Id,
+        ttl: i64,
+    },
 }
 
 impl Query {
     /// Get the access token from query
-    pub fn get_access(&self) -> &Id {
+    pub fn get_access(&self) -> &Access {
         match self {
             Query::CostPay { access, .. } => access,
             Query::CostGet { access } => access,
@@ -74,9 +156,20 @@ impl Query {
             Query::MemeGet { head, .. } => &head.access,
             Query::GeneMeta { head, .. } => &head.access,
             Query::GeneCall { head, .. } => &head.access,
+            Query::GeneSubscribe { head, .. } => &head.access,
+            Query::FedCreditClaim { head, .. } => &head.access,
+            Query::FedMemeClone { head, .. } => &head.access,
+            Query::FedMemeVisa { head, .. } => &head.access,
             _ => panic!("Query not passed through Auth: {:?}", self),
         }
     }
+    /// Get the TOTP code of sensitive queries, None if not sensitive
+    pub fn get_totp(&self) -> Option<&OptionString> {
+        match self {
+            Query::CostPay { totp, .. } => Some(totp),
+            _ => None,
+        }
+    }
     /// Get the cost struct from query
     pub fn get_costs(&self) -> Costs {
         match self {
@@ -85,6 +178,10 @@ impl Query {
             Query::MemeGet { head, .. } => head.costs,
             Query::GeneMeta { head, .. } => head.costs,
             Query::GeneCall { head, .. } => head.costs,
+            Query::GeneSubscribe { head, .. } => head.costs,
+            Query::FedCreditClaim { head, .. } => head.costs,
+            Query::FedMemeClone { head, .. } => head.costs,
+            Query::FedMemeVisa { head, .. } => head.costs,
             _ => panic!("Query not passed through Cost: {:?}", self),
         }
     }
@@ -94,6 +191,18 @@ impl Query {
             Query::MemeMeta { head, .. } => &head.fed,
             Query::GeneMeta { head, .. } => &head.fed,
             Query::GeneCall { head, .. } => &head.fed,
+            Query::GeneSubscribe { head, .. } => &head.fed,
+            _ => &None,
+        }
+    }
+    /// Get the signature of a query forwarded by a peer
+    pub fn get_sig(&self) -> &Option<FedSig> {
+        match self {
+            Query::MemeMeta { head, .. } => &head.sig,
+            Query::GeneMeta { head, .. } => &head.sig,
+            Query::GeneCall { head, .. } => &head.sig,
+            Query::FedCreditClaim { head, .. } => &head.sig,
+            Query::MemeGet { head, .. } => &head.sig,
             _ => &None,
         }
     }
@@ -117,29 +226,87 @@ impl TryFrom<Request<Incoming>> for Query {
                 "AuthSessionStart" => Ok(Query::AuthSessionStart),
                 "AuthSessionRefresh" => Ok(Query::AuthSessionRefresh {
                     refresh: Id::try_get(&req, "refresh")?,
+                    scope: opt(&req, "scope"),
                 }),
                 "AuthSessionEnd" => Ok(Query::AuthSessionEnd {
-                    access: Id::try_get(&req, "access")?,
+                    access: Access::try_get(&req, "access")?,
                     option_refresh: Id::opt(&req, "refresh"),
                 }),
+                "AuthSessionList" => Ok(Query::AuthSessionList {
+                    access: Access::try_get(&req, "access")?,
+                }),
+                "AuthSessionRevoke" => Ok(Query::AuthSessionRevoke {
+                    access: Access::try_get(&req, "access")?,
+                    handle: Query::retrieve(&req, "handle")?.to_string(),
+                    totp: opt(&req, "totp"),
+                }),
+                "AuthSessionRevokeAll" => Ok(Query::AuthSessionRevokeAll {
+                    access: Access::try_get(&req, "access")?,
+                    totp: opt(&req, "totp"),
+                }),
+                "AuthSessionAnonymous" => Ok(Query::AuthSessionAnonymous {
+                    access: Access::try_get(&req, "access")?,
+                    refresh: Id::try_get(&req, "refresh")?,
+                    label: opt(&req, "label"),
+                }),
                 "AuthSmsSendTo" => Ok(Query::AuthSmsSendTo {
-                    access: Id::try_get(&req, "access")?,
+                    access: Access::try_get(&req, "access")?,
                 }),
                 "AuthSmsSent" => Ok(Query::AuthSmsSent {
-                    access: Id::try_get(&req, "access")?,
+                    access: Access::try_get(&req, "access")?,
                     refresh: Id::try_get(&req, "refresh")?,
                     phone: Query::retrieve(&req, "phone")?.to_string(),
                     message: Id::try_get(&req, "message")?,
+                    totp: opt(&req, "totp"),
+                    label: opt(&req, "label"),
+                }),
+                "AuthPhoneChange" => Ok(Query::AuthPhoneChange {
+                    access: Access::try_get(&req, "access")?,
+                    phone: Query::retrieve(&req, "phone")?.to_string(),
+                    message: Id::try_get(&req, "message")?,
+                    totp: opt(&req, "totp"),
+                }),
+                "AuthEmailStart" => Ok(Query::AuthEmailStart {
+                    access: Access::try_get(&req, "access")?,
+                    email: Query::retrieve(&req, "email")?.to_string(),
+                }),
+                "AuthEmailVerify" => Ok(Query::AuthEmailVerify {
+                    access: Access::try_get(&req, "access")?,
+                    refresh: Id::try_get(&req, "refresh")?,
+                    token: Id::try_get(&req, "token")?,
+                    code: Id::opt(&req, "code"),
+                    totp: opt(&req, "totp"),
+                    label: opt(&req, "label"),
+                }),
+                "AuthAccountDelete" => Ok(Query::AuthAccountDelete {
+                    access: Access::try_get(&req, "access")?,
+                    totp: opt(&req, "totp"),
+                }),
+                "AuthAccountDeleteCancel" => Ok(Query::AuthAccountDeleteCancel {
+                    access: Access::try_get(&req, "access")?,
+                }),
+                "AuthTotpEnroll" => Ok(Query::AuthTotpEnroll {
+                    access: Access::try_get(&req, "access")?,
+                    totp: opt(&req, "totp"),
+                }),
+                "AuthTotpVerify" => Ok(Query::AuthTotpVerify {
+                    access: Access::try_get(&req, "access")?,
+                    code: Query::retrieve(&req, "code")?.to_string(),
+                }),
+                "AuthTotpDisable" => Ok(Query::AuthTotpDisable {
+                    access: Access::try_get(&req, "access")?,
+                    code: Query::retrieve(&req, "code")?.to_string(),
                 }),
                 "CostPay" => Ok(Query::CostPay {
-                    access: Id::try_get(&req, "access")?,
+                    access: Access::try_get(&req, "access")?,
                     vendor: Id::try_get(&req, "vendor")?,
+                    totp: opt(&req, "totp"),
                 }),
                 "CostGet" => Ok(Query::CostGet {
-                    access: Id::try_get(&req, "access")?,
+                    access: Access::try_get(&req, "access")?,
                 }),
                 "CostCheckIn" => Ok(Query::CostCheckIn {
-                    access: Id::try_get(&req, "access")?,
+                    access: Access::try_get(&req, "access")?,
                 }),
                 "GeneMeta" => Ok(Query::GeneMeta {
                     head: Head::try_get(&req)?,
@@ -150,6 +317,11 @@ impl TryFrom<Request<Incoming>> for Query {
                     gid: try_get(&req, "gid")?,
                     arg: try_get(&req, "arg")?,
                 }),
+                "GeneSubscribe" => Ok(Query::GeneSubscribe {
+                    head: Head::try_get(&req)?,
+                    gid: try_get(&req, "gid")?,
+                    arg: try_get(&req, "arg")?,
+                }),
                 "MemeMeta" => Ok(Query::MemeMeta {
                     head: Head::try_get(&req)?,
                     hash: try_get_hash(&req)?,
@@ -164,9 +336,31 @@ impl TryFrom<Request<Incoming>> for Query {
                     hash: try_get_hash(&req)?,
                     public: try_get::<bool>(&req, "public")?,
                 }),
+                "FedCreditClaim" => Ok(Query::FedCreditClaim {
+                    head: Head::try_get(&req)?,
+                    amount: try_get::<i64>(&req, "amount")?,
+                    rate: try_get::<f64>(&req, "rate")?,
+                }),
+                "FedMemeClone" => Ok(Query::FedMemeClone {
+                    head: Head::try_get(&req)?,
+                    hash: try_get_hash(&req)?,
+                    days: try_get::<u64>(&req, "days")?,
+                    public: try_get::<bool>(&req, "public")?,
+                }),
+                "FedMemeVisa" => Ok(Query::FedMemeVisa {
+                    head: Head::try_get(&req)?,
+                    hash: try_get_hash(&req)?,
+                    peer: Id::try_get(&req, "peer")?,
+                    ttl: try_get::<i64>(&req, "ttl")?,
+                }),
                 _ => Err(Error::ApiUnknownQueryType),
             },
             Err(_) => Err(Error::ApiMissingQueryType),
         }
     }
 }
+
+/// Optional string entry
+fn opt(req: &Request<Incoming>, key: &str) -> OptionString {
+    Query::retrieve(req, key).ok().map(String::from)
+}
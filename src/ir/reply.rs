@@ -1,8 +1,9 @@
-use super::{Costs, Hash, Id};
+use super::{Access, Costs, Hash, Id};
 use crate::Error;
 use crate::api::{empty, full};
 use crate::body::ResponseBody as RB;
-use crate::body::S3StreamItem;
+use crate::body::{FedStream, GeneStream, S3StreamItem};
+use chrono::{DateTime, Utc};
 use http::response::Builder;
 use http_body_util::StreamBody;
 use hyper::{Response, StatusCode};
@@ -13,23 +14,122 @@ use std::pin::Pin;
 type BoxS3Stream = Pin<Box<ResponseDataStream>>;
 
 pub enum Reply {
-    Error { error: Error },
-    AuthSessionStart { access: Id, refresh: Id },
-    AuthSessionRefresh { access: Id },
+    Error {
+        error: Error,
+    },
+    AuthSessionStart {
+        access: Access,
+        refresh: Id,
+    },
+    AuthSessionRefresh {
+        access: Access,
+    },
     AuthSessionEnd,
-    AuthSmsSendTo { phone: &'static str, message: Id },
-    AuthSmsSent { uid: Id },
-    CostPay { uri: String },
-    CostGet { credit: i64 },
-    CostCheckIn { award: i64 },
-    GeneMeta { changes: Costs, meta: String },
-    GeneCall { changes: Costs, result: String },
-    MemeMeta { changes: Costs, meta: String },
-    MemePut { changes: Costs, hash: Hash },
-    MemeGet { changes: Costs, raw: BoxS3Stream },
+    AuthSessionList {
+        sessions: String,
+    },
+    AuthSessionRevoke,
+    AuthSessionRevokeAll,
+    AuthSessionAnonymous {
+        uid: Id,
+    },
+    AuthSmsSendTo {
+        phone: &'static str,
+        message: Id,
+    },
+    AuthSmsSent {
+        uid: Id,
+    },
+    AuthPhoneChange,
+    AuthEmailStart {
+        token: Id,
+    },
+    AuthEmailVerify {
+        uid: Id,
+    },
+    AuthAccountDelete {
+        archive: Hash,
+        due: DateTime<Utc>,
+    },
+    AuthAccountDeleteCancel,
+    AuthTotpEnroll {
+        secret: String,
+        uri: String,
+        recovery: String,
+    },
+    AuthTotpVerify,
+    AuthTotpDisable,
+    CostPay {
+        uri: String,
+    },
+    CostGet {
+        credit: i64,
+    },
+    CostCheckIn {
+        award: i64,
+    },
+    GeneMeta {
+        changes: Costs,
+        meta: String,
+    },
+    GeneCall {
+        changes: Costs,
+        result: String,
+    },
+    /// Server-sent events, until the feed ends.
+    GeneSubscribe {
+        changes: Costs,
+        raw: GeneStream,
+    },
+    MemeMeta {
+        changes: Costs,
+        meta: String,
+    },
+    MemePut {
+        changes: Costs,
+        hash: Hash,
+    },
+    MemeGet {
+        changes: Costs,
+        raw: BoxS3Stream,
+    },
+    /// Reply of a peer instance, passed through.
+    Fed {
+        kind: String,
+        changes: Costs,
+        raw: FedStream,
+    },
+    FedCreditClaim {
+        changes: Costs,
+        credit: i64,
+    },
+    FedMemeClone {
+        changes: Costs,
+        hash: Hash,
+    },
+    FedMemeVisa {
+        changes: Costs,
+    },
 }
 
 impl Reply {
+    /// Get the remaining costs of a paid reply.
+    pub fn get_changes(&self) -> Option<Costs> {
+        match self {
+            Reply::GeneMeta { changes, .. }
+            | Reply::GeneCall { changes, .. }
+            | Reply::GeneSubscribe { changes, .. }
+            | Reply::MemeMeta { changes, .. }
+            | Reply::MemePut { changes, .. }
+            | Reply::MemeGet { changes, .. }
+            | Reply::Fed { changes, .. }
+            | Reply::FedCreditClaim { changes, .. }
+            | Reply::FedMemeClone { changes, .. }
+            | Reply::FedMemeVisa { changes } => Some(*changes),
+            _ => None,
+        }
+    }
+
     pub fn to_response(self) -> Response<RB> {
         fn response_changes(changes: Costs) -> Builder {
             Response::builder()
@@ -55,7 +155,39 @@ impl Reply {
                 .unwrap();
         }
 
+        if let Reply::Fed { kind, changes, raw } = self {
+            return response_changes(changes)
+                .header("type", kind)
+                .body(RB::FedStream(StreamBody::new(raw)))
+                .unwrap();
+        }
+
         match self {
+            Reply::Error {
+                error: Error::LimitExceeded(retry_after),
+            } => Response::builder()
+                .header("type", "Error")
+                .header("error", "LimitExceeded")
+                .header("retry-after", retry_after.to_string())
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .body(empty())
+                .unwrap(),
+            Reply::Error {
+                error: Error::GeneMapSchema(message),
+            } => Response::builder()
+                .header("type", "Error")
+                .header("error", "GeneMapSchema")
+                .status(StatusCode::UNPROCESSABLE_ENTITY)
+                .body(full(message))
+                .unwrap(),
+            Reply::Error {
+                error: Error::FedPeer(error),
+            } => Response::builder()
+                .header("type", "Error")
+                .header("error", error)
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(empty())
+                .unwrap(),
             Reply::Error { error } => Response::builder()
                 .header("type", "Error")
                 .header("error", error.to_string())
@@ -77,6 +209,23 @@ impl Reply {
                 .header("type", "AuthSessionEnd")
                 .body(empty())
                 .unwrap(),
+            Reply::AuthSessionList { sessions } => Response::builder()
+                .header("type", "AuthSessionList")
+                .body(full(sessions))
+                .unwrap(),
+            Reply::AuthSessionRevoke => Response::builder()
+                .header("type", "AuthSessionRevoke")
+                .body(empty())
+                .unwrap(),
+            Reply::AuthSessionRevokeAll => Response::builder()
+                .header("type", "AuthSessionRevokeAll")
+                .body(empty())
+                .unwrap(),
+            Reply::AuthSessionAnonymous { uid } => Response::builder()
+                .header("type", "AuthSessionAnonymous")
+                .header("uid", uid.to_string())
+                .body(empty())
+                .unwrap(),
             Reply::AuthSmsSendTo { phone, message } => Response::builder()
                 .header("type", "AuthSmsSendTo")
                 .header("phone", phone)
@@ -88,6 +237,49 @@ impl Reply {
                 .header("uid", uid.to_string())
                 .body(empty())
                 .unwrap(),
+            Reply::AuthEmailStart { token } => Response::builder()
+                .header("type", "AuthEmailStart")
+                .header("token", token.to_string())
+                .body(empty())
+                .unwrap(),
+            Reply::AuthEmailVerify { uid } => Response::builder()
+                .header("type", "AuthEmailVerify")
+                .header("uid", uid.to_string())
+                .body(empty())
+                .unwrap(),
+            Reply::AuthPhoneChange => Response::builder()
+                .header("type", "AuthPhoneChange")
+                .body(empty())
+                .unwrap(),
+            Reply::AuthAccountDelete { archive, due } => Response::builder()
+                .header("type", "AuthAccountDelete")
+                .header("archive", hex::encode(archive))
+                .header("due", due.to_rfc3339())
+                .body(empty())
+                .unwrap(),
+            Reply::AuthAccountDeleteCancel => Response::builder()
+                .header("type", "AuthAccountDeleteCancel")
+                .body(empty())
+                .unwrap(),
+            Reply::AuthTotpEnroll {
+                secret,
+                uri,
+                recovery,
+            } => Response::builder()
+                .header("type", "AuthTotpEnroll")
+                .header("secret", secret)
+                .header("uri", uri)
+                .header("recovery", recovery)
+                .body(empty())
+                .unwrap(),
+            Reply::AuthTotpVerify => Response::builder()
+                .header("type", "AuthTotpVerify")
+                .body(empty())
+                .unwrap(),
+            Reply::AuthTotpDisable => Response::builder()
+                .header("type", "AuthTotpDisable")
+                .body(empty())
+                .unwrap(),
             Reply::CostPay { uri } => Response::builder()
                 .header("type", "CostPay")
                 .header("uri", uri)
@@ -111,6 +303,12 @@ impl Reply {
                 .header("type", "GeneCall")
                 .body(full(result))
                 .unwrap(),
+            Reply::GeneSubscribe { changes, raw } => response_changes(changes)
+                .header("type", "GeneSubscribe")
+                .header("content-type", "text/event-stream")
+                .header("cache-control", "no-cache")
+                .body(RB::GeneStream(StreamBody::new(raw)))
+                .unwrap(),
             Reply::MemeMeta { changes, meta } => response_changes(changes)
                 .header("type", "MemeMeta")
                 .body(full(meta))
@@ -120,7 +318,21 @@ impl Reply {
                 .header("hash", hex::encode(hash))
                 .body(empty())
                 .unwrap(),
-            Reply::MemeGet { .. } => unreachable!(),
+            Reply::FedCreditClaim { changes, credit } => response_changes(changes)
+                .header("type", "FedCreditClaim")
+                .header("credit", credit.to_string())
+                .body(empty())
+                .unwrap(),
+            Reply::FedMemeClone { changes, hash } => response_changes(changes)
+                .header("type", "FedMemeClone")
+                .header("hash", hex::encode(hash))
+                .body(empty())
+                .unwrap(),
+            Reply::FedMemeVisa { changes } => response_changes(changes)
+                .header("type", "FedMemeVisa")
+                .body(empty())
+                .unwrap(),
+            Reply::MemeGet { .. } | Reply::Fed { .. } => unreachable!(),
         }
     }
 }
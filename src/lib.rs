@@ -9,6 +9,7 @@ pub mod error;
 pub mod fed;
 pub mod gene;
 pub mod ir;
+pub mod limit;
 pub mod meme;
 
 pub mod macros {
@@ -24,15 +25,28 @@ pub use error::Error;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-pub async fn run() -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+type BoxResult = std::result::Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+pub async fn run() -> BoxResult {
     #[cfg(feature = "console")]
     console_subscriber::init();
 
     // Config: collect ENV to static variables.
     let c = to_static!(config::Config::new());
 
+    serve(c).await
+}
+
+/// Start an instance with config c. Instances may share a process.
+pub async fn serve(c: &'static config::Config) -> BoxResult {
+    serve_with(c, gene::Registry::builtin(c)).await
+}
+
+/// Start an instance with the genes of a registry, like the builtin ones and more.
+pub async fn serve_with(c: &'static config::Config, genes: gene::Registry) -> BoxResult {
     // Database: stateless database struct.
-    let db = to_static!(database::Database::new(c, true).await);
+    let db: &'static database::Database = to_static!(database::Database::new(c, true).await);
+    tokio::spawn(db.poll_access_revocations());
 
     // Ripperd: remove EOL data.
     use database::ripperd::Ripperd;
@@ -43,19 +57,26 @@ pub async fn run() -> std::result::Result<(), Box<dyn std::error::Error + Send +
     let meme = to_static!(meme::Meme::new(c, db));
 
     // Gene: function primitives.
-    let gene = to_static!(gene::Gene::new(c, db, meme));
+    let gene = to_static!(gene::Genes::new(c, db, meme, genes));
 
     // Fed: call other instances.
-    let fed = to_static!(fed::Fed::new(c, gene));
+    let fed: &'static fed::Fed = to_static!(fed::Fed::new(c, db, meme, gene));
+    tokio::spawn(fed.settle());
 
     // Cost: set limit on time, space, traffic and tip.
     let cost = to_static!(cost::Cost::new(c, db, fed));
 
+    // Limiter: token buckets per IP, uid and query type.
+    let limiter = to_static!(limit::Limiter::new(c, db)?);
+
     // Auth: OAuth 2.0 style authentication.
-    let auth = to_static!(auth::Auth::new(c, db, cost));
+    let auth = to_static!(auth::Auth::new(c, db, cost, limiter));
+
+    // Limit: throttle clients before authentication.
+    let limit = to_static!(limit::Limit::new(auth, limiter));
 
     // API: GraphQL & plain http.
-    let api: &'static api::Api = to_static!(api::Api::new(c, auth));
+    let api: &'static api::Api = to_static!(api::Api::new(c, auth, limit));
 
     // Open endpoints.
     api.serve().await
@@ -0,0 +1,263 @@
+//! Rate limiting per client IP, per uid and per query type.
+//!
+//! Each rule is a token bucket, kept as a GCRA theoretical arrival time,
+//! so a key costs one integer in the store.
+//!
+//! Limits are on by default, see Config. Endpoints outside of queries are
+//! limited by name like queries, SmsWebhook and EmailLink. A test server
+//! hitting them from one address may clear RATE_LIMIT_IP, RATE_LIMIT_UID,
+//! RATE_LIMIT_QUERIES and RATE_LIMIT_ANONYMOUS.
+
+use crate::auth::Auth;
+use crate::config::Config;
+use crate::database::Database;
+use crate::ir::{Id, Query, Reply};
+use crate::{Error, Result};
+use chrono::Utc;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Mutex;
+
+/// Local keys are pruned when there are more than this.
+const LOCAL_MAX_KEYS: usize = 100_000;
+
+/// Attempts before giving up a contended shared key.
+const SHARED_RETRIES: usize = 3;
+
+/// Allow count requests per period seconds, all at once at most.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rule {
+    pub count: u32,
+    pub period: i64,
+}
+
+impl Rule {
+    /// Parse "COUNT/SECONDS", None if empty or zero. More than one per
+    /// millisecond is out of resolution and rejected.
+    pub fn parse(s: &str) -> Result<Option<Rule>> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Ok(None);
+        }
+        let invalid = || Error::LimitRule(s.to_string());
+        let (count, period) = s.split_once('/').ok_or_else(invalid)?;
+        let rule = Rule {
+            count: count.trim().parse().map_err(|_| invalid())?,
+            period: period.trim().parse().map_err(|_| invalid())?,
+        };
+        if rule.count > 0 && rule.period > 0 && rule.interval() == 0 {
+            return Err(invalid());
+        }
+        Ok((rule.count > 0 && rule.period > 0).then_some(rule))
+    }
+
+    /// Milliseconds between two requests in the long run.
+    fn interval(&self) -> i64 {
+        self.period * 1000 / self.count as i64
+    }
+
+    /// GCRA step at now, both in milliseconds. Ok with the new arrival time,
+    /// or Err with milliseconds to wait.
+    pub fn step(&self, tat: Option<i64>, now: i64) -> std::result::Result<i64, i64> {
+        let new_tat = tat.unwrap_or(now).max(now) + self.interval();
+        let ahead = new_tat - now - self.period * 1000;
+        if ahead > 0 { Err(ahead) } else { Ok(new_tat) }
+    }
+}
+
+pub type LimitFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// Where arrival times are kept.
+pub trait LimitStore: Send + Sync {
+    /// Take a token from the bucket of key, or fail with LimitExceeded.
+    fn take<'a>(&'a self, key: &'a str, rule: Rule) -> LimitFuture<'a>;
+}
+
+/// Per instance, limits multiply by the number of instances.
+pub struct Local {
+    tats: Mutex<HashMap<String, i64>>,
+}
+
+impl LimitStore for Local {
+    fn take<'a>(&'a self, key: &'a str, rule: Rule) -> LimitFuture<'a> {
+        Box::pin(async move {
+            let now = Utc::now().timestamp_millis();
+            let mut tats = self.tats.lock().unwrap();
+            if tats.len() > LOCAL_MAX_KEYS {
+                tats.retain(|_, tat| *tat > now);
+            }
+            let new_tat = rule.step(tats.get(key).copied(), now).map_err(exceeded)?;
+            tats.insert(key.to_string(), new_tat);
+            Ok(())
+        })
+    }
+}
+
+/// Shared by all instances through ScyllaDB lightweight transactions.
+pub struct Shared {
+    db: &'static Database,
+}
+
+impl LimitStore for Shared {
+    fn take<'a>(&'a self, key: &'a str, rule: Rule) -> LimitFuture<'a> {
+        Box::pin(async move {
+            for _ in 0..SHARED_RETRIES {
+                let now = Utc::now().timestamp_millis();
+                let tat = self.db.get_rate_tat(key).await?;
+                let new_tat = rule.step(tat, now).map_err(exceeded)?;
+                if self
+                    .db
+                    .set_rate_tat(key, tat, new_tat, rule.period + 1)
+                    .await?
+                {
+                    return Ok(());
+                }
+            }
+            // Too hot to tell, treat as exceeded
+            Err(exceeded(rule.interval()))
+        })
+    }
+}
+
+fn exceeded(millis: i64) -> Error {
+    Error::LimitExceeded((millis as u64).div_ceil(1000))
+}
+
+/// Rules and their store.
+pub struct Limiter {
+    store: Box<dyn LimitStore>,
+    ip: Option<Rule>,
+    uid: Option<Rule>,
+    queries: HashMap<String, Rule>,
+    anonymous: Option<Rule>,
+    totp: Option<Rule>,
+}
+
+impl Limiter {
+    /// Fail with LimitRule on a malformed rule.
+    pub fn new(config: &Config, db: &'static Database) -> Result<Limiter> {
+        let store: Box<dyn LimitStore> = match config.rate_limit_store.as_str() {
+            "scylla" => Box::new(Shared { db }),
+            _ => Box::new(Local {
+                tats: Mutex::new(HashMap::new()),
+            }),
+        };
+        let mut queries = HashMap::new();
+        for entry in config.rate_limit_queries.split(',') {
+            if entry.trim().is_empty() {
+                continue;
+            }
+            let (query, rule) = entry
+                .split_once('=')
+                .ok_or_else(|| Error::LimitRule(entry.trim().to_string()))?;
+            if let Some(rule) = Rule::parse(rule)? {
+                queries.insert(query.trim().to_string(), rule);
+            }
+        }
+        Ok(Limiter {
+            store,
+            ip: Rule::parse(&config.rate_limit_ip)?,
+            uid: Rule::parse(&config.rate_limit_uid)?,
+            queries,
+            anonymous: Rule::parse(&config.rate_limit_anonymous)?,
+            totp: Rule::parse(&config.rate_limit_totp)?,
+        })
+    }
+
+    /// Limit all queries from ip, and each query type from ip.
+    pub async fn check_ip(&self, ip: &IpAddr, query: &str) -> Result<()> {
+        if let Some(rule) = self.ip {
+            self.store.take(&format!("ip:{}", ip), rule).await?;
+        }
+        if let Some(rule) = self.queries.get(query) {
+            self.store
+                .take(&format!("query:{}:{}", query, ip), *rule)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Limit anonymous credit grants to ip.
+    pub async fn check_anonymous(&self, ip: &IpAddr) -> Result<()> {
+        match self.anonymous {
+            Some(rule) => self.store.take(&format!("anon:{}", ip), rule).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Limit second factor attempts on an account, from any ip.
+    pub async fn check_totp(&self, uid: &Id) -> Result<()> {
+        match self.totp {
+            Some(rule) => self.store.take(&format!("totp:{}", uid), rule).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Limit all queries of an authenticated user.
+    pub async fn check_uid(&self, uid: &Id) -> Result<()> {
+        match self.uid {
+            Some(rule) => self.store.take(&format!("uid:{}", uid), rule).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Throttle clients before they reach Auth.
+pub struct Limit {
+    auth: &'static Auth,
+    limiter: &'static Limiter,
+}
+
+impl Limit {
+    pub fn new(auth: &'static Auth, limiter: &'static Limiter) -> Limit {
+        Limit { auth, limiter }
+    }
+
+    /// Limit an endpoint outside of queries, like EmailLink, from ip.
+    pub async fn check(&self, ip: &IpAddr, endpoint: &str) -> Result<()> {
+        self.limiter.check_ip(ip, endpoint).await
+    }
+
+    pub async fn handle(&self, query: Query, ip: &IpAddr) -> Result<Reply> {
+        self.limiter.check_ip(ip, (&query).into()).await?;
+        if let Query::AuthSessionAnonymous { .. } = query {
+            self.limiter.check_anonymous(ip).await?;
+        }
+        self.auth.handle(query).await
+    }
+}
+
+#[test]
+fn test_rule() {
+    assert_eq!(
+        Rule::parse("10/60").unwrap(),
+        Some(Rule {
+            count: 10,
+            period: 60
+        })
+    );
+    assert_eq!(Rule::parse("").unwrap(), None);
+    assert_eq!(Rule::parse("0/60").unwrap(), None);
+    assert!(Rule::parse("10").is_err());
+    assert!(Rule::parse("ten/60").is_err());
+    assert!(Rule::parse("1001/1").is_err());
+    assert!(Rule::parse("1000/1").unwrap().is_some());
+}
+
+#[test]
+fn test_gcra() {
+    // Burst of 3, then one per 20 seconds
+    let rule = Rule::parse("3/60").unwrap().unwrap();
+    let mut tat = None;
+    for _ in 0..3 {
+        tat = Some(rule.step(tat, 0).unwrap());
+    }
+    assert_eq!(rule.step(tat, 0), Err(20_000));
+    assert_eq!(rule.step(tat, 19_999), Err(1));
+    let t = rule.step(tat, 20_000).unwrap();
+    assert_eq!(t, 80_000);
+    assert!(rule.step(Some(t), 20_000).is_err());
+    // Idle time refills the bucket
+    assert_eq!(rule.step(Some(t), 1_000_000), Ok(1_020_000));
+}
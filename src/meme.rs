@@ -3,6 +3,7 @@ use crate::database::Database;
 use crate::ir::query::QueryBody;
 use crate::ir::{Costs, Hash, Id, Reply};
 use crate::{Error, Result};
+use bytes::Bytes;
 use chrono::{DateTime, Days, Utc};
 use http_body_util::BodyExt;
 use s3::bucket::CHUNK_SIZE;
@@ -10,6 +11,10 @@ use serde_json::json;
 use sqlx::Row;
 use std::time::Duration;
 use tokio::time::Instant;
+use tokio_stream::{Stream, StreamExt};
+
+/// Longest visa for a peer, in seconds.
+pub const VISA_TTL_MAX: i64 = 30 * 24 * 3600;
 
 pub struct Meme {
     db: &'static Database,
@@ -76,16 +81,39 @@ impl Meme {
         Ok(json.to_string())
     }
 
-    /// Stream version didn't work.
-    /// Try using chunk.
+    /// Upload the body of a MemePut.
     pub async fn put_meme(
         &self,
         uid: &Id,
         changes: &mut Costs,
         deadline: Instant,
         days: u64,
-        mut raw: QueryBody,
+        raw: QueryBody,
     ) -> Result<Reply> {
+        let raw = raw.into_data_stream().map(|data| data.map_err(Error::from));
+        let hash = self
+            .put_stream(uid, changes, deadline, days, raw, None)
+            .await?;
+        Ok(Reply::MemePut {
+            changes: *changes,
+            hash,
+        })
+    }
+
+    /// Stream version didn't work.
+    /// Try using chunk.
+    /// If expected is given, the upload is dropped unless the hash matches.
+    /// Memes start private, pub is set by the censor.
+    pub async fn put_stream(
+        &self,
+        uid: &Id,
+        changes: &mut Costs,
+        deadline: Instant,
+        days: u64,
+        raw: impl Stream<Item = Result<Bytes>>,
+        expected: Option<&Hash>,
+    ) -> Result<Hash> {
+        let mut raw = std::pin::pin!(raw);
         // Create object with a random name.
         let oid = {
             let mut rng = rand::rng();
@@ -105,47 +133,46 @@ impl Meme {
         let mut parts = vec![];
         let mut stack = vec![];
         let mut chunk_size = 0;
-        while let Some(result) = raw.frame().await {
-            let frame = result?;
-            if let Ok(data) = frame.into_data() {
-                // Space check
-                let cost = match (data.len() as i64 * self.space_cost_obj).checked_mul(days as i64)
-                {
-                    Some(i) => i / 1000, // per day per KB
-                    None => return Err(Error::CostSpaceTooLarge),
-                };
-                if changes.space < cost {
-                    changes.space = 0;
-                    return Err(Error::CostSpace);
-                } else {
-                    changes.space -= cost;
-                }
-                // Time check
-                if Instant::now() > deadline {
-                    return Err(Error::CostTime);
-                }
-                // Update metadata
-                hasher.update(&data);
-                size += data.len();
-                // Append to stack;
-                chunk_size += data.len();
-                stack.push(data);
-                if chunk_size >= CHUNK_SIZE {
-                    part_number += 1;
-                    let part = mr
-                        .put_multipart_chunk(
-                            stack.concat(),
-                            &path,
-                            part_number,
-                            &upload_id,
-                            &content_type,
-                        )
-                        .await?;
-                    parts.push(part);
-                    // Reset stack
-                    chunk_size = 0;
-                    stack.clear();
-                }
+        let mut charged = 0;
+        while let Some(data) = raw.next().await {
+            let data = data?;
+            // Space check
+            let cost = match (data.len() as i64 * self.space_cost_obj).checked_mul(days as i64) {
+                Some(i) => i / 1000, // per day per KB
+                None => return Err(Error::CostSpaceTooLarge),
+            };
+            if changes.space < cost {
+                changes.space = 0;
+                return Err(Error::CostSpace);
+            } else {
+                changes.space -= cost;
+                charged += cost;
+            }
+            // Time check
+            if Instant::now() > deadline {
+                return Err(Error::CostTime);
+            }
+            // Update metadata
+            hasher.update(&data);
+            size += data.len();
+            // Append to stack;
+            chunk_size += data.len();
+            stack.push(data);
+            if chunk_size >= CHUNK_SIZE {
+                part_number += 1;
+                let part = mr
+                    .put_multipart_chunk(
+                        stack.concat(),
+                        &path,
+                        part_number,
+                        &upload_id,
+                        &content_type,
+                    )
+                    .await?;
+                parts.push(part);
+                // Reset stack
+                chunk_size = 0;
+                stack.clear();
             }
         }
         // Upload the last chunk.
@@ -168,6 +195,12 @@ impl Meme {
 
         // Create metadata
         let hash = hasher.finalize();
+        if expected.is_some_and(|expected| expected != hash.as_bytes()) {
+            // Nothing is kept, so the space is not charged.
+            changes.space += charged;
+            mr.delete_object(&path).await?;
+            return Err(Error::MemeHash);
+        }
         let now: DateTime<Utc> = Utc::now();
         let eol = now
             .checked_add_days(Days::new(days))
@@ -200,10 +233,46 @@ impl Meme {
         let remaining: Duration = deadline - now;
         changes.time = remaining.as_millis() as i64 * self.time_cost;
 
-        Ok(Reply::MemePut {
-            changes: *changes,
-            hash: hash.into(),
-        })
+        Ok(hash.into())
+    }
+
+    /// Read a meme into memory, failing beyond limit bytes.
+    pub async fn read_meme(
+        &self,
+        uid: &Id,
+        changes: &mut Costs,
+        deadline: Instant,
+        hash: Hash,
+        public: bool,
+        limit: usize,
+    ) -> Result<Vec<u8>> {
+        let Reply::MemeGet { mut raw, .. } =
+            self.get_meme(uid, changes, deadline, hash, public).await?
+        else {
+            return Err(Error::Logical);
+        };
+        let mut data = vec![];
+        while let Some(chunk) = raw.bytes.next().await {
+            data.extend_from_slice(&chunk?);
+            if data.len() > limit {
+                return Err(Error::CostSpace);
+            }
+        }
+        Ok(data)
+    }
+
+    /// Let peer fetch the private meme of uid by hash for ttl seconds.
+    pub async fn put_visa(&self, uid: &Id, hash: &Hash, peer: &Id, ttl: i64) -> Result<()> {
+        if !(1..=VISA_TTL_MAX).contains(&ttl) {
+            return Err(Error::NumCheck);
+        }
+        sqlx::query("SELECT 1 FROM meme_meta WHERE uid = $1 AND hash = $2 LIMIT 1")
+            .bind(&uid.0[..])
+            .bind(&hash[..])
+            .fetch_optional(&self.db.crdb)
+            .await?
+            .ok_or(Error::MemeNotFound)?;
+        self.db.set_meme_visa(peer, hash, uid, ttl).await
     }
 
     /// Current implementation uses high-level stream.
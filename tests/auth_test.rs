@@ -91,6 +91,41 @@ async fn session_sms() {
     }
 }
 
+#[tokio::test]
+async fn session_email() {
+    let mut client = Client::zero().await;
+    let (access, refresh) = client.auth_session_start().await.unwrap();
+    client.config.session = Some(Session::new(&access, &refresh));
+
+    let email = format!("{}@example.com", common::random_string(16).to_lowercase());
+    let token = client.auth_email_start(&email).await.unwrap();
+    let mail = common::read_mail(&email);
+    let link = mail.lines().find(|l| l.starts_with("http")).unwrap();
+    reqwest::get(link).await.unwrap();
+
+    let uid = client.auth_email_verify(&token, None, None).await.unwrap();
+    let (access_uid, refresh_uid) = get_tokens(client).await;
+    if uid != access_uid || uid != refresh_uid {
+        panic!()
+    }
+}
+
+#[tokio::test]
+async fn email_link_to_phone() {
+    let (client, uid) = common::new_user().await;
+
+    let email = format!("{}@example.com", common::random_string(16).to_lowercase());
+    let token = client.auth_email_start(&email).await.unwrap();
+    let mail = common::read_mail(&email);
+    let code = mail.rsplit("code: ").next().unwrap().trim();
+
+    let email_uid = client
+        .auth_email_verify(&token, Some(code), None)
+        .await
+        .unwrap();
+    assert_eq!(uid, email_uid);
+}
+
 async fn get_tokens(client: Client) -> (String, String) {
     let db = Database::default().await;
     let session = &client.config.session.unwrap();
@@ -118,3 +153,319 @@ async fn get_tokens(client: Client) -> (String, String) {
 
     (access_uid.to_string(), refresh_uid.to_string())
 }
+
+#[tokio::test]
+async fn session_list_and_revoke() {
+    use serde_json::Value;
+
+    let number = common::random_string(16);
+    let (laptop, uid) = common::login(&number).await;
+    let (phone, phone_uid) = common::login(&number).await;
+    assert_eq!(uid, phone_uid);
+
+    let sessions: Vec<Value> =
+        serde_json::from_str(&laptop.auth_session_list().await.unwrap()).unwrap();
+    assert_eq!(sessions.len(), 4);
+    assert!(
+        sessions
+            .iter()
+            .all(|s| s["label"].as_str().unwrap().starts_with("vcli"))
+    );
+    let other = sessions.iter().find(|s| s["current"] == false).unwrap()["handle"]
+        .as_str()
+        .unwrap();
+
+    // Kill the other login remotely
+    laptop.auth_session_revoke(other, None).await.unwrap();
+    let phone_session = phone.config.session.as_ref().unwrap();
+    assert_eq!(session_tokens_exist(phone_session).await, (false, false));
+    let sessions: Vec<Value> =
+        serde_json::from_str(&laptop.auth_session_list().await.unwrap()).unwrap();
+    assert_eq!(sessions.len(), 2);
+
+    laptop.auth_session_revoke_all(None).await.unwrap();
+    let laptop_session = laptop.config.session.as_ref().unwrap();
+    assert_eq!(session_tokens_exist(laptop_session).await, (false, false));
+}
+
+#[tokio::test]
+async fn account_delete() {
+    use serde_json::Value;
+
+    let (client, uid) = common::new_user().await;
+    let meme = client.meme_put(1, "hello".into()).await.unwrap();
+    let ns = common::random_string(16).to_lowercase();
+    let claim = serde_json::json!({"_type": "Claim", "_ns": ns}).to_string();
+    client
+        .gene_call(None, "map_1", Some(claim.clone()))
+        .await
+        .unwrap();
+    let db = Database::default().await;
+    let (id, peer) = (Id::from_str(&uid).unwrap(), Id::rand(&mut rand::rng()));
+    let hash: [u8; 32] = hex::decode(&meme).unwrap().try_into().unwrap();
+    db.set_meme_visa(&peer, &hash, &id, 600).await.unwrap();
+
+    // The archive is a private meme listing everything
+    let (archive, _due) = client.auth_account_delete(None).await.unwrap();
+    let export = client.meme_get(false, archive.clone()).await.unwrap();
+    let export: Value = serde_json::from_slice(&export).unwrap();
+    assert_eq!(export["uid"], uid);
+    assert!(export["phone"].is_string());
+    assert_eq!(export["memes"][0]["hash"], meme);
+    assert_eq!(export["map_ns"][0]["ns"], ns);
+    assert_eq!(export["meme_visas"][0]["peer"], peer.to_string());
+
+    // Asking again keeps the pending archive
+    let (again, _) = client.auth_account_delete(None).await.unwrap();
+    assert_eq!(again, archive);
+
+    // Cancel, then schedule again and let the grace period pass
+    client.auth_account_delete_cancel().await.unwrap();
+    client.auth_account_delete(None).await.unwrap();
+    let uid = id;
+    db.delete_account(&uid).await.unwrap();
+
+    assert_eq!(db.get_uid_to_phone(&uid).await.unwrap(), None);
+    assert_eq!(db.get_credit(&uid).await.unwrap(), 0);
+    assert_eq!(db.get_meme_visa(&peer, &hash).await.unwrap(), None);
+
+    // Namespaces of deleted accounts are free to claim
+    let (other, _) = common::new_user().await;
+    other.gene_call(None, "map_1", Some(claim)).await.unwrap();
+    let session = client.config.session.as_ref().unwrap();
+    assert_eq!(session_tokens_exist(session).await, (false, false));
+}
+
+#[tokio::test]
+async fn phone_change_and_recover() {
+    let old = common::random_string(16);
+    let new = common::random_string(16);
+    let (client, uid) = common::login(&old).await;
+    let db = Database::default().await;
+    let uid = Id::from_str(&uid).unwrap();
+
+    let (phone, message) = client.auth_sms_send_to().await.unwrap();
+    let message_id = Id::from_str(&message).unwrap();
+    db.sms_sent(&new, &phone, &message_id.0).await.unwrap();
+    client
+        .auth_phone_change(&phone, &message, None)
+        .await
+        .unwrap();
+
+    assert_eq!(db.get_phone_to_uid(&new).await.unwrap(), Some(uid));
+    assert_eq!(db.get_uid_to_phone(&uid).await.unwrap(), Some(new.clone()));
+    assert_eq!(db.get_phone_to_uid(&old).await.unwrap(), None);
+    assert_eq!(db.get_phone_tombstone(&old).await.unwrap(), Some(uid));
+
+    // The old phone recovers the account and signs out the others
+    let (_, recovered) = common::login(&old).await;
+    assert_eq!(recovered, uid.to_string());
+    assert_eq!(db.get_phone_to_uid(&old).await.unwrap(), Some(uid));
+    assert_eq!(db.get_phone_to_uid(&new).await.unwrap(), None);
+    assert_eq!(db.get_phone_tombstone(&old).await.unwrap(), None);
+    let session = client.config.session.as_ref().unwrap();
+    assert_eq!(session_tokens_exist(session).await, (false, false));
+}
+
+/// Pay without exiting on error, return the error if any.
+async fn pay_error(client: &Client, totp: Option<&str>) -> Option<String> {
+    let mut builder = reqwest::Client::new()
+        .post(&client.config.url)
+        .header("type", "CostPay")
+        .header("access", &client.config.session.as_ref().unwrap().access)
+        .header("vendor", Id::zero().to_string());
+    if let Some(totp) = totp {
+        builder = builder.header("totp", totp);
+    }
+    let response = builder.send().await.unwrap();
+    response
+        .headers()
+        .get("error")
+        .map(|e| e.to_str().unwrap().to_string())
+}
+
+#[tokio::test]
+async fn totp_guards_pay() {
+    use data_encoding::BASE32_NOPAD;
+    use voxov::auth::totp;
+
+    let (client, _) = common::new_user().await;
+
+    let (secret, uri, recovery) = client.auth_totp_enroll(None).await.unwrap();
+    assert!(uri.starts_with("otpauth://totp/VOxOV:"));
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).unwrap();
+    let step = chrono::Utc::now().timestamp() / 30;
+    let code = format!("{:06}", totp::code(&secret, step));
+    client.auth_totp_verify(&code).await.unwrap();
+
+    // Sensitive queries need the second factor, replays are rejected
+    assert_eq!(pay_error(&client, None).await.unwrap(), "AuthTotpRequired");
+    assert_eq!(
+        pay_error(&client, Some(&code)).await.unwrap(),
+        "AuthTotpInvalid"
+    );
+
+    // Recovery codes work once
+    let mut codes = recovery.split(',');
+    let first = codes.next().unwrap();
+    assert_eq!(pay_error(&client, Some(first)).await, None);
+    assert!(pay_error(&client, Some(first)).await.is_some());
+
+    client
+        .auth_totp_disable(codes.next().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(pay_error(&client, None).await, None);
+}
+
+/// Revoke all logins without exiting on error, return the error if any.
+async fn revoke_all_error(client: &Client, totp: Option<&str>) -> Option<String> {
+    let mut builder = reqwest::Client::new()
+        .post(&client.config.url)
+        .header("type", "AuthSessionRevokeAll")
+        .header("access", &client.config.session.as_ref().unwrap().access);
+    if let Some(totp) = totp {
+        builder = builder.header("totp", totp);
+    }
+    let response = builder.send().await.unwrap();
+    response
+        .headers()
+        .get("error")
+        .map(|e| e.to_str().unwrap().to_string())
+}
+
+#[tokio::test]
+async fn totp_guards_revoke_and_locks_out() {
+    use data_encoding::BASE32_NOPAD;
+    use voxov::auth::totp;
+
+    let (client, _) = common::new_user().await;
+
+    let (secret, _, recovery) = client.auth_totp_enroll(None).await.unwrap();
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).unwrap();
+    let step = chrono::Utc::now().timestamp() / 30;
+    let code = format!("{:06}", totp::code(&secret, step));
+    client.auth_totp_verify(&code).await.unwrap();
+
+    assert_eq!(
+        revoke_all_error(&client, None).await.unwrap(),
+        "AuthTotpRequired"
+    );
+
+    // Guessing runs out of attempts, even with a valid code after
+    for _ in 0..4 {
+        assert_eq!(
+            revoke_all_error(&client, Some("not a code")).await.unwrap(),
+            "AuthTotpInvalid"
+        );
+    }
+    let first = recovery.split(',').next().unwrap();
+    assert_eq!(
+        revoke_all_error(&client, Some(first)).await.unwrap(),
+        "LimitExceeded"
+    );
+}
+
+/// List sessions without exiting on error, return the error if any.
+async fn access_error(client: &Client) -> Option<String> {
+    let response = reqwest::Client::new()
+        .post(&client.config.url)
+        .header("type", "AuthSessionList")
+        .header("access", &client.config.session.as_ref().unwrap().access)
+        .send()
+        .await
+        .unwrap();
+    response
+        .headers()
+        .get("error")
+        .map(|e| e.to_str().unwrap().to_string())
+}
+
+/// Signed access tokens stop working once their login is revoked.
+#[tokio::test]
+async fn signed_access_revoke() {
+    use voxov::config::Config;
+    use voxov::database::session_handle;
+    use voxov::to_static;
+
+    let url = "http://127.0.0.1:18095";
+    let mut c = Config::new();
+    c.http_addr = "127.0.0.1:18095".parse().unwrap();
+    c.samsara = false;
+    c.ripperd_disabled = true;
+    c.access_keys = format!("k1:{}", common::random_string(32));
+    tokio::spawn(voxov::serve(to_static!(c)));
+
+    let signed_client = || async {
+        let mut client = Client::zero().await;
+        client.config.url = url.to_string();
+        while client.ping().await.is_err() {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        client
+    };
+    let number = common::random_string(16);
+    let (phone, _) = common::login_with(signed_client().await, &number).await;
+    let (laptop, _) = common::login_with(signed_client().await, &number).await;
+    assert!(phone.config.session.as_ref().unwrap().access.contains('.'));
+    assert_eq!(access_error(&phone).await, None);
+
+    // Revoking one login denies its signed token only
+    let refresh = Id::from_str(&phone.config.session.as_ref().unwrap().refresh).unwrap();
+    let handle = hex::encode(session_handle(&refresh.0));
+    laptop.auth_session_revoke(&handle, None).await.unwrap();
+    assert_eq!(
+        access_error(&phone).await.unwrap(),
+        "AuthInvalidAccessToken"
+    );
+    assert_eq!(access_error(&laptop).await, None);
+
+    laptop.auth_session_revoke_all(None).await.unwrap();
+    assert_eq!(
+        access_error(&laptop).await.unwrap(),
+        "AuthInvalidAccessToken"
+    );
+}
+
+/// Anonymous sessions only read, until SMS upgrades them in place.
+#[tokio::test]
+async fn anonymous_session_upgrade() {
+    use voxov::config::Config;
+    use voxov::to_static;
+
+    let mut c = Config::new();
+    c.http_addr = "127.0.0.1:18096".parse().unwrap();
+    c.samsara = false;
+    c.ripperd_disabled = true;
+    c.anonymous = true;
+    tokio::spawn(voxov::serve(to_static!(c)));
+
+    let mut client = Client::zero().await;
+    client.config.url = "http://127.0.0.1:18096".to_string();
+    while client.ping().await.is_err() {
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    let (access, refresh) = client.auth_session_start().await.unwrap();
+    client.config.session = Some(Session::new(&access, &refresh));
+
+    let anonymous = client.auth_session_anonymous().await.unwrap();
+    let db = Database::default().await;
+    let uid = Id::from_str(&anonymous).unwrap();
+    assert!(db.is_anonymous(&uid).await.unwrap());
+    assert!(db.get_credit(&uid).await.unwrap() > 0);
+    assert_eq!(pay_error(&client, None).await.unwrap(), "AuthAnonymous");
+
+    // Asking again keeps the account
+    assert_eq!(client.auth_session_anonymous().await.unwrap(), anonymous);
+
+    // A new phone upgrades the same account
+    let (phone, message) = client.auth_sms_send_to().await.unwrap();
+    let message_id = Id::from_str(&message).unwrap();
+    db.sms_sent(&common::random_string(16), &phone, &message_id.0)
+        .await
+        .unwrap();
+    let upgraded = client.auth_sms_sent(&phone, &message, None).await.unwrap();
+    assert_eq!(upgraded, anonymous);
+    assert!(!db.is_anonymous(&uid).await.unwrap());
+    assert_eq!(pay_error(&client, None).await, None);
+}
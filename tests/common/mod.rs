@@ -1,20 +1,35 @@
 use rand::{Rng, distr::Alphanumeric};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::{env, fs};
 use vcli::{client::Client, config::Session};
 use voxov::database::Database;
 
-/// Authenticate user with number, return (client, uid).
+/// Authenticate a new user, return (client, uid).
 pub async fn new_user() -> (Client, String) {
-    let mut client = Client::zero().await;
+    login(&random_string(16)).await
+}
+
+/// Authenticate user with number, return (client, uid).
+pub async fn login(number: &str) -> (Client, String) {
+    login_with(Client::zero().await, number).await
+}
+
+/// Authenticate user with number at the server of client, return (client, uid).
+/// Signed access tokens are refreshed to carry the uid.
+pub async fn login_with(mut client: Client, number: &str) -> (Client, String) {
     let (access, refresh) = client.auth_session_start().await.unwrap();
     client.config.session = Some(Session::new(&access, &refresh));
     let (phone, message) = client.auth_sms_send_to().await.unwrap();
     let db = Database::default().await;
-    let number = random_string(16);
     // Simulate carrier callback: record that user's phone sent the SMS
     let message_id = voxov::ir::Id::from_str(&message).unwrap();
-    db.sms_sent(&number, &phone, &message_id.0).await.unwrap();
-    let uid = client.auth_sms_sent(&phone, &message).await.unwrap();
+    db.sms_sent(number, &phone, &message_id.0).await.unwrap();
+    let uid = client.auth_sms_sent(&phone, &message, None).await.unwrap();
+    if access.contains('.') {
+        let access = client.auth_session_refresh().await.unwrap();
+        client.config.session.as_mut().unwrap().set_access(&access);
+    }
     (client, uid)
 }
 
@@ -26,3 +41,13 @@ pub fn random_string(n: usize) -> String {
         .map(char::from)
         .collect()
 }
+
+/// Read the last mail to email written by the file transport.
+#[allow(dead_code)]
+pub fn read_mail(email: &str) -> String {
+    let dir = match env::var("MAIL_DIR") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => env::temp_dir().join("voxov-mail"),
+    };
+    fs::read_to_string(dir.join(format!("{}.eml", email))).unwrap()
+}
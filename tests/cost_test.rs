@@ -1,4 +1,7 @@
-use voxov::database::Database;
+use chrono::{Duration, Utc};
+use vcli::client::Client;
+use voxov::config::Config;
+use voxov::database::{Database, ripperd::Ripperd};
 use voxov::ir::Id;
 
 mod common;
@@ -7,7 +10,7 @@ use common::new_user;
 #[tokio::test]
 async fn cost_pay() {
     let (client, _) = new_user().await;
-    client.cost_pay().await.unwrap();
+    client.cost_pay(None).await.unwrap();
 }
 
 #[tokio::test]
@@ -27,8 +30,120 @@ async fn cost_check_in() {
     assert_eq!(credit_before + award, credit_after);
 }
 
-async fn get_credit(uid: &String) -> i64 {
+#[tokio::test]
+async fn cost_hold_capture() {
+    let (client, uid) = new_user().await;
+    let db = Database::default().await;
+    let id = Id::try_from(uid.as_str()).unwrap();
+    let start = get_credit(&uid).await;
+
+    // Held up front, then the rest is returned once.
+    let deadline = Utc::now() + Duration::seconds(60);
+    let hold = db.hold_credit(&id, 1000, deadline, "Test").await.unwrap();
+    assert_eq!(get_credit(&uid).await, start - 1000);
+    db.capture_hold(&hold.id, 300, "Test").await.unwrap();
+    assert_eq!(get_credit(&uid).await, start - 300);
+    db.capture_hold(&hold.id, 0, "Test").await.unwrap();
+    assert_eq!(get_credit(&uid).await, start - 300);
+
+    // Captures are clamped to the hold.
+    let hold = db.hold_credit(&id, 1000, deadline, "Test").await.unwrap();
+    db.capture_hold(&hold.id, 5000, "Test").await.unwrap();
+    assert_eq!(get_credit(&uid).await, start - 1300);
+    let hold = db.hold_credit(&id, 1000, deadline, "Test").await.unwrap();
+    db.capture_hold(&hold.id, -5000, "Test").await.unwrap();
+    assert_eq!(get_credit(&uid).await, start - 1300);
+
+    // A query pays the plan less the changes it replies with.
+    let start = get_credit(&uid).await;
+    let response = gene(&client, "GeneMeta", "map_1").await;
+    assert!(response.headers().get("error").is_none());
+    let left: i64 = ["time", "space", "traffic", "tip"]
+        .iter()
+        .map(|k| header(&response, k))
+        .sum();
+    assert_eq!(get_credit(&uid).await, start - (plan_sum(&client) - left));
+    assert_eq!(open_holds(&db, &id).await, 0);
+}
+
+#[tokio::test]
+async fn cost_release_on_error() {
+    let (client, uid) = new_user().await;
+    let db = Database::default().await;
+    let id = Id::try_from(uid.as_str()).unwrap();
+    let start = get_credit(&uid).await;
+
+    // Failures pay for their time only, and leave no hold behind.
+    let response = gene(&client, "GeneCall", "no_such_gene").await;
+    assert_eq!(response.headers()["error"], "GeneInvalidId");
+    let used = start - get_credit(&uid).await;
+    assert!((0..=client.config.plan.time as i64).contains(&used));
+    assert_eq!(open_holds(&db, &id).await, 0);
+}
+
+#[tokio::test]
+async fn cost_ripperd_hold() {
+    let (_, uid) = new_user().await;
+    let mut config = Config::new();
+    config.ripperd_disabled = false;
+    config.ripperd_interval = 1;
+    let db: &'static Database = Box::leak(Box::new(Database::new(&config, false).await));
+    let id = Id::try_from(uid.as_str()).unwrap();
+    let start = get_credit(&uid).await;
+
+    // A hold left by a crashed request is returned after its deadline.
+    let deadline = Utc::now() - Duration::seconds(1);
+    db.hold_credit(&id, 1000, deadline, "Test").await.unwrap();
+    assert_eq!(get_credit(&uid).await, start - 1000);
+    let ripperd: &'static Ripperd = Box::leak(Box::new(Ripperd::new(&config, db)));
+    tokio::spawn(ripperd.ripperd());
+    for _ in 0..50 {
+        if get_credit(&uid).await == start {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    }
+    assert_eq!(get_credit(&uid).await, start);
+    assert_eq!(open_holds(db, &id).await, 0);
+}
+
+/// Send a gene query without exiting on error.
+async fn gene(client: &Client, kind: &str, gid: &str) -> reqwest::Response {
+    let plan = &client.config.plan;
+    reqwest::Client::new()
+        .post(&client.config.url)
+        .header("type", kind)
+        .header("access", &client.config.session.as_ref().unwrap().access)
+        .header("time", plan.time.to_string())
+        .header("space", plan.space.to_string())
+        .header("traffic", plan.traffic.to_string())
+        .header("tip", plan.tip.to_string())
+        .header("gid", gid)
+        .header("arg", "")
+        .send()
+        .await
+        .unwrap()
+}
+
+fn header(response: &reqwest::Response, key: &str) -> i64 {
+    response.headers()[key].to_str().unwrap().parse().unwrap()
+}
+
+fn plan_sum(client: &Client) -> i64 {
+    let plan = &client.config.plan;
+    (plan.time + plan.space + plan.traffic + plan.tip) as i64
+}
+
+async fn open_holds(db: &Database, uid: &Id) -> i64 {
+    sqlx::query_scalar("SELECT count(*) FROM credit_holds WHERE uid = $1")
+        .bind(&uid.0[..])
+        .fetch_one(&db.crdb)
+        .await
+        .unwrap()
+}
+
+async fn get_credit(uid: &str) -> i64 {
     let db = Database::default().await;
-    let uid_id = Id::try_from(uid.as_str()).unwrap();
+    let uid_id = Id::try_from(uid).unwrap();
     db.get_credit(&uid_id).await.unwrap()
 }
@@ -0,0 +1,198 @@
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::time::Duration;
+use vcli::{client::Client, config::Session};
+use voxov::config::Config;
+use voxov::database::Database;
+use voxov::ir::Id;
+use voxov::to_static;
+
+const CREDIT: i64 = 10_000_000_000;
+
+/// New account with credit, return (uid, access, refresh).
+async fn account(db: &Database) -> (Id, Id, Id) {
+    let mut rng = rand::rng();
+    let (uid, access, refresh) = (Id::rand(&mut rng), Id::rand(&mut rng), Id::rand(&mut rng));
+    db.incr_credit(&uid, None, CREDIT, "FedTest").await.unwrap();
+    db.set_access(&access.0, &uid).await.unwrap();
+    db.set_refresh(&refresh.0, &uid).await.unwrap();
+    (uid, access, refresh)
+}
+
+/// Config of an instance at port, with a peer at peer_port.
+/// Each instance has its own keyspace and database, named after port.
+fn config(port: u16, id: Id, seed: u8, peer: Id, peer_port: u16, rate: f64) -> Config {
+    let mut c = Config::new();
+    c.http_addr = format!("127.0.0.1:{}", port).parse().unwrap();
+    c.samsara = false;
+    c.ripperd_disabled = true;
+    c.scylla_keyspace = format!("voxov_fed_{}", port);
+    c.crdb_addr = c
+        .crdb_addr
+        .replacen("/voxov", &format!("/voxov_fed_{}", port), 1);
+    c.fed_id = id;
+    c.fed_keys = format!("k1:{}", hex::encode([seed; 32]));
+    c.fed_members = to_static!(HashMap::from([(
+        peer,
+        format!("http://127.0.0.1:{}", peer_port)
+    )]));
+    c.fed_rates = to_static!(HashMap::from([(peer, (rate, rate))]));
+    c.fed_settle_interval = 1;
+    c
+}
+
+/// An instance in the test, with its own database.
+struct Instance {
+    id: Id,
+    port: u16,
+    db: Database,
+}
+
+/// Start two instances at ports that know each other, return them
+/// and the access token of the account of b at a.
+/// One credit of b is worth two of a.
+async fn instances(port_a: u16, port_b: u16) -> (Instance, Instance, Id) {
+    let (a, b) = {
+        let mut rng = rand::rng();
+        (Id::rand(&mut rng), Id::rand(&mut rng))
+    };
+    let mut ca = config(port_a, a, 1, b, port_b, 2.0);
+    let mut cb = config(port_b, b, 2, a, port_a, 0.5);
+    let db_a = Database::new(&ca, true).await;
+    let db_b = Database::new(&cb, true).await;
+
+    let (a_uid_at_b, _, a_at_b) = account(&db_b).await;
+    let (b_uid_at_a, b_access_at_a, b_at_a) = account(&db_a).await;
+    ca.fed_refresh = format!("{}={}", b, a_at_b);
+    ca.fed_accounts = to_static!(HashMap::from([(b, b_uid_at_a)]));
+    cb.fed_refresh = format!("{}={}", a, b_at_a);
+    cb.fed_accounts = to_static!(HashMap::from([(a, a_uid_at_b)]));
+    tokio::spawn(voxov::serve(to_static!(ca)));
+    tokio::spawn(voxov::serve(to_static!(cb)));
+    (
+        Instance {
+            id: a,
+            port: port_a,
+            db: db_a,
+        },
+        Instance {
+            id: b,
+            port: port_b,
+            db: db_b,
+        },
+        b_access_at_a,
+    )
+}
+
+/// Client of a new user at the instance.
+async fn client(instance: &Instance) -> Client {
+    let (_, access, refresh) = account(&instance.db).await;
+    let mut client = Client::zero().await;
+    client.config.url = format!("http://127.0.0.1:{}", instance.port);
+    client.config.session = Some(Session::new(&access.to_string(), &refresh.to_string()));
+    while client.ping().await.is_err() {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    client
+}
+
+/// Two instances forward to each other, then settle.
+#[tokio::test]
+async fn fed_settlement() {
+    let (a, b, _) = instances(18091, 18092).await;
+    let client = client(&a).await;
+
+    let info = client
+        .gene_call(Some(b.id.to_string()), "info_1", None)
+        .await
+        .unwrap();
+    assert!(info.contains(&b.id.to_string()));
+
+    // a owes b for the call, in credits of b.
+    let (_, owed_there) = a.db.get_fed_balance(&b.id).await.unwrap();
+    assert!(owed_there > 0);
+
+    // b claims it back at a.
+    for _ in 0..100 {
+        let (owed_here, _) = b.db.get_fed_balance(&a.id).await.unwrap();
+        let (_, owed_there) = a.db.get_fed_balance(&b.id).await.unwrap();
+        if owed_here == 0 && owed_there == 0 {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("Not settled");
+}
+
+/// A private meme at b is cloned to a with a visa.
+#[tokio::test]
+async fn fed_meme_clone() {
+    let (a, b, _) = instances(18093, 18094).await;
+    let client_a = client(&a).await;
+    let client_b = client(&b).await;
+
+    let bytes = Bytes::from((0..1000).map(|_| rand::random()).collect::<Vec<u8>>());
+    let hash = client_b.meme_put(1, bytes.clone()).await.unwrap();
+    client_b
+        .meme_visa(hash.clone(), a.id.to_string(), 60)
+        .await
+        .unwrap();
+
+    let cloned = client_a
+        .meme_clone(false, b.id.to_string(), hash.clone(), 1)
+        .await
+        .unwrap();
+    assert_eq!(cloned, hash);
+    assert_eq!(client_a.meme_get(false, hash).await.unwrap(), bytes);
+}
+
+/// The error of a peer comes back as is, and only the time is charged.
+#[tokio::test]
+async fn fed_forward_error() {
+    let (a, b, _) = instances(18097, 18098).await;
+    let client = client(&a).await;
+    let credit = || async { client.cost_get().await.unwrap().parse::<u64>().unwrap() };
+    let before = credit().await;
+
+    let plan = &client.config.plan;
+    let response = reqwest::Client::new()
+        .post(&client.config.url)
+        .header("type", "GeneCall")
+        .header("access", &client.config.session.as_ref().unwrap().access)
+        .header("fed", b.id.to_string())
+        .header("gid", "missing_1")
+        .header("arg", "")
+        .header("time", plan.time.to_string())
+        .header("space", plan.space.to_string())
+        .header("traffic", plan.traffic.to_string())
+        .header("tip", plan.tip.to_string())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["error"], "GeneInvalidId");
+
+    assert!(before - credit().await <= plan.time);
+}
+
+/// The account of a peer only takes queries signed by that peer.
+#[tokio::test]
+async fn fed_account_needs_signature() {
+    let (a, _, b_access_at_a) = instances(18099, 18100).await;
+    let client = client(&a).await;
+
+    let plan = &client.config.plan;
+    let response = reqwest::Client::new()
+        .post(&client.config.url)
+        .header("type", "GeneCall")
+        .header("access", b_access_at_a.to_string())
+        .header("gid", "info_1")
+        .header("arg", "")
+        .header("time", plan.time.to_string())
+        .header("space", plan.space.to_string())
+        .header("traffic", plan.traffic.to_string())
+        .header("tip", plan.tip.to_string())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["error"], "FedSignature");
+}
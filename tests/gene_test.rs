@@ -1,5 +1,5 @@
 mod common;
-use common::new_user;
+use common::{new_user, random_string};
 
 #[tokio::test]
 async fn gene_meta() {
@@ -12,3 +12,39 @@ async fn gene_call() {
     let (client, _) = new_user().await;
     client.gene_call(None, "info_1", None).await.unwrap();
 }
+
+/// Echo the arg back.
+const ECHO: &str = r#"(module
+  (memory (export "memory") 1)
+  (global $top (mut i32) (i32.const 1024))
+  (func (export "alloc") (param $len i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (global.get $top))
+    (global.set $top (i32.add (global.get $top) (local.get $len)))
+    (local.get $ptr))
+  (func (export "call") (param $ptr i32) (param $len i32) (result i64)
+    (i64.or
+      (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+      (i64.extend_i32_u (local.get $len)))))"#;
+
+#[tokio::test]
+async fn gene_wasm() {
+    let (client, _) = new_user().await;
+    let module = wat::parse_str(ECHO).unwrap();
+    let hash = client.meme_put(1, module.into()).await.unwrap();
+
+    let gid = format!("echo{}_1", random_string(8).to_lowercase());
+    let register = serde_json::json!({"_type": "Register", "id": gid, "hash": hash});
+    client
+        .gene_call(None, "wasm_1", Some(register.to_string()))
+        .await
+        .unwrap();
+
+    let arg = r#"{"hello":"wasm"}"#;
+    let result = client
+        .gene_call(None, &gid, Some(arg.into()))
+        .await
+        .unwrap();
+    assert_eq!(result, arg);
+    client.gene_meta(None, &gid).await.unwrap();
+}
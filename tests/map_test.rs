@@ -0,0 +1,670 @@
+use chrono::{Duration, Utc};
+use serde_json::{Value, json};
+use std::str::FromStr;
+use vcli::client::Client;
+use voxov::database::Database;
+use voxov::ir::Id;
+
+mod common;
+use common::{new_user, random_string};
+
+/// New namespace, so tests don't see each other's docs.
+fn ns() -> String {
+    random_string(16).to_lowercase()
+}
+
+/// Put a doc with fields into ns.
+async fn put(client: &Client, ns: &str, fields: Value) {
+    let mut doc = json!({
+        "_type": "Put",
+        "_eol": (Utc::now() + Duration::days(2)).timestamp(),
+        "_ns": ns,
+    });
+    for (k, v) in fields.as_object().unwrap() {
+        doc[k] = v.clone();
+    }
+    client
+        .gene_call(None, "map_1", Some(doc.to_string()))
+        .await
+        .unwrap();
+}
+
+/// Call map_1 with request.
+async fn call(client: &Client, request: Value) -> String {
+    client
+        .gene_call(None, "map_1", Some(request.to_string()))
+        .await
+        .unwrap()
+}
+
+/// Call map_1 without exiting on error, return the error and the body if any.
+async fn map_error(client: &Client, request: Value) -> Option<(String, String)> {
+    let plan = &client.config.plan;
+    let response = reqwest::Client::new()
+        .post(&client.config.url)
+        .header("type", "GeneCall")
+        .header("access", &client.config.session.as_ref().unwrap().access)
+        .header("time", plan.time.to_string())
+        .header("space", plan.space.to_string())
+        .header("traffic", plan.traffic.to_string())
+        .header("tip", plan.tip.to_string())
+        .header("gid", "map_1")
+        .header("arg", request.to_string())
+        .send()
+        .await
+        .unwrap();
+    let error = response
+        .headers()
+        .get("error")?
+        .to_str()
+        .unwrap()
+        .to_string();
+    Some((error, response.text().await.unwrap()))
+}
+
+/// Get docs by filter.
+async fn get(client: &Client, filter: Value) -> Vec<Value> {
+    get_page(client, filter).await.0
+}
+
+/// Get docs by filter, and the cursor of the next page.
+async fn get_page(client: &Client, filter: Value) -> (Vec<Value>, Option<String>) {
+    let mut request = filter;
+    request["_type"] = "Get".into();
+    let result = client
+        .gene_call(None, "map_1", Some(request.to_string()))
+        .await
+        .unwrap();
+    let result: Value = serde_json::from_str(&result).unwrap();
+    let result = result.as_object().unwrap();
+    let docs = (0..)
+        .map_while(|i: usize| result.get(&i.to_string()).cloned())
+        .collect();
+    let next = result
+        .get("_next")
+        .map(|next| next.as_str().unwrap().into());
+    (docs, next)
+}
+
+#[tokio::test]
+async fn map_get_ns() {
+    let (client, _) = new_user().await;
+    let (a, b) = (format!("{}a", ns()), format!("{}b", ns()));
+    put(&client, &a, json!({})).await;
+    put(&client, &b, json!({})).await;
+
+    assert_eq!(get(&client, json!({"_ns": a})).await.len(), 1);
+
+    // Range [a, a_) holds a and nothing else.
+    let docs = get(&client, json!({"_ns": a, "_ns_": format!("{}_", a)})).await;
+    assert_eq!(docs.len(), 1);
+    assert_eq!(docs[0]["_ns"], a);
+}
+
+#[tokio::test]
+async fn map_get_id_uid() {
+    let (client, uid) = new_user().await;
+    let ns = ns();
+    put(&client, &ns, json!({"k": 1})).await;
+    put(&client, &ns, json!({"k": 2})).await;
+
+    let docs = get(&client, json!({"_ns": ns, "_uid": uid})).await;
+    assert_eq!(docs.len(), 2);
+    assert!(docs.iter().all(|doc| doc["_uid"] == uid));
+
+    let id = docs[0]["_id"].clone();
+    let docs = get(&client, json!({"_id": id})).await;
+    assert_eq!(docs.len(), 1);
+    assert_eq!(docs[0]["_id"], id);
+}
+
+#[tokio::test]
+async fn map_get_indexed() {
+    let (client, _) = new_user().await;
+    let ns = ns();
+    for i in 0..5 {
+        put(&client, &ns, json!({"_0": i, "_7": format!("s{}", i)})).await;
+    }
+
+    let docs = get(&client, json!({"_ns": ns, "_0": 3})).await;
+    assert_eq!(docs.len(), 1);
+    assert_eq!(docs[0]["_0"], 3);
+
+    let docs = get(&client, json!({"_ns": ns, "_0": 1, "_0_": 4})).await;
+    assert_eq!(docs.len(), 3);
+
+    let docs = get(&client, json!({"_ns": ns, "_0_": 2})).await;
+    assert_eq!(docs.len(), 2);
+
+    let docs = get(&client, json!({"_ns": ns, "_7": "s1", "_7_": "s3"})).await;
+    assert_eq!(docs.len(), 2);
+}
+
+#[tokio::test]
+async fn map_get_managed() {
+    let (client, _) = new_user().await;
+    let ns = ns();
+    put(&client, &ns, json!({"k": "small"})).await;
+    put(&client, &ns, json!({"k": "x".repeat(2000)})).await;
+
+    let docs = get(&client, json!({"_ns": ns, "_size": 0, "_size_": 1000})).await;
+    assert_eq!(docs.len(), 1);
+    assert_eq!(docs[0]["k"], "small");
+
+    assert_eq!(get(&client, json!({"_ns": ns, "_tip": 0})).await.len(), 2);
+    assert_eq!(
+        get(&client, json!({"_ns": ns, "_pub": true})).await.len(),
+        0
+    );
+
+    let now = Utc::now().timestamp();
+    let docs = get(
+        &client,
+        json!({"_ns": ns, "_eol": now, "_eol_": now + 3 * 86400}),
+    )
+    .await;
+    assert_eq!(docs.len(), 2);
+    let docs = get(&client, json!({"_ns": ns, "_eol_": now})).await;
+    assert_eq!(docs.len(), 0);
+}
+
+#[tokio::test]
+async fn map_get_private() {
+    let (owner, _) = new_user().await;
+    let (other, _) = new_user().await;
+    let ns = ns();
+    put(&owner, &ns, json!({})).await;
+
+    assert_eq!(get(&owner, json!({"_ns": ns})).await.len(), 1);
+    assert_eq!(get(&other, json!({"_ns": ns})).await.len(), 0);
+}
+
+#[tokio::test]
+async fn map_get_geo() {
+    let (client, _) = new_user().await;
+    let ns = ns();
+    put(&client, &ns, json!({"_geo": [121.47, 31.23]})).await;
+    put(&client, &ns, json!({"_geo": [-74.0, 40.71]})).await;
+
+    let docs = get(
+        &client,
+        json!({"_ns": ns, "_geo": [120.0, 30.0, 122.0, 32.0]}),
+    )
+    .await;
+    assert_eq!(docs.len(), 1);
+    assert_eq!(docs[0]["_geo"], json!([121.47, 31.23]));
+}
+
+#[tokio::test]
+async fn map_get_n_v() {
+    let (client, _) = new_user().await;
+    let ns = ns();
+    for i in 0..3 {
+        put(&client, &ns, json!({"_1": i, "a": i, "b": i})).await;
+    }
+
+    assert_eq!(get(&client, json!({"_ns": ns, "_n": 2})).await.len(), 2);
+
+    let docs = get(&client, json!({"_ns": ns, "_v": ["a", "_1"]})).await;
+    assert_eq!(docs.len(), 3);
+    let mut keys: Vec<_> = docs[0].as_object().unwrap().keys().cloned().collect();
+    keys.sort();
+    assert_eq!(keys, ["_1", "_id", "a"]);
+}
+
+#[tokio::test]
+async fn map_get_page() {
+    let (client, _) = new_user().await;
+    let ns = ns();
+    for i in [3, 0, 4, 1, 2] {
+        put(&client, &ns, json!({"_0": i})).await;
+    }
+
+    for desc in [false, true] {
+        let mut seen = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let filter =
+                json!({"_ns": ns, "_n": 2, "_sort": "_0", "_desc": desc, "_cursor": cursor});
+            let (docs, next) = get_page(&client, filter).await;
+            seen.extend(docs.iter().map(|doc| doc["_0"].as_i64().unwrap()));
+            match next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        let mut expected: Vec<i64> = (0..5).collect();
+        if desc {
+            expected.reverse();
+        }
+        assert_eq!(seen, expected);
+    }
+
+    // A cursor belongs to its sort.
+    let (_, next) = get_page(&client, json!({"_ns": ns, "_n": 2, "_sort": "_0"})).await;
+    let request = json!({"_type": "Get", "_ns": ns, "_sort": "_eol", "_cursor": next});
+    assert_eq!(
+        map_error(&client, request).await.unwrap().0,
+        "GeneMapCursor"
+    );
+}
+
+#[tokio::test]
+async fn map_get_geo_radius() {
+    let (client, _) = new_user().await;
+    let ns = ns();
+    put(
+        &client,
+        &ns,
+        json!({"_geo": [120.58, 31.30], "city": "Suzhou"}),
+    )
+    .await;
+    put(
+        &client,
+        &ns,
+        json!({"_geo": [121.47, 31.23], "city": "Shanghai"}),
+    )
+    .await;
+    put(
+        &client,
+        &ns,
+        json!({"_geo": [116.40, 39.90], "city": "Beijing"}),
+    )
+    .await;
+
+    let filter = json!({"_ns": ns, "_geo": [121.47, 31.23, 100_000.0], "_sort": "_dist"});
+    let docs = get(&client, filter).await;
+    assert_eq!(docs.len(), 2);
+    assert_eq!(docs[0]["city"], "Shanghai");
+    assert_eq!(docs[1]["city"], "Suzhou");
+    let dist = docs[1]["_dist"].as_f64().unwrap();
+    assert!((80_000.0..90_000.0).contains(&dist));
+
+    // Across the antimeridian.
+    let ns = self::ns();
+    put(&client, &ns, json!({"_geo": [179.5, 0.0]})).await;
+    put(&client, &ns, json!({"_geo": [-179.5, 0.0]})).await;
+    put(&client, &ns, json!({"_geo": [0.0, 0.0]})).await;
+    let docs = get(
+        &client,
+        json!({"_ns": ns, "_geo": [179.0, -1.0, -179.0, 1.0]}),
+    )
+    .await;
+    assert_eq!(docs.len(), 2);
+    let docs = get(&client, json!({"_ns": ns, "_geo": [179.9, 0.0, 200_000.0]})).await;
+    assert_eq!(docs.len(), 2);
+
+    // Out of range.
+    let request = json!({"_type": "Get", "_ns": ns, "_geo": [0.0, 91.0, 1.0]});
+    assert_eq!(map_error(&client, request).await.unwrap().0, "GeoRange");
+}
+
+#[tokio::test]
+async fn map_patch() {
+    let (client, _) = new_user().await;
+    let ns = ns();
+    put(&client, &ns, json!({"_0": 1, "k": "v", "n": {"a": 1}})).await;
+    let doc = get(&client, json!({"_ns": ns})).await.remove(0);
+    assert_eq!(doc["_version"], 0);
+
+    let patch = json!({
+        "_type": "Patch",
+        "_id": doc["_id"],
+        "_version": 0,
+        "_merge": {"k": null, "m": "w"},
+        "_ops": [{"op": "add", "path": "/n/b", "value": 2}],
+        "_incr": {"_0": 2, "count": 1},
+    });
+    let result = client
+        .gene_call(None, "map_1", Some(patch.to_string()))
+        .await
+        .unwrap();
+    let result: Value = serde_json::from_str(&result).unwrap();
+    assert_eq!(result["_version"], 1);
+
+    let doc = get(&client, json!({"_ns": ns})).await.remove(0);
+    assert_eq!(doc["_0"], 3);
+    assert_eq!(doc["count"], 1);
+    assert_eq!(doc["m"], "w");
+    assert_eq!(doc["n"], json!({"a": 1, "b": 2}));
+    assert!(doc.get("k").is_none());
+    assert_eq!(doc["_pub"], false);
+
+    // The same version again is stale.
+    assert_eq!(map_error(&client, patch).await.unwrap().0, "GeneMapVersion");
+}
+
+#[tokio::test]
+async fn map_patch_shrink() {
+    let (client, _) = new_user().await;
+    let ns = ns();
+    let eol = (Utc::now() + Duration::days(300)).timestamp();
+    let big = "x".repeat(100_000);
+    let credit = || async { client.cost_get().await.unwrap().parse::<u64>().unwrap() };
+    let start = credit().await;
+    call(
+        &client,
+        json!({"_type": "Put", "_eol": eol, "_ns": ns, "k": big}),
+    )
+    .await;
+    let doc = get(&client, json!({"_ns": ns})).await.remove(0);
+
+    // The space refund is more than the call costs, so the credit grows.
+    let before = credit().await;
+    call(
+        &client,
+        json!({"_type": "Patch", "_id": doc["_id"], "_merge": {"k": ""}}),
+    )
+    .await;
+    assert!(credit().await > before);
+
+    // Refunds never exceed what was paid, however often the doc changes size.
+    for _ in 0..3 {
+        let grow = json!({"_type": "Patch", "_id": doc["_id"], "_merge": {"k": big}});
+        call(&client, grow).await;
+        let shrink = json!({"_type": "Patch", "_id": doc["_id"], "_merge": {"k": ""}});
+        call(&client, shrink).await;
+    }
+    call(&client, json!({"_type": "Drop", "_id": doc["_id"]})).await;
+    assert!(credit().await < start);
+}
+
+#[tokio::test]
+async fn map_batch() {
+    let (client, _) = new_user().await;
+    let ns = ns();
+    let eol = (Utc::now() + Duration::days(2)).timestamp();
+    let doc = |k: i64| json!({"_type": "Put", "_eol": eol, "_ns": ns, "k": k});
+
+    let batch = json!({"_type": "Batch", "_ops": [doc(1), doc(2), {"_type": "Get", "_ns": ns}]});
+    let result = client
+        .gene_call(None, "map_1", Some(batch.to_string()))
+        .await
+        .unwrap();
+    let result: Value = serde_json::from_str(&result).unwrap();
+    assert_eq!(result.as_array().unwrap().len(), 3);
+    assert_eq!(result[2]["1"]["_ns"], ns);
+
+    // A bad Patch undoes the Put before it.
+    let patch = json!({"_type": "Patch", "_id": "bad"});
+    let batch = json!({"_type": "Batch", "_ops": [doc(3), patch]});
+    assert_eq!(
+        map_error(&client, batch).await.unwrap().0,
+        "GeneMapNotFound"
+    );
+    assert_eq!(get(&client, json!({"_ns": ns})).await.len(), 2);
+
+    // No batches in batches.
+    let batch = json!({"_type": "Batch", "_ops": [{"_type": "Batch", "_ops": []}]});
+    assert_eq!(map_error(&client, batch).await.unwrap().0, "GeneMapBatch");
+}
+
+#[tokio::test]
+async fn map_aggregate() {
+    let (client, _) = new_user().await;
+    let ns = ns();
+    for (kind, n) in [
+        ("a", json!(1)),
+        ("a", json!(2)),
+        ("b", json!(3)),
+        ("b", json!("x")),
+    ] {
+        put(&client, &ns, json!({"_0": kind, "_1": n})).await;
+    }
+
+    let aggregate = |filter: Value| {
+        let mut request = filter;
+        request["_type"] = "Aggregate".into();
+        request["_ns"] = ns.clone().into();
+        let client = &client;
+        async move {
+            let result = client
+                .gene_call(None, "map_1", Some(request.to_string()))
+                .await
+                .unwrap();
+            serde_json::from_str::<Value>(&result).unwrap()
+        }
+    };
+
+    let result = aggregate(json!({"_of": "_1"})).await;
+    assert_eq!(result["count"], 4);
+    assert_eq!(result["sum"], 6.0);
+    assert_eq!(result["min"], 1.0);
+    assert_eq!(result["max"], 3.0);
+    assert_eq!(result["avg"], 2.0);
+
+    let result = aggregate(json!({"_of": "_1", "_by": "_0"})).await;
+    let groups = result["groups"].as_array().unwrap();
+    assert_eq!(groups.len(), 2);
+    assert_eq!(groups[0]["key"], "a");
+    assert_eq!(groups[0]["count"], 2);
+    assert_eq!(groups[0]["sum"], 3.0);
+    assert_eq!(groups[1]["count"], 2);
+    assert_eq!(groups[1]["avg"], 3.0);
+
+    let result = aggregate(json!({"_0": "b"})).await;
+    assert_eq!(result["count"], 2);
+
+    // Tipped docs of others are left out even where readable, but not own ones.
+    let (other, other_uid) = new_user().await;
+    call(&client, json!({"_type": "Claim", "_ns": ns})).await;
+    let grant = json!({"_type": "Grant", "_ns": ns, "_uid": other_uid, "_level": "write"});
+    call(&client, grant).await;
+    let eol = (Utc::now() + Duration::days(2)).timestamp();
+    let tipped = json!({"_type": "Put", "_eol": eol, "_ns": ns, "_tip": 1, "_0": "c", "_1": 9});
+    call(&other, tipped.clone()).await;
+    call(&client, tipped).await;
+    let result = aggregate(json!({"_of": "_1", "_by": "_0"})).await;
+    let groups = result["groups"].as_array().unwrap();
+    assert_eq!(groups.len(), 3);
+    assert_eq!(groups[2]["count"], 1);
+    let result = aggregate(json!({"_of": "_1"})).await;
+    assert_eq!(result["count"], 5);
+    assert_eq!(result["max"], 9.0);
+}
+
+#[tokio::test]
+async fn map_schema() {
+    let (owner, _) = new_user().await;
+    let (other, other_uid) = new_user().await;
+    let ns = ns();
+    let schema = json!({
+        "type": "object",
+        "properties": {"_0": {"type": "integer"}, "name": {"type": "string"}},
+        "required": ["name"],
+    });
+    let eol = (Utc::now() + Duration::days(2)).timestamp();
+
+    // Only claimed namespaces have schemas.
+    let unclaimed = json!({"_type": "Put", "_eol": eol, "_ns": "_schema", "_0": ns, "schema": {}});
+    assert_eq!(map_error(&owner, unclaimed).await.unwrap().0, "Namespace");
+    call(&owner, json!({"_type": "Claim", "_ns": ns})).await;
+    let grant = json!({"_type": "Grant", "_ns": ns, "_level": "write"});
+    call(&owner, grant).await;
+    put(&owner, "_schema", json!({"_0": ns, "schema": schema})).await;
+
+    put(&owner, &ns, json!({"_0": 1, "name": "a"})).await;
+    put(&other, &ns, json!({"name": "b"})).await;
+
+    let bad = json!({"_type": "Put", "_eol": eol, "_ns": ns, "_0": "x"});
+    let (error, body) = map_error(&other, bad.clone()).await.unwrap();
+    assert_eq!(error, "GeneMapSchema");
+    assert!(body.contains("/_0"));
+    assert!(body.contains("name"));
+
+    // Patches are checked too.
+    let doc = get(&owner, json!({"_ns": ns, "_0": 1})).await.remove(0);
+    let patch = json!({"_type": "Patch", "_id": doc["_id"], "_merge": {"name": null}});
+    assert_eq!(map_error(&owner, patch).await.unwrap().0, "GeneMapSchema");
+
+    // The schema of ns is kept by its admins.
+    let steal = json!({"_type": "Put", "_eol": eol, "_ns": "_schema", "_0": ns, "schema": {}});
+    assert_eq!(map_error(&other, steal).await.unwrap().0, "Namespace");
+
+    // A new version of the schema takes effect.
+    let id = get(&owner, json!({"_ns": "_schema", "_0": ns})).await[0]["_id"].clone();
+    let lax =
+        json!({"_type": "Put", "_id": id, "_eol": eol, "_ns": "_schema", "_0": ns, "schema": {}});
+    call(&owner, lax).await;
+    put(&other, &ns, json!({"_0": "x"})).await;
+
+    // Other admins replace it, and the owner drops theirs.
+    let grant = json!({"_type": "Grant", "_ns": ns, "_uid": other_uid, "_level": "admin"});
+    call(&owner, grant).await;
+    put(&other, "_schema", json!({"_0": ns, "schema": schema})).await;
+    let schemas = get(&other, json!({"_ns": "_schema", "_0": ns})).await;
+    assert_eq!(schemas.len(), 1);
+    assert_eq!(
+        map_error(&owner, bad.clone()).await.unwrap().0,
+        "GeneMapSchema"
+    );
+    call(&owner, json!({"_type": "Drop", "_id": schemas[0]["_id"]})).await;
+    call(&owner, bad).await;
+}
+
+/// A schema left by others blocks a Claim, as their docs do.
+#[tokio::test]
+async fn map_schema_claim() {
+    let (owner, owner_uid) = new_user().await;
+    let (other, other_uid) = new_user().await;
+    let (third, _) = new_user().await;
+    let ns = ns();
+
+    call(&owner, json!({"_type": "Claim", "_ns": ns})).await;
+    let grant = json!({"_type": "Grant", "_ns": ns, "_uid": other_uid, "_level": "admin"});
+    call(&owner, grant).await;
+    put(&other, "_schema", json!({"_0": ns, "schema": {}})).await;
+
+    // The ns is unclaimed with its owner gone, the schema of other stays.
+    let db = Database::default().await;
+    let owner_uid = Id::from_str(&owner_uid).unwrap();
+    db.delete_account(&owner_uid).await.unwrap();
+    let claim = json!({"_type": "Claim", "_ns": ns});
+    assert_eq!(
+        map_error(&third, claim.clone()).await.unwrap().0,
+        "Namespace"
+    );
+    call(&other, claim).await;
+}
+
+#[tokio::test]
+async fn map_acl() {
+    let (owner, owner_uid) = new_user().await;
+    let (other, other_uid) = new_user().await;
+    let (third, _) = new_user().await;
+    let ns = ns();
+
+    call(&owner, json!({"_type": "Claim", "_ns": ns})).await;
+    put(&owner, &ns, json!({})).await;
+    let eol = (Utc::now() + Duration::days(2)).timestamp();
+    let doc = json!({"_type": "Put", "_eol": eol, "_ns": ns});
+    assert_eq!(map_error(&other, doc.clone()).await.unwrap().0, "Namespace");
+    assert_eq!(get(&other, json!({"_ns": ns})).await.len(), 0);
+
+    // Writers read too.
+    let grant = json!({"_type": "Grant", "_ns": ns, "_uid": other_uid, "_level": "write"});
+    call(&owner, grant).await;
+    call(&other, doc).await;
+    assert_eq!(get(&other, json!({"_ns": ns})).await.len(), 2);
+    assert_eq!(get(&third, json!({"_ns": ns})).await.len(), 0);
+
+    let grant = json!({"_type": "Grant", "_ns": ns, "_level": "read"});
+    assert_eq!(
+        map_error(&other, grant.clone()).await.unwrap().0,
+        "Namespace"
+    );
+    call(&owner, grant).await;
+    assert_eq!(get(&third, json!({"_ns": ns})).await.len(), 2);
+
+    // Only admins drop docs of others.
+    let docs = get(&owner, json!({"_ns": ns, "_uid": owner_uid})).await;
+    let drop = json!({"_type": "Drop", "_id": docs[0]["_id"]});
+    assert_eq!(
+        map_error(&other, drop.clone()).await.unwrap().0,
+        "GeneMapNotFound"
+    );
+    let grant = json!({"_type": "Grant", "_ns": ns, "_uid": other_uid, "_level": "admin"});
+    call(&owner, grant).await;
+    call(&other, drop).await;
+    assert_eq!(get(&third, json!({"_ns": ns})).await.len(), 1);
+
+    // Namespaces with docs of others can't be claimed.
+    let claim = json!({"_type": "Claim", "_ns": ns});
+    assert_eq!(map_error(&third, claim).await.unwrap().0, "Namespace");
+}
+
+/// Events of a subscription, which chunks may split or join.
+struct Feed {
+    response: reqwest::Response,
+    buf: Vec<u8>,
+}
+
+impl Feed {
+    fn new(response: reqwest::Response) -> Feed {
+        Feed {
+            response,
+            buf: vec![],
+        }
+    }
+}
+
+/// Next event of a subscription, skipping pings.
+async fn event(feed: &mut Feed) -> Value {
+    loop {
+        if let Some(end) = feed.buf.windows(2).position(|w| w == b"\n\n") {
+            let event: Vec<u8> = feed.buf.drain(..end + 2).collect();
+            let event = String::from_utf8(event).unwrap();
+            if let Some(data) = event.strip_prefix("data: ") {
+                return serde_json::from_str(data.trim_end()).unwrap();
+            }
+            continue;
+        }
+        let chunk = feed.response.chunk().await.unwrap().unwrap();
+        feed.buf.extend_from_slice(&chunk);
+    }
+}
+
+#[tokio::test]
+async fn map_subscribe() {
+    let (client, _) = new_user().await;
+    let (other, _) = new_user().await;
+    let ns = ns();
+    let filter = json!({"_ns": ns, "_0": "a"}).to_string();
+    let mut feed = Feed::new(client.gene_subscribe("map_1", Some(filter)).await.unwrap());
+    let mut other_feed = Feed::new(
+        other
+            .gene_subscribe("map_1", Some(json!({"_ns": ns}).to_string()))
+            .await
+            .unwrap(),
+    );
+
+    put(&client, &ns, json!({"_0": "b"})).await;
+    put(&client, &ns, json!({"_0": "a", "k": 1})).await;
+    let put_event = event(&mut feed).await;
+    assert_eq!(put_event["_op"], "Put");
+    assert_eq!(put_event["k"], 1);
+
+    let id = &put_event["_id"];
+    call(
+        &client,
+        json!({"_type": "Patch", "_id": id, "_incr": {"k": 1}}),
+    )
+    .await;
+    let patch_event = event(&mut feed).await;
+    assert_eq!(patch_event["_op"], "Patch");
+    assert_eq!(patch_event["k"], 2);
+
+    call(&client, json!({"_type": "Drop", "_id": id})).await;
+    assert_eq!(event(&mut feed).await, json!({"_id": id, "_op": "Drop"}));
+
+    // Drops are filtered too, so the next event is the Put after it.
+    let other_id = &get(&client, json!({"_ns": ns, "_0": "b"})).await[0]["_id"];
+    call(&client, json!({"_type": "Drop", "_id": other_id})).await;
+    put(&client, &ns, json!({"_0": "a", "k": 3})).await;
+    assert_eq!(event(&mut feed).await["k"], 3);
+
+    // Private docs of others are skipped.
+    put(&other, &ns, json!({"_0": "c"})).await;
+    let own_event = event(&mut other_feed).await;
+    assert_eq!(own_event["_0"], "c");
+}
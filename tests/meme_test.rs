@@ -1,4 +1,3 @@
-use blake3;
 use chrono::{DateTime, Duration, Utc};
 use serde_json::Value;
 use std::collections::HashMap;
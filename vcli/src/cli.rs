@@ -41,15 +41,82 @@ pub enum Command {
 #[derive(Subcommand)]
 pub enum AuthCommand {
     /// Interactively authenticate with SMS
-    Sms,
+    Sms {
+        /// TOTP or recovery code, if enabled.
+        #[arg(long)]
+        totp: Option<String>,
+    },
+    /// Interactively move the account to a new phone.
+    Phone {
+        /// TOTP or recovery code, if enabled.
+        #[arg(long)]
+        totp: Option<String>,
+    },
+    /// Interactively authenticate with a magic link mailed to ADDRESS.
+    Email {
+        address: String,
+        /// TOTP or recovery code, if enabled.
+        #[arg(long)]
+        totp: Option<String>,
+    },
     /// Skip authentication, and set phone.
     Skip { phone: String },
+    /// Browse public data without a phone.
+    Anonymous,
+    /// List logins of the user.
+    Sessions,
+    /// End this login. --all ends every login of the user.
+    Logout {
+        #[arg(long)]
+        all: bool,
+        /// TOTP or recovery code, if enabled and --all.
+        #[arg(long)]
+        totp: Option<String>,
+    },
+    /// End the login with HANDLE from `auth sessions`.
+    Revoke {
+        handle: String,
+        /// TOTP or recovery code, if enabled.
+        #[arg(long)]
+        totp: Option<String>,
+    },
+    /// Export all data, then delete the account after a grace period.
+    Delete {
+        /// Keep the account instead.
+        #[arg(long)]
+        cancel: bool,
+        /// TOTP or recovery code, if enabled.
+        #[arg(long)]
+        totp: Option<String>,
+    },
+    /// Second factor related subcommands.
+    Totp {
+        #[command(subcommand)]
+        command: TotpCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TotpCommand {
+    /// Interactively enroll. Replacing an enabled one needs its code.
+    Enroll {
+        #[arg(long)]
+        totp: Option<String>,
+    },
+    /// Enable with the CODE from the app.
+    Verify { code: String },
+    /// Disable with a CODE or a recovery code.
+    Disable { code: String },
 }
 
 #[derive(Subcommand)]
 pub enum CostCommand {
     /// Get the link to pay.
-    Pay,
+    Pay {
+        /// TOTP or recovery code, if enabled.
+        #[arg(long)]
+        totp: Option<String>,
+    },
     /// Get the account balance.
     Get,
 }
@@ -60,6 +127,8 @@ pub enum GeneCommand {
     Meta { gid: String },
     /// Call the gene with ARG.
     Call { gid: String, arg: Option<String> },
+    /// Subscribe to the gene with ARG, printing events.
+    Subscribe { gid: String, arg: Option<String> },
 }
 
 #[derive(Subcommand)]
@@ -75,4 +144,18 @@ pub enum MemeCommand {
         hash: String,
         file: Option<String>,
     },
+    /// Clone meme by HASH from peer FED, then keep DAYS days. -p means public meme.
+    Clone {
+        #[arg(short, long)]
+        public: bool,
+        fed: String,
+        hash: String,
+        days: u32,
+    },
+    /// Let peer PEER clone the private meme by HASH for TTL seconds.
+    Visa {
+        hash: String,
+        peer: String,
+        ttl: u32,
+    },
 }
@@ -100,23 +100,111 @@ impl Client {
         };
     }
 
+    /// Signed access tokens keep the uid they were issued for,
+    /// so get a new one after authentication.
+    async fn rebind_access(&mut self) -> Result<()> {
+        if self.get_access()?.contains('.') {
+            let access = self.auth_session_refresh().await?;
+            self.config.session.as_mut().unwrap().set_access(&access);
+            self.config.save();
+        }
+        Ok(())
+    }
+
     /// Authenticate interactively.
-    pub async fn auth_sms(&self) -> Result<String> {
+    pub async fn auth_sms(&mut self, totp: Option<&str>) -> Result<String> {
         let (phone, message) = self.auth_sms_send_to().await?;
         println!("Send SMS message {} to {}.", message, phone);
         println!("Press enter after sent.");
         let mut s = "".to_string();
         let _ = stdin().read_line(&mut s);
-        let uid = self.auth_sms_sent(&phone, &message).await?;
+        let uid = self.auth_sms_sent(&phone, &message, totp).await?;
+        self.rebind_access().await?;
+        Ok(format!("Your user ID is {}", uid))
+    }
+
+    /// Change phone interactively.
+    pub async fn auth_phone(&self, totp: Option<&str>) -> Result<String> {
+        let (phone, message) = self.auth_sms_send_to().await?;
+        println!(
+            "Send SMS message {} to {} from your new phone.",
+            message, phone
+        );
+        println!("Press enter after sent.");
+        let mut s = "".to_string();
+        let _ = stdin().read_line(&mut s);
+        self.auth_phone_change(&phone, &message, totp).await?;
+        Ok("Phone changed".to_string())
+    }
+
+    /// Authenticate interactively with email.
+    pub async fn auth_email(&mut self, email: &str, totp: Option<&str>) -> Result<String> {
+        let token = self.auth_email_start(email).await?;
+        println!("Open the link mailed to {}, then press enter.", email);
+        println!("Or paste the code from the mail.");
+        let mut s = "".to_string();
+        let _ = stdin().read_line(&mut s);
+        let code = Some(s.trim()).filter(|s| !s.is_empty());
+        let uid = self.auth_email_verify(&token, code, totp).await?;
+        self.rebind_access().await?;
         Ok(format!("Your user ID is {}", uid))
     }
 
     /// Skip authentication.
-    pub async fn auth_skip(&self, phone: &str) -> Result<String> {
-        let uid = self.auth_sms_sent(phone, "").await?;
+    pub async fn auth_skip(&mut self, phone: &str) -> Result<String> {
+        let uid = self.auth_sms_sent(phone, "", None).await?;
+        self.rebind_access().await?;
         Ok(format!("Your user ID is {}", uid))
     }
 
+    /// Browse public data without a phone, until `auth sms` or `auth email`.
+    pub async fn auth_anonymous(&mut self) -> Result<String> {
+        let uid = self.auth_session_anonymous().await?;
+        self.rebind_access().await?;
+        Ok(format!("Your anonymous user ID is {}", uid))
+    }
+
+    /// End this login, or every login of the user, and forget the tokens.
+    pub async fn auth_logout(&mut self, all: bool, totp: Option<&str>) -> Result<String> {
+        if all {
+            self.auth_session_revoke_all(totp).await?;
+        } else {
+            self.auth_session_end(true).await?;
+        }
+        self.config.session = None;
+        self.config.save();
+        Ok("Logged out".to_string())
+    }
+
+    /// Schedule or cancel account deletion.
+    pub async fn auth_delete(&self, cancel: bool, totp: Option<&str>) -> Result<String> {
+        if cancel {
+            self.auth_account_delete_cancel().await?;
+            return Ok("Account deletion cancelled".to_string());
+        }
+        let (archive, due) = self.auth_account_delete(totp).await?;
+        Ok(format!(
+            "Your data is exported to meme {}.\nThe account will be deleted at {}, run `auth delete --cancel` to keep it.",
+            archive, due
+        ))
+    }
+
+    /// Enroll TOTP interactively.
+    pub async fn auth_totp_enroll_interactive(&self, totp: Option<&str>) -> Result<String> {
+        let (secret, uri, recovery) = self.auth_totp_enroll(totp).await?;
+        println!("Add this to your authenticator app:\n{}", uri);
+        println!("Or enter the secret: {}", secret);
+        println!("Keep these recovery codes safe:");
+        for code in recovery.split(',') {
+            println!("  {}", code);
+        }
+        println!("Then enter the code from the app:");
+        let mut s = "".to_string();
+        let _ = stdin().read_line(&mut s);
+        self.auth_totp_verify(s.trim()).await?;
+        Ok("TOTP enabled".to_string())
+    }
+
     /// Print cost based on plan and returned changes.
     pub fn eprint_cost(&self, response: &Response) -> Result<()> {
         macro_rules! get {
@@ -42,6 +42,62 @@ impl Client {
         Ok(())
     }
 
+    /// List tokens of the user as JSON.
+    pub async fn auth_session_list(&self) -> Result<String> {
+        let response = self
+            .post()
+            .header("type", "AuthSessionList")
+            .header("access", &self.get_access()?)
+            .send()
+            .await?;
+        handle_error!(response);
+        Ok(response.text().await?)
+    }
+
+    /// Revoke a login by its handle.
+    pub async fn auth_session_revoke(&self, handle: &str, totp: Option<&str>) -> Result<()> {
+        let mut builder = self
+            .post()
+            .header("type", "AuthSessionRevoke")
+            .header("access", &self.get_access()?)
+            .header("handle", handle);
+        if let Some(totp) = totp {
+            builder = builder.header("totp", totp);
+        }
+        let response = builder.send().await?;
+        handle_error!(response);
+        Ok(())
+    }
+
+    /// Revoke every login, including this one.
+    pub async fn auth_session_revoke_all(&self, totp: Option<&str>) -> Result<()> {
+        let mut builder = self
+            .post()
+            .header("type", "AuthSessionRevokeAll")
+            .header("access", &self.get_access()?);
+        if let Some(totp) = totp {
+            builder = builder.header("totp", totp);
+        }
+        let response = builder.send().await?;
+        handle_error!(response);
+        Ok(())
+    }
+
+    /// Bind this session to a new anonymous account.
+    pub async fn auth_session_anonymous(&self) -> Result<String> {
+        let response = self
+            .post()
+            .header("type", "AuthSessionAnonymous")
+            .header("access", &self.get_access()?)
+            .header("refresh", &self.get_refresh()?)
+            .header("label", session_label())
+            .send()
+            .await?;
+        handle_error!(response);
+        let uid = get_header(&response, "uid");
+        Ok(uid)
+    }
+
     /// Get where to send SMS.
     pub async fn auth_sms_send_to(&self) -> Result<(String, String)> {
         let response = self
@@ -58,18 +114,163 @@ impl Client {
     }
 
     /// Notify the server that SMS is sent.
-    pub async fn auth_sms_sent(&self, phone: &str, message: &str) -> Result<String> {
-        let response = self
+    pub async fn auth_sms_sent(
+        &self,
+        phone: &str,
+        message: &str,
+        totp: Option<&str>,
+    ) -> Result<String> {
+        let mut builder = self
             .post()
             .header("type", "AuthSmsSent")
             .header("access", &self.get_access()?)
             .header("refresh", &self.get_refresh()?)
             .header("phone", phone)
             .header("message", message)
+            .header("label", session_label());
+        if let Some(totp) = totp {
+            builder = builder.header("totp", totp);
+        }
+        let response = builder.send().await?;
+        handle_error!(response);
+        let uid = get_header(&response, "uid");
+        Ok(uid)
+    }
+
+    /// Notify the server that SMS is sent from the new phone.
+    pub async fn auth_phone_change(
+        &self,
+        phone: &str,
+        message: &str,
+        totp: Option<&str>,
+    ) -> Result<()> {
+        let mut builder = self
+            .post()
+            .header("type", "AuthPhoneChange")
+            .header("access", &self.get_access()?)
+            .header("phone", phone)
+            .header("message", message);
+        if let Some(totp) = totp {
+            builder = builder.header("totp", totp);
+        }
+        let response = builder.send().await?;
+        handle_error!(response);
+        Ok(())
+    }
+
+    /// Ask the server to mail a magic link.
+    pub async fn auth_email_start(&self, email: &str) -> Result<String> {
+        let response = self
+            .post()
+            .header("type", "AuthEmailStart")
+            .header("access", &self.get_access()?)
+            .header("email", email)
             .send()
             .await?;
         handle_error!(response);
+        let token = get_header(&response, "token");
+        Ok(token)
+    }
+
+    /// Notify the server that the link is opened, or send the mailed code.
+    pub async fn auth_email_verify(
+        &self,
+        token: &str,
+        code: Option<&str>,
+        totp: Option<&str>,
+    ) -> Result<String> {
+        let mut builder = self
+            .post()
+            .header("type", "AuthEmailVerify")
+            .header("access", &self.get_access()?)
+            .header("refresh", &self.get_refresh()?)
+            .header("token", token)
+            .header("label", session_label());
+        if let Some(code) = code {
+            builder = builder.header("code", code);
+        }
+        if let Some(totp) = totp {
+            builder = builder.header("totp", totp);
+        }
+        let response = builder.send().await?;
+        handle_error!(response);
         let uid = get_header(&response, "uid");
         Ok(uid)
     }
+
+    /// Schedule account deletion, returns the archive hash and due time.
+    pub async fn auth_account_delete(&self, totp: Option<&str>) -> Result<(String, String)> {
+        let mut builder = self
+            .post()
+            .header("type", "AuthAccountDelete")
+            .header("access", &self.get_access()?);
+        if let Some(totp) = totp {
+            builder = builder.header("totp", totp);
+        }
+        let response = builder.send().await?;
+        handle_error!(response);
+        let archive = get_header(&response, "archive");
+        let due = get_header(&response, "due");
+        Ok((archive, due))
+    }
+
+    /// Cancel scheduled account deletion.
+    pub async fn auth_account_delete_cancel(&self) -> Result<()> {
+        let response = self
+            .post()
+            .header("type", "AuthAccountDeleteCancel")
+            .header("access", &self.get_access()?)
+            .send()
+            .await?;
+        handle_error!(response);
+        Ok(())
+    }
+
+    /// Start TOTP enrollment, returns the secret, URI and recovery codes.
+    pub async fn auth_totp_enroll(&self, totp: Option<&str>) -> Result<(String, String, String)> {
+        let mut builder = self
+            .post()
+            .header("type", "AuthTotpEnroll")
+            .header("access", &self.get_access()?);
+        if let Some(totp) = totp {
+            builder = builder.header("totp", totp);
+        }
+        let response = builder.send().await?;
+        handle_error!(response);
+        let secret = get_header(&response, "secret");
+        let uri = get_header(&response, "uri");
+        let recovery = get_header(&response, "recovery");
+        Ok((secret, uri, recovery))
+    }
+
+    /// Enable TOTP with the first code.
+    pub async fn auth_totp_verify(&self, code: &str) -> Result<()> {
+        let response = self
+            .post()
+            .header("type", "AuthTotpVerify")
+            .header("access", &self.get_access()?)
+            .header("code", code)
+            .send()
+            .await?;
+        handle_error!(response);
+        Ok(())
+    }
+
+    /// Disable TOTP with a code or a recovery code.
+    pub async fn auth_totp_disable(&self, code: &str) -> Result<()> {
+        let response = self
+            .post()
+            .header("type", "AuthTotpDisable")
+            .header("access", &self.get_access()?)
+            .header("code", code)
+            .send()
+            .await?;
+        handle_error!(response);
+        Ok(())
+    }
+}
+
+/// Name this device in the session list.
+fn session_label() -> String {
+    format!("vcli on {}", std::env::consts::OS)
 }
@@ -3,14 +3,16 @@ use crate::handle_error;
 
 impl Client {
     /// Get the link to pay.
-    pub async fn cost_pay(&self) -> Result<String> {
-        let response = self
+    pub async fn cost_pay(&self, totp: Option<&str>) -> Result<String> {
+        let mut builder = self
             .post()
             .header("type", "CostPay")
             .header("access", &self.get_access()?)
-            .header("vendor", "00000000000000000000000000000000")
-            .send()
-            .await?;
+            .header("vendor", "00000000000000000000000000000000");
+        if let Some(totp) = totp {
+            builder = builder.header("totp", totp);
+        }
+        let response = builder.send().await?;
         handle_error!(response);
         let uri = get_header(&response, "uri");
         Ok(uri)
@@ -1,6 +1,10 @@
 use super::{Client, Result, get_header};
 use crate::handle_error;
-use std::{fs::File, io::Read};
+use reqwest::Response;
+use std::{
+    fs::File,
+    io::{Read, Write},
+};
 
 impl Client {
     /// Get functions metadata.
@@ -35,6 +39,31 @@ impl Client {
         Ok(response.text().await?)
     }
 
+    /// Subscribe to function, the response streams server-sent events.
+    pub async fn gene_subscribe(&self, gid: &str, arg: Option<String>) -> Result<Response> {
+        let response = self
+            .post_head(None)
+            .header("type", "GeneSubscribe")
+            .header("gid", gid)
+            .header("arg", arg.unwrap_or_default())
+            .send()
+            .await?;
+        handle_error!(response);
+        self.eprint_cost(&response)?;
+        Ok(response)
+    }
+
+    /// Print events until the subscription ends.
+    pub async fn gene_subscribe_print(&self, gid: &str, arg: Option<String>) -> Result<String> {
+        let mut response = self.gene_subscribe(gid, arg).await?;
+        let mut stdout = std::io::stdout();
+        while let Some(chunk) = response.chunk().await? {
+            stdout.write_all(&chunk)?;
+            stdout.flush()?;
+        }
+        Ok("".into())
+    }
+
     /// Map operations.
     pub async fn gene_map_1(&self, file: Option<String>) -> Result<String> {
         match file {
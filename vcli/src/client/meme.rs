@@ -107,4 +107,41 @@ impl Client {
             }
         }
     }
+
+    /// Clone a meme from a peer.
+    pub async fn meme_clone(
+        &self,
+        public: bool,
+        fed: String,
+        hash: String,
+        days: u32,
+    ) -> Result<String> {
+        let response = self
+            .post_head(Some(fed))
+            .header("type", "FedMemeClone")
+            .header("hash", hash)
+            .header("days", days)
+            .header("public", public.to_string())
+            .send()
+            .await?;
+        handle_error!(response);
+        self.eprint_cost(&response)?;
+        let hash = get_header(&response, "hash");
+        Ok(hash)
+    }
+
+    /// Grant a peer a visa for a private meme.
+    pub async fn meme_visa(&self, hash: String, peer: String, ttl: u32) -> Result<String> {
+        let response = self
+            .post_head(None)
+            .header("type", "FedMemeVisa")
+            .header("hash", hash)
+            .header("peer", peer)
+            .header("ttl", ttl)
+            .send()
+            .await?;
+        handle_error!(response);
+        self.eprint_cost(&response)?;
+        Ok("".into())
+    }
 }
@@ -1,28 +1,56 @@
 use clap::Parser;
 use std::process::exit;
 use vcli::{
-    cli::{AuthCommand, Cli, Command, CostCommand, GeneCommand, MemeCommand},
+    cli::{AuthCommand, Cli, Command, CostCommand, GeneCommand, MemeCommand, TotpCommand},
     client::Client,
 };
 
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
-    let client = Client::default().await;
+    let mut client = Client::default().await;
 
     let result = match cli.command {
         Command::Ping => client.ping().await,
         Command::Auth { command } => match command {
-            AuthCommand::Sms => client.auth_sms().await,
+            AuthCommand::Sms { totp } => client.auth_sms(totp.as_deref()).await,
+            AuthCommand::Phone { totp } => client.auth_phone(totp.as_deref()).await,
+            AuthCommand::Email { address, totp } => {
+                client.auth_email(&address, totp.as_deref()).await
+            }
             AuthCommand::Skip { phone } => client.auth_skip(&phone).await,
+            AuthCommand::Anonymous => client.auth_anonymous().await,
+            AuthCommand::Sessions => client.auth_session_list().await,
+            AuthCommand::Logout { all, totp } => client.auth_logout(all, totp.as_deref()).await,
+            AuthCommand::Revoke { handle, totp } => client
+                .auth_session_revoke(&handle, totp.as_deref())
+                .await
+                .map(|_| "Revoked".to_string()),
+            AuthCommand::Delete { cancel, totp } => {
+                client.auth_delete(cancel, totp.as_deref()).await
+            }
+            AuthCommand::Totp { command } => match command {
+                TotpCommand::Enroll { totp } => {
+                    client.auth_totp_enroll_interactive(totp.as_deref()).await
+                }
+                TotpCommand::Verify { code } => client
+                    .auth_totp_verify(&code)
+                    .await
+                    .map(|_| "TOTP enabled".to_string()),
+                TotpCommand::Disable { code } => client
+                    .auth_totp_disable(&code)
+                    .await
+                    .map(|_| "TOTP disabled".to_string()),
+            },
         },
         Command::Cost { command } => match command {
-            CostCommand::Pay => client.cost_pay().await,
+            CostCommand::Pay { totp } => client.cost_pay(totp.as_deref()).await,
             CostCommand::Get => client.cost_get().await,
         },
         Command::Gene { fed, command } => match command {
             GeneCommand::Meta { gid } => client.gene_meta(fed, &gid).await,
             GeneCommand::Call { gid, arg } => client.gene_call(fed, &gid, arg).await,
+            GeneCommand::Subscribe { gid, arg } => client.gene_subscribe_print(&gid, arg).await,
         },
         Command::Meme { command } => match command {
             MemeCommand::Meta { hash } => client.meme_meta(hash).await,
@@ -30,6 +58,13 @@ async fn main() {
             MemeCommand::Get { public, hash, file } => {
                 client.meme_get_file(public, hash, file).await
             }
+            MemeCommand::Clone {
+                public,
+                fed,
+                hash,
+                days,
+            } => client.meme_clone(public, fed, hash, days).await,
+            MemeCommand::Visa { hash, peer, ttl } => client.meme_visa(hash, peer, ttl).await,
         },
         Command::Map { file } => client.gene_map_1(file).await,
     };